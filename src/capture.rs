@@ -0,0 +1,143 @@
+use std::io::{Result, Error, ErrorKind, BufRead, BufReader, Write, BufWriter};
+use std::fs::File;
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use device_manager::DeviceAddress;
+use device_mapping::DeviceMap;
+
+// Capture file is a text file, it starts with a header line naming the device:
+//     # game-kb-driver capture 0x046dc21c G13
+// followed by one line per packet, microseconds since the capture started and the packet bytes in hex:
+//     1530021 00 80 7f 01 00 00 00 80
+
+const HEADER: &str = "# game-kb-driver capture";
+
+/// One raw packet read from a device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapturedPacket {
+    /// Microseconds since the start of the capture.
+    pub timestamp: u64,
+    /// Raw bytes of the packet.
+    pub data: Vec<u8>,
+}
+
+/// Packets captured from a device.
+#[derive(Debug)]
+pub struct Capture {
+    /// Key of the device the packets were captured from (vendor_id << 16 + product_id).
+    pub key: u32,
+    /// Name of the device mapping used while capturing, including its instance.
+    pub name: String,
+    /// All the captured packets in order.
+    pub packets: Vec<CapturedPacket>,
+}
+
+/// Writes packets of a device into a capture file as they are read.
+pub struct CaptureWriter {
+    writer: BufWriter<File>,
+    start: Instant,
+}
+
+impl CaptureWriter {
+    /// Creates new capture file in the directory, named by the device and the time of the capture.
+    pub fn create(dir: &Path, key: u32, mapping: &DeviceMap, address: &DeviceAddress) -> Result<CaptureWriter> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let file_name = format!("{}-{}-{}.capture", mapping.full_name(), address, now);
+        let file = File::create(dir.join(file_name))?;
+        let mut writer = BufWriter::new(file);
+        writeln!(writer, "{} 0x{:08x} {}", HEADER, key, mapping.full_name())?;
+        Ok(CaptureWriter {
+            writer,
+            start: Instant::now(),
+        })
+    }
+
+    /// Appends the packet to the capture.
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        let elapsed = self.start.elapsed();
+        let timestamp = elapsed.as_secs() * 1_000_000 + (elapsed.subsec_nanos() / 1000) as u64;
+        let mut line = timestamp.to_string();
+        for b in data {
+            line = format!("{} {:02x}", line, b);
+        }
+        writeln!(self.writer, "{}", line)?;
+        // flush every packet, so the capture is complete even if the driver crashes
+        self.writer.flush()
+    }
+}
+
+fn parse_line(line: &str, number: usize) -> Result<CapturedPacket> {
+    let invalid = || {
+        let msg = format!("Invalid packet on line {}: {}", number, line);
+        Error::new(ErrorKind::InvalidData, msg)
+    };
+    let mut parts = line.split_whitespace();
+    let timestamp = parts.next().and_then(|t| t.parse().ok()).ok_or_else(|| invalid())?;
+    let mut data = Vec::new();
+    for part in parts {
+        data.push(u8::from_str_radix(part, 16).map_err(|_| invalid())?);
+    }
+    Ok(CapturedPacket {
+        timestamp,
+        data,
+    })
+}
+
+impl Capture {
+    /// Reads capture file.
+    pub fn new(file_path: &str) -> Result<Capture> {
+        let file = File::open(file_path)?;
+        Capture::from_reader(BufReader::new(file))
+    }
+
+    /// Reads capture from any buffered reader.
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<Capture> {
+        let mut lines = reader.lines();
+        let header = match lines.next() {
+            Some(line) => line?,
+            None => return Err(Error::new(ErrorKind::InvalidData, "Empty capture")),
+        };
+        if !header.starts_with(HEADER) {
+            return Err(Error::new(ErrorKind::InvalidData, "Missing capture header"));
+        }
+        let mut parts = header[HEADER.len()..].trim().splitn(2, ' ');
+        let key = parts.next()
+            .and_then(|k| u32::from_str_radix(k.trim_start_matches("0x"), 16).ok());
+        let key = match key {
+            Some(value) => value,
+            None => return Err(Error::new(ErrorKind::InvalidData, "Missing device key in capture header")),
+        };
+        let name = parts.next().unwrap_or("").to_owned();
+
+        let mut packets = Vec::new();
+        for (i, line) in lines.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            packets.push(parse_line(&line, i + 2)?);
+        }
+        Ok(Capture {
+            key,
+            name,
+            packets,
+        })
+    }
+}
+
+#[test]
+fn test_read_capture() {
+    let text = "# game-kb-driver capture 0x046dc21c G13\n\
+                0 00 80 7f 00 00 00 00 80\n\
+                \n\
+                15230 00 80 7f 10 00 00 00 80\n";
+    let capture = Capture::from_reader(text.as_bytes()).unwrap();
+    assert_eq!(capture.key, 0x046dc21c);
+    assert_eq!(capture.name, "G13");
+    assert_eq!(capture.packets.len(), 2);
+    assert_eq!(capture.packets[1], CapturedPacket { timestamp: 15230, data: vec![0, 0x80, 0x7f, 0x10, 0, 0, 0, 0x80] });
+
+    assert!(Capture::from_reader("0 00 01\n".as_bytes()).is_err());
+    assert!(Capture::from_reader("# game-kb-driver capture 0x046dc21c G13\n0 zz\n".as_bytes()).is_err());
+}
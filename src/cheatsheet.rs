@@ -0,0 +1,335 @@
+use device_mapping::{DeviceInputUid, DeviceMaps, device_key};
+use profile_definition::{Action, Mode, Profile};
+
+/// Distance between the keys in the diagram, in pixels.
+const PITCH_X: f64 = 92.0;
+const PITCH_Y: f64 = 68.0;
+/// Space between the keys.
+const GAP: f64 = 8.0;
+const MARGIN: f64 = 20.0;
+/// Height of the title above the keys.
+const TITLE_HEIGHT: f64 = 40.0;
+/// Height of a line of the inputs listed below the diagram.
+const LINE_HEIGHT: f64 = 18.0;
+
+/// Vendor and product id of the G13, the only device with a diagram.
+const G13_VENDOR_ID: u16 = 0x046d;
+const G13_PRODUCT_ID: u16 = 0xc21c;
+
+/// Keys of the G13 with their place in the diagram, column, row, width and height in pitches.
+const G13_KEYS: &[(&str, f64, f64, f64, f64)] = &[
+    ("Menu", 0.5, 0.0, 1.0, 0.7),
+    ("Menu1", 1.5, 0.0, 1.0, 0.7),
+    ("Menu2", 2.5, 0.0, 1.0, 0.7),
+    ("Menu3", 3.5, 0.0, 1.0, 0.7),
+    ("Menu4", 4.5, 0.0, 1.0, 0.7),
+    ("MenuLight", 5.5, 0.0, 1.0, 0.7),
+    ("Mode1", 1.5, 0.75, 1.0, 0.7),
+    ("Mode2", 2.5, 0.75, 1.0, 0.7),
+    ("Mode3", 3.5, 0.75, 1.0, 0.7),
+    ("Mode4", 4.5, 0.75, 1.0, 0.7),
+    ("G1", 0.0, 1.6, 1.0, 1.0),
+    ("G2", 1.0, 1.6, 1.0, 1.0),
+    ("G3", 2.0, 1.6, 1.0, 1.0),
+    ("G4", 3.0, 1.6, 1.0, 1.0),
+    ("G5", 4.0, 1.6, 1.0, 1.0),
+    ("G6", 5.0, 1.6, 1.0, 1.0),
+    ("G7", 6.0, 1.6, 1.0, 1.0),
+    ("G8", 0.0, 2.6, 1.0, 1.0),
+    ("G9", 1.0, 2.6, 1.0, 1.0),
+    ("G10", 2.0, 2.6, 1.0, 1.0),
+    ("G11", 3.0, 2.6, 1.0, 1.0),
+    ("G12", 4.0, 2.6, 1.0, 1.0),
+    ("G13", 5.0, 2.6, 1.0, 1.0),
+    ("G14", 6.0, 2.6, 1.0, 1.0),
+    ("G15", 1.0, 3.6, 1.0, 1.0),
+    ("G16", 2.0, 3.6, 1.0, 1.0),
+    ("G17", 3.0, 3.6, 1.0, 1.0),
+    ("G18", 4.0, 3.6, 1.0, 1.0),
+    ("G19", 5.0, 3.6, 1.0, 1.0),
+    ("G20", 2.0, 4.6, 1.0, 1.0),
+    ("G21", 3.0, 4.6, 1.0, 1.0),
+    ("G22", 4.0, 4.6, 1.0, 1.0),
+    // the stick is pressed as G25, with G23 left of it and G24 below it
+    ("G25", 8.2, 2.6, 1.0, 1.0),
+    ("G23", 7.2, 4.6, 1.0, 1.0),
+    ("G24", 9.2, 4.6, 1.0, 1.0),
+];
+
+/// Directions of the stick with the axis and its side, and their place in the diagram around the stick.
+const G13_STICK: &[(&str, &str, bool, f64, f64)] = &[
+    ("Stick up", "Y", false, 8.2, 1.6),
+    ("Stick left", "X", false, 7.2, 2.6),
+    ("Stick right", "X", true, 9.2, 2.6),
+    ("Stick down", "Y", true, 8.2, 3.6),
+];
+
+/// Output format of a cheat-sheet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SheetFormat {
+    Svg,
+    Html,
+}
+
+impl SheetFormat {
+    pub fn new(name: &str) -> Option<SheetFormat> {
+        match name {
+            "svg" => Some(SheetFormat::Svg),
+            "html" | "htm" => Some(SheetFormat::Html),
+            _ => None,
+        }
+    }
+}
+
+/// Whether the device, given by its name with the instance, is a G13 in the devices definition.
+pub fn is_g13(mappings: &DeviceMaps, device: &str) -> bool {
+    match mappings.devices.get(&device_key(G13_VENDOR_ID, G13_PRODUCT_ID)) {
+        Some(maps) => maps.iter().any(|map| map.name == device || map.full_name() == device),
+        None => false,
+    }
+}
+
+/// Labels of the inputs of a device in a mode of a profile, to be drawn on the layout of the G13, or listed
+/// for the other devices.
+#[derive(Debug)]
+pub struct Sheet {
+    title: String,
+    /// Whether the inputs are drawn on the layout of the G13.
+    g13: bool,
+    /// Names of the digitals with the labels of their actions.
+    keys: Vec<(String, String)>,
+    /// Names of the analogs with the labels of their negative and positive side.
+    axes: Vec<(String, String, String)>,
+}
+
+/// Label of a key of the action, empty without any.
+fn key_label(action: &Action) -> String {
+    match action {
+        &Action::NoOp => String::new(),
+        &Action::Key(ref key) => key.to_owned(),
+        &Action::Axis(ref left, ref right) => format!("{}/{}", left, right),
+    }
+}
+
+/// Escapes the text for xml.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Text of the label centered in the box, long key combinations are split after the `+`.
+fn label_text(label: &str, x: f64, y: f64, width: f64, height: f64) -> String {
+    let lines: Vec<String> = if label.len() > 10 && label.contains('+') {
+        let parts: Vec<&str> = label.split('+').collect();
+        let last = parts.len() - 1;
+        parts.iter().enumerate().map(|(index, part)| if index < last { format!("{}+", part) } else { part.to_string() }).collect()
+    } else {
+        vec![label.to_owned()]
+    };
+    let size = if lines.iter().any(|line| line.len() > 9) { 10.0 } else { 13.0 };
+    let top = y + height / 2.0 + 6.0 - (lines.len() - 1) as f64 * size / 2.0;
+    let mut res = format!("<text x=\"{:.1}\" y=\"{:.1}\" class=\"action\" font-size=\"{}\">", x + width / 2.0, top, size);
+    for (index, line) in lines.iter().enumerate() {
+        res = format!("{}<tspan x=\"{:.1}\" y=\"{:.1}\">{}</tspan>", res, x + width / 2.0, top + index as f64 * size, escape(line));
+    }
+    format!("{}</text>", res)
+}
+
+/// Box of an input with its name in the corner and its action in the middle.
+fn key_box(name: &str, label: &str, column: f64, row: f64, width: f64, height: f64) -> String {
+    let x = MARGIN + column * PITCH_X;
+    let y = MARGIN + TITLE_HEIGHT + row * PITCH_Y;
+    let width = width * PITCH_X - GAP;
+    let height = height * PITCH_Y - GAP;
+    let class = if label.is_empty() { "key" } else { "key bound" };
+    let mut res = format!("<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" rx=\"6\" class=\"{}\"/>", x, y, width, height, class);
+    res = format!("{}<text x=\"{:.1}\" y=\"{:.1}\" class=\"name\">{}</text>", res, x + 4.0, y + 11.0, escape(name));
+    if !label.is_empty() {
+        res = format!("{}{}", res, label_text(label, x, y + 4.0, width, height));
+    }
+    format!("  {}\n", res)
+}
+
+impl Sheet {
+    /// Labels of the inputs of the device, given by its name with the instance, in the mode. Only the inputs
+    /// of a G13 are drawn on its diagram.
+    pub fn new(profile: &Profile, mode: &Mode, inputs: &[DeviceInputUid], device: &str, g13: bool) -> Sheet {
+        let mut keys = Vec::new();
+        let mut axes = Vec::new();
+        for input in inputs {
+            let (input_device, name, uid) = input.parts();
+            if input_device != device {
+                continue;
+            }
+            let action = &mode.output[uid as usize];
+            match (input, action) {
+                (&DeviceInputUid::Analog(..), &Action::Axis(ref left, ref right)) => axes.push((name.to_owned(), left.to_owned(), right.to_owned())),
+                (&DeviceInputUid::Analog(..), action) => axes.push((name.to_owned(), String::new(), key_label(action))),
+                (&DeviceInputUid::Digital(..), action) => keys.push((name.to_owned(), key_label(action))),
+            }
+        }
+        Sheet {
+            title: format!("{} - {}", profile.name, mode.name),
+            g13,
+            keys,
+            axes,
+        }
+    }
+
+    /// Whether the device has any inputs.
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty() && self.axes.is_empty()
+    }
+
+    /// Label of the side of the axis, none when the device has no such axis.
+    fn axis_label(&self, axis: &str, positive: bool) -> Option<&str> {
+        self.axes.iter().find(|entry| entry.0 == axis).map(|entry| if positive { entry.2.as_str() } else { entry.1.as_str() })
+    }
+
+    /// Inputs listed below the diagram with their labels. These are the bound inputs which are not on the layout
+    /// of the G13, or all the inputs of other devices, the unbound ones labeled with `-`.
+    fn other_inputs(&self) -> Vec<(String, String)> {
+        if !self.g13 {
+            let label = |label: &str| if label.is_empty() { "-".to_owned() } else { label.to_owned() };
+            let keys = self.keys.iter().map(|key| (key.0.to_owned(), label(&key.1)));
+            let axes = self.axes.iter().map(|axis| (axis.0.to_owned(), format!("{} / {}", label(&axis.1), label(&axis.2))));
+            return keys.chain(axes).collect();
+        }
+        let keys = self.keys.iter()
+            .filter(|key| !key.1.is_empty() && !G13_KEYS.iter().any(|entry| entry.0 == key.0))
+            .cloned();
+        let axes = self.axes.iter()
+            .filter(|axis| (!axis.1.is_empty() || !axis.2.is_empty()) && !G13_STICK.iter().any(|entry| entry.1 == axis.0))
+            .map(|axis| (axis.0.to_owned(), format!("{} / {}", axis.1, axis.2)));
+        keys.chain(axes).collect()
+    }
+
+    /// Diagram of the G13 with the actions of the keys and the stick, the inputs which are not on the G13
+    /// are listed below it. Inputs of other devices are only listed.
+    pub fn svg(&self) -> String {
+        let others = self.other_inputs();
+        let width = 2.0 * MARGIN + 10.2 * PITCH_X - GAP;
+        let keys_bottom = if self.g13 { MARGIN + TITLE_HEIGHT + 5.6 * PITCH_Y } else { MARGIN + TITLE_HEIGHT };
+        let height = keys_bottom + MARGIN + others.len() as f64 * LINE_HEIGHT;
+        let mut res = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{:.1}\" height=\"{:.1}\" viewBox=\"0 0 {:.1} {:.1}\">\n", width, height, width, height);
+        res = format!("{}  <style>
+    text {{ font-family: sans-serif; fill: #000; }}
+    .title {{ font-size: 20px; font-weight: bold; }}
+    .name {{ font-size: 9px; fill: #666; }}
+    .action {{ text-anchor: middle; }}
+    .key {{ fill: #fff; stroke: #999; }}
+    .bound {{ fill: #fff4e0; stroke: #333; }}
+    .stick {{ fill: none; stroke: #999; stroke-dasharray: 4 3; }}
+  </style>
+", res);
+        res = format!("{}  <text x=\"{:.1}\" y=\"{:.1}\" class=\"title\">{}</text>\n", res, MARGIN, MARGIN + 20.0, escape(&self.title));
+        if self.g13 {
+            res = format!("{}{}", res, self.g13_diagram());
+        }
+        for (index, &(ref name, ref label)) in others.iter().enumerate() {
+            let y = keys_bottom + (index + 1) as f64 * LINE_HEIGHT;
+            res = format!("{}  <text x=\"{:.1}\" y=\"{:.1}\" font-size=\"13\">{}: {}</text>\n", res, MARGIN, y, escape(name), escape(label));
+        }
+        format!("{}</svg>\n", res)
+    }
+
+    /// Keys and the stick of the G13 with their actions.
+    fn g13_diagram(&self) -> String {
+        let mut res = String::new();
+        // the stick with its directions around it
+        let center_x = MARGIN + 8.7 * PITCH_X - GAP / 2.0;
+        let center_y = MARGIN + TITLE_HEIGHT + 3.1 * PITCH_Y - GAP / 2.0;
+        res = format!("{}  <circle cx=\"{:.1}\" cy=\"{:.1}\" r=\"{:.1}\" class=\"stick\"/>\n", res, center_x, center_y, 1.45 * PITCH_Y);
+        for &(name, axis, positive, column, row) in G13_STICK {
+            if let Some(label) = self.axis_label(axis, positive) {
+                res = format!("{}{}", res, key_box(name, label, column, row, 1.0, 1.0));
+            }
+        }
+        for &(name, column, row, width, height) in G13_KEYS {
+            if let Some(key) = self.keys.iter().find(|key| key.0 == name) {
+                res = format!("{}{}", res, key_box(name, &key.1, column, row, width, height));
+            }
+        }
+        res
+    }
+
+    /// Self-contained html page with the diagram, printed in landscape.
+    pub fn html(&self) -> String {
+        format!("<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>{}</title>
+<style>
+  @page {{ size: landscape; }}
+  body {{ margin: 0; }}
+</style>
+</head>
+<body>
+{}</body>
+</html>
+", escape(&self.title), self.svg())
+    }
+
+    /// Renders the sheet in the format.
+    pub fn render(&self, format: SheetFormat) -> String {
+        match format {
+            SheetFormat::Svg => self.svg(),
+            SheetFormat::Html => self.html(),
+        }
+    }
+}
+
+#[test]
+fn test_sheet() {
+    let inputs = vec![
+        DeviceInputUid::Digital("G13".to_owned(), "G1".to_owned(), 0),
+        DeviceInputUid::Digital("G13".to_owned(), "G2".to_owned(), 1),
+        DeviceInputUid::Digital("G13".to_owned(), "Extra".to_owned(), 2),
+        DeviceInputUid::Analog("G13".to_owned(), "X".to_owned(), 3),
+        DeviceInputUid::Digital("G600".to_owned(), "G9".to_owned(), 4),
+    ];
+    let mode = Mode {
+        name: "Build".to_owned(),
+        backlight: None,
+        leds: None,
+        output: vec![
+            Action::Key("Escape".to_owned()),
+            Action::NoOp,
+            Action::Key("ctrl+shift+<".to_owned()),
+            Action::Axis("a".to_owned(), "d".to_owned()),
+            Action::Key("q".to_owned()),
+        ],
+    };
+    let profile = Profile {
+        name: "Factorio".to_owned(),
+        pattern: "*".to_owned(),
+        backlight: None,
+        leds: None,
+        modes: vec![mode.clone()],
+    };
+    let sheet = Sheet::new(&profile, &mode, &inputs, "G13", true);
+    assert_eq!(sheet.keys.len(), 3);
+    assert_eq!(sheet.other_inputs(), vec![("Extra".to_owned(), "ctrl+shift+<".to_owned())]);
+    let svg = sheet.svg();
+    assert!(svg.contains(">Factorio - Build</text>"));
+    assert!(svg.contains(">Escape</tspan>"));
+    assert!(svg.contains(">Extra: ctrl+shift+&lt;</text>"));
+    // the stick has only the x axis
+    assert!(svg.contains(">Stick left</text>") && svg.contains(">Stick right</text>") && !svg.contains("Stick up"));
+    assert!(!svg.contains(">q<"));
+    assert!(sheet.html().starts_with("<!DOCTYPE html>"));
+    assert!(Sheet::new(&profile, &mode, &inputs, "G15", false).is_empty());
+    // the G600 has keys named as the ones of the G13, they are listed, not drawn on its diagram
+    let svg = Sheet::new(&profile, &mode, &inputs, "G600", false).svg();
+    assert!(svg.contains(">G9: q</text>"));
+    assert!(!svg.contains("class=\"key") && !svg.contains("class=\"stick"));
+}
+
+#[test]
+fn test_is_g13() {
+    use config::Source;
+    let mappings = DeviceMaps::new(&Source::bundled_devices()).unwrap();
+    assert!(is_g13(&mappings, "G13"));
+    assert!(!is_g13(&mappings, "G600"));
+    assert!(!is_g13(&mappings, "G15"));
+}
@@ -0,0 +1,269 @@
+use std::env;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::result;
+
+use log::LevelFilter;
+use log4rs;
+use log4rs::append::console::ConsoleAppender;
+use log4rs::config::{Appender, Root};
+use log4rs::encode::pattern::PatternEncoder;
+use serde::{Deserialize, Serialize};
+use serde_json;
+use serde_yaml;
+use toml;
+
+use error::{Error, Result};
+use output::OutputKind;
+
+/// Directory of the configuration files in the configuration directories.
+const CONFIG_DIR: &str = "game-kb-driver";
+/// Devices definition used without devices.yaml, with the G13.
+const BUNDLED_DEVICES: &str = include_str!("../devices.yaml");
+/// Profiles used without profiles.yaml.
+const BUNDLED_PROFILES: &str = include_str!("../profiles.yaml");
+/// Seconds between the polls for devices, when there is no poll_interval in config.yaml.
+const DEFAULT_POLL_INTERVAL: u64 = 4;
+
+/// Error of parsing or writing a configuration file in any of the formats.
+pub type FormatError = Box<dyn error::Error + Send + Sync>;
+
+/// Format of a configuration file, given by the extension of the file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl Format {
+    /// Format of the file extension, none when it isn't a configuration file.
+    pub fn from_extension(ext: &str) -> Option<Format> {
+        match ext {
+            "yaml" | "yml" => Some(Format::Yaml),
+            "toml" => Some(Format::Toml),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+
+    /// Format of the file by its extension, yaml for unknown extensions.
+    pub fn of(path: &Path) -> Format {
+        path.extension().and_then(|ext| ext.to_str()).and_then(Format::from_extension).unwrap_or(Format::Yaml)
+    }
+
+    /// Parses the text in the format.
+    pub fn parse<T: Deserialize>(self, text: &str) -> result::Result<T, FormatError> {
+        match self {
+            Format::Yaml => Ok(serde_yaml::from_str(text)?),
+            Format::Toml => Ok(toml::from_str(text)?),
+            Format::Json => Ok(serde_json::from_str(text)?),
+        }
+    }
+
+    /// Writes the value in the format. Toml is written through its value, which puts the tables after the plain
+    /// values as toml requires, but can't have tuples, so they are made sequences through the yaml value first.
+    pub fn write<T: Serialize>(self, value: &T) -> result::Result<String, FormatError> {
+        match self {
+            Format::Yaml => Ok(format!("{}\n", serde_yaml::to_string(value)?)),
+            Format::Toml => Ok(toml::to_string(&toml::Value::try_from(serde_yaml::to_value(value)?)?)?),
+            Format::Json => Ok(format!("{}\n", serde_json::to_string_pretty(value)?)),
+        }
+    }
+}
+
+/// Configuration file given on the command line or found in the configuration directories,
+/// or the default bundled in the binary.
+#[derive(Debug, Clone)]
+pub enum Source {
+    File(PathBuf),
+    /// Name of the file and its content.
+    Bundled(&'static str, &'static str),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Source::File(ref path) => write!(f, "{}", path.display()),
+            &Source::Bundled(name, _) => write!(f, "bundled {}", name),
+        }
+    }
+}
+
+impl Source {
+    /// Format of the file, the bundled files are yaml.
+    pub fn format(&self) -> Format {
+        match self {
+            &Source::File(ref path) => Format::of(path),
+            &Source::Bundled(..) => Format::Yaml,
+        }
+    }
+
+    /// Content of the file.
+    pub fn read_to_string(&self) -> io::Result<String> {
+        match self {
+            &Source::File(ref path) => fs::read_to_string(path),
+            &Source::Bundled(_, text) => Ok(text.to_owned()),
+        }
+    }
+
+    /// Devices definition given on the command line or found, the bundled one otherwise.
+    pub fn devices(given: Option<&str>) -> Source {
+        find(given, &["devices.yaml", "devices.toml", "devices.json"]).map(Source::File).unwrap_or_else(Source::bundled_devices)
+    }
+
+    /// Devices definition built into the driver.
+    pub fn bundled_devices() -> Source {
+        Source::Bundled("devices.yaml", BUNDLED_DEVICES)
+    }
+
+    /// Profiles file or directory given on the command line or found, the bundled profiles otherwise.
+    pub fn profiles(given: Option<&str>) -> Source {
+        find(given, &["profiles.yaml", "profiles.toml", "profiles.json", "profiles.d"]).map(Source::File).unwrap_or(Source::Bundled("profiles.yaml", BUNDLED_PROFILES))
+    }
+}
+
+/// Configuration directories in the order they are searched, the one of the user from `$XDG_CONFIG_HOME`,
+/// or `~/.config`, and the system one.
+pub fn config_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    let user_dir = env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()).map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+    if let Some(dir) = user_dir {
+        dirs.push(dir.join(CONFIG_DIR));
+    }
+    dirs.push(Path::new("/etc").join(CONFIG_DIR));
+    dirs
+}
+
+/// Finds the first of the names in the first directory which has any of them.
+fn find_in(dirs: &[PathBuf], names: &[&str]) -> Option<PathBuf> {
+    dirs.iter()
+        .flat_map(|dir| names.iter().map(move |name| dir.join(name)))
+        .find(|path| path.exists())
+}
+
+/// Path given on the command line as it is, or the first of the names found in the configuration directories.
+pub fn find(given: Option<&str>, names: &[&str]) -> Option<PathBuf> {
+    match given {
+        Some(path) => Some(PathBuf::from(path)),
+        None => find_in(&config_dirs(), names),
+    }
+}
+
+/// Initializes logging from log.yaml given on the command line or found, without it the messages
+/// from info up are written to the console.
+pub fn init_logging(given: Option<&str>) -> Result<()> {
+    if let Some(path) = find(given, &["log.yaml"]) {
+        return log4rs::init_file(&path, Default::default())
+            .map_err(|err| Error::invalid(format!("{}: {}", path.display(), err)));
+    }
+    let stdout = ConsoleAppender::builder()
+        .encoder(Box::new(PatternEncoder::new("{h({d(%H:%M:%S)} {l}: {M} - {m}{n})}")))
+        .build();
+    let config = log4rs::config::Config::builder()
+        .appender(Appender::builder().build("stdout", Box::new(stdout)))
+        .build(Root::builder().appender("stdout").build(LevelFilter::Info))
+        .map_err(|err| Error::invalid(err.to_string()))?;
+    log4rs::init_config(config).map_err(|err| Error::invalid(err.to_string()))?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct ConfigDef {
+    /// Backend of the key presses, xdo or print.
+    output: Option<String>,
+    /// Seconds between the polls for devices.
+    poll_interval: Option<u64>,
+    /// Profile selected when the driver starts.
+    profile: Option<String>,
+    /// User the driver switches to when started as root.
+    user: Option<String>,
+}
+
+/// Settings of the driver from config.yaml.
+#[derive(Debug)]
+pub struct Config {
+    /// Where the key presses are sent.
+    pub output: OutputKind,
+    /// Seconds between the polls for devices, when device events are not available, and between
+    /// the retries of the failed devices.
+    pub poll_interval: u64,
+    /// Profile selected by the first letters of its name, unless one is given on the command line.
+    pub profile: Option<String>,
+    /// User the driver switches to after opening the devices as root, unless one is given on the command line.
+    pub user: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            output: OutputKind::Xdo,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            profile: None,
+            user: None,
+        }
+    }
+}
+
+impl Config {
+    /// Reads config.yaml, or config.toml or config.json, given on the command line or found, the defaults are
+    /// used without it.
+    pub fn new(given: Option<&str>) -> Result<Config> {
+        match find(given, &["config.yaml", "config.toml", "config.json"]) {
+            Some(path) => {
+                let text = fs::read_to_string(&path)?;
+                Config::parse(&text, Format::of(&path)).map_err(|err| match err {
+                    Error::ConfigParse(_, err) => Error::ConfigParse(path.display().to_string(), err),
+                    Error::ConfigValidation(msg) => Error::invalid(format!("{}: {}", path.display(), msg)),
+                    err => err,
+                })
+            }
+            None => Ok(Config::default()),
+        }
+    }
+
+    /// Parses the settings in the format, missing settings have the default values.
+    fn parse(text: &str, format: Format) -> Result<Config> {
+        let def: ConfigDef = format.parse(text).map_err(|err| Error::ConfigParse(String::new(), err))?;
+        let output = match def.output {
+            Some(name) => match OutputKind::new(&name) {
+                Some(value) => value,
+                None => return Err(Error::invalid(format!("unknown output {}, expected xdo or print", name))),
+            },
+            None => OutputKind::Xdo,
+        };
+        let poll_interval = def.poll_interval.unwrap_or(DEFAULT_POLL_INTERVAL);
+        if poll_interval == 0 {
+            return Err(Error::invalid("poll_interval must be at least 1 second"));
+        }
+        Ok(Config {
+            output,
+            poll_interval,
+            profile: def.profile,
+            user: def.user,
+        })
+    }
+}
+
+#[test]
+fn test_find_in() {
+    let dirs = vec![PathBuf::from("testdata/missing"), PathBuf::from("testdata")];
+    // the first directory with any of the names wins, the order of the names matters only within it
+    assert_eq!(find_in(&dirs, &["profiles.yaml", "profiles"]), Some(PathBuf::from("testdata/profiles")));
+    assert_eq!(find_in(&dirs, &["devices.yaml"]), None);
+}
+
+#[test]
+fn test_parse_config() {
+    let config = Config::parse("output: print\nprofile: fact\n", Format::Yaml).unwrap();
+    assert_eq!((config.output, config.poll_interval, config.profile), (OutputKind::Print, 4, Some("fact".to_owned())));
+    assert!(Config::parse("output: uinput\n", Format::Yaml).is_err());
+    assert!(Config::parse("poll_interval: 0\n", Format::Yaml).is_err());
+    let config = Config::parse("poll_interval = 2\nuser = \"player\"\n", Format::Toml).unwrap();
+    assert_eq!((config.poll_interval, config.user), (2, Some("player".to_owned())));
+    assert_eq!(Config::parse("{\"output\": \"print\"}", Format::Json).unwrap().output, OutputKind::Print);
+}
@@ -0,0 +1,433 @@
+use std::io::{Result, Error, ErrorKind, BufRead, BufReader, Write};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+use libc;
+
+use config::Source;
+use device_manager::DeviceAddress;
+use device_mapping::DeviceInputUid;
+use device_output::{self, Color, DeviceCommand, DeviceOutputs};
+use input::Input;
+use lcd::{self, Image};
+use mapper::Mapper;
+use output::KeyOutput;
+use profile_definition::Profiles;
+
+/// Time the stream of events waits for an input, before it checks whether the client has hung up.
+const HANGUP_CHECK: Duration = Duration::from_secs(1);
+
+/// Reply to a request, lines of the result or an error message.
+pub type Response = ::std::result::Result<Vec<String>, String>;
+
+/// Request handled by the mapping thread.
+pub enum Request {
+    /// Lists the profiles, the active one is marked.
+    Profiles,
+    /// Gets the active profile, or activates the profile selected by the first letters of its name.
+    Profile(Option<String>),
+    /// Gets the active mode, or activates the mode selected by the first letters of its name or its number.
+    Mode(Option<String>),
+    /// Replaces the profiles by the reloaded ones.
+    Reload(Profiles),
+    /// Sends every following input to the sender, until it is closed.
+    Events(Sender<Input>),
+}
+
+/// Message for the mapping thread, inputs of the devices or requests from the control socket.
+pub enum MappingMessage {
+    Input(Input),
+    Request(Request, Sender<Response>),
+    /// Device with the inputs of the uids is gone, its inputs which are not at rest are released.
+    DeviceGone(Vec<u16>),
+    /// Releases the held keys and stops the mapping, the sender is dropped when done.
+    Stop(Sender<()>),
+}
+
+impl From<Input> for MappingMessage {
+    fn from(inp: Input) -> MappingMessage {
+        MappingMessage::Input(inp)
+    }
+}
+
+/// Command read from a line of the control socket.
+#[derive(Debug, PartialEq)]
+enum Command {
+    Help,
+    Devices,
+    Profiles,
+    Profile(Option<String>),
+    Mode(Option<String>),
+    Reload,
+    Events,
+    /// Command for the devices with the name, or for all devices without the name.
+    Device(Option<String>, DeviceCommand),
+}
+
+const HELP: &[&str] = &[
+    "devices                      list connected devices",
+    "profiles                     list profiles, the active one is marked by *",
+    "profile [NAME]               get or set the active profile",
+    "mode [NAME|NUMBER]           get or set the active mode",
+    "reload                       reload the profiles file",
+    "events                       stream inputs of the devices",
+    "device NAME|all backlight #rrggbb",
+    "device NAME|all leds [M1 M2 M3 MR]",
+    "device NAME|all image FILE   show PBM or PNG image on the display",
+    "device NAME|all report BYTES send feature report given in hex, report id first",
+];
+
+/// Parses command of the device, e.g. `backlight #ff0000`.
+fn parse_device_command(args: &[&str]) -> ::std::result::Result<DeviceCommand, String> {
+    match args.split_first() {
+        Some((&"backlight", &[color])) => match Color::parse(color) {
+            Some(value) => Ok(DeviceCommand::Backlight(value)),
+            None => Err(format!("Invalid colour {}, expected #rrggbb", color)),
+        },
+        Some((&"leds", names)) => match device_output::parse_leds(names) {
+            Some(value) => Ok(DeviceCommand::Leds(value)),
+            None => Err(format!("Invalid leds, expected some of {:?}", device_output::LED_NAMES)),
+        },
+        Some((&"image", &[file_path])) => {
+            let image = Image::open(file_path).map_err(|err| format!("Failed to read image {}: {}", file_path, err))?;
+            let mut frame = Image::frame();
+            frame.draw((lcd::WIDTH as i32 - image.width as i32) / 2, (lcd::HEIGHT as i32 - image.height as i32) / 2, &image);
+            Ok(DeviceCommand::Frame(frame))
+        }
+        Some((&"report", bytes)) if !bytes.is_empty() => {
+            let data = bytes.iter()
+                .map(|byte| u8::from_str_radix(byte, 16).map_err(|_| format!("Invalid byte {}", byte)))
+                .collect::<::std::result::Result<Vec<u8>, String>>()?;
+            Ok(DeviceCommand::FeatureReport(data))
+        }
+        _ => Err("Invalid device command, see help".to_owned()),
+    }
+}
+
+/// Parses line received on the control socket.
+fn parse_command(line: &str) -> ::std::result::Result<Command, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    // names may contain spaces
+    let rest = |from: usize| if words.len() > from { Some(words[from..].join(" ")) } else { None };
+    match words.first() {
+        Some(&"help") => Ok(Command::Help),
+        Some(&"devices") => Ok(Command::Devices),
+        Some(&"profiles") => Ok(Command::Profiles),
+        Some(&"profile") => Ok(Command::Profile(rest(1))),
+        Some(&"mode") => Ok(Command::Mode(rest(1))),
+        Some(&"reload") => Ok(Command::Reload),
+        Some(&"events") => Ok(Command::Events),
+        Some(&"device") if words.len() > 2 => {
+            let target = if words[1] == "all" { None } else { Some(words[1].to_owned()) };
+            Ok(Command::Device(target, parse_device_command(&words[2..])?))
+        }
+        Some(command) => Err(format!("Unknown command {}, see help", command)),
+        None => Err("Empty command".to_owned()),
+    }
+}
+
+/// Handles request in the mapping thread, returns true in the second value when the active profile or mode has changed.
+pub fn handle_request<O: KeyOutput>(mapper: &mut Mapper<O>, request: Request, subscribers: &mut Vec<Sender<Input>>) -> (Response, bool) {
+    match request {
+        Request::Profiles => {
+            let active = mapper.profile().name.to_owned();
+            let names = mapper.profiles().profiles.iter()
+                .map(|profile| format!("{} {}", if profile.name == active { "*" } else { " " }, profile.name))
+                .collect();
+            (Ok(names), false)
+        }
+        Request::Profile(None) => (Ok(vec![mapper.profile().name.to_owned()]), false),
+        Request::Profile(Some(name)) => {
+            if mapper.set_profile(&name) {
+                info!("Profile selected: {}", mapper.profile().name);
+                (Ok(vec![mapper.profile().name.to_owned()]), true)
+            } else {
+                (Err(format!("No profile {}", name)), false)
+            }
+        }
+        Request::Mode(None) => (Ok(vec![mapper.mode().name.to_owned()]), false),
+        Request::Mode(Some(name)) => {
+            if mapper.set_mode(&name) {
+                info!("Mode selected: {}", mapper.mode().name);
+                (Ok(vec![mapper.mode().name.to_owned()]), true)
+            } else {
+                (Err(format!("No mode {} in profile {}", name, mapper.profile().name)), false)
+            }
+        }
+        Request::Reload(profiles) => {
+            mapper.set_profiles(profiles);
+            info!("Profiles reloaded, profile selected: {}", mapper.profile().name);
+            (Ok(vec![mapper.profile().name.to_owned()]), true)
+        }
+        Request::Events(sender) => {
+            subscribers.push(sender);
+            (Ok(Vec::new()), false)
+        }
+    }
+}
+
+/// Shared state of the control socket, used by the threads of the connected clients.
+pub struct Control {
+    /// Requests are sent to the mapping thread through this.
+    pub mapping_sender: Sender<MappingMessage>,
+    /// Addresses and names of the connected devices.
+    pub mapped: Arc<Mutex<HashMap<DeviceAddress, String>>>,
+    /// Command channels of the devices with outputs.
+    pub outputs: Arc<Mutex<DeviceOutputs>>,
+    /// Profiles file or directory reloaded on request.
+    pub profiles: Source,
+    /// Inputs of all the devices, used to reload the profiles and to name the inputs.
+    pub device_inputs: Vec<DeviceInputUid>,
+}
+
+impl Control {
+    /// Sends request to the mapping thread and waits for its response.
+    fn request(&self, request: Request) -> Response {
+        let (sender, receiver) = mpsc::channel();
+        self.mapping_sender.send(MappingMessage::Request(request, sender)).map_err(|_| "Mapping has stopped".to_owned())?;
+        receiver.recv().map_err(|_| "Mapping has stopped".to_owned())?
+    }
+
+    /// Device and input names of the input uids.
+    fn input_names(&self) -> HashMap<u16, (String, String)> {
+        self.device_inputs.iter().map(|input| {
+            let (device, name, uid) = input.parts();
+            (uid, (device.to_owned(), name.to_owned()))
+        }).collect()
+    }
+
+    /// Executes the command, writing the result lines to the client.
+    fn execute(&self, command: Command, writer: &mut UnixStream) -> Result<Response> {
+        let response = match command {
+            Command::Help => Ok(HELP.iter().map(|line| line.to_string()).collect()),
+            Command::Devices => {
+                let mut devices: Vec<String> = self.mapped.lock().unwrap().iter()
+                    .map(|(address, name)| format!("{} {}", name, address))
+                    .collect();
+                devices.sort();
+                Ok(devices)
+            }
+            Command::Profiles => self.request(Request::Profiles),
+            Command::Profile(name) => self.request(Request::Profile(name)),
+            Command::Mode(name) => self.request(Request::Mode(name)),
+            Command::Reload => match Profiles::new(&self.profiles, self.device_inputs.clone()) {
+                Ok(profiles) => self.request(Request::Reload(profiles)),
+                Err(err) => Err(format!("Failed to read profiles: {}", err)),
+            },
+            Command::Events => {
+                let (sender, receiver) = mpsc::channel();
+                if let Err(err) = self.request(Request::Events(sender)) {
+                    return Ok(Err(err));
+                }
+                let names = self.input_names();
+                // inputs of devices added to the devices file after the start are labelled by their uid
+                let name = |uid: u16| match names.get(&uid) {
+                    Some(&(ref device, ref name)) => format!("{} {}", device, name),
+                    None => format!("unknown {}", uid),
+                };
+                // streams until the client disconnects, an idle client is checked for the hangup regularly
+                loop {
+                    let line = match receiver.recv_timeout(HANGUP_CHECK) {
+                        Ok(Input::ButtonDown(uid)) => format!("down {}", name(uid)),
+                        Ok(Input::ButtonUp(uid)) => format!("up {}", name(uid)),
+                        Ok(Input::Axis(uid, value)) => format!("axis {} {:.2}", name(uid), value),
+                        Err(RecvTimeoutError::Timeout) if hung_up(writer) => break,
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    };
+                    writeln!(writer, "{}", line)?;
+                    writer.flush()?;
+                }
+                Ok(Vec::new())
+            }
+            Command::Device(target, command) => {
+                let sent = self.outputs.lock().unwrap().send(target.as_ref().map(|t| t.as_str()), command);
+                Ok(vec![format!("sent to {} devices", sent)])
+            }
+        };
+        Ok(response)
+    }
+}
+
+/// Whether the client has closed the connection, the commands it sends meanwhile are read later.
+fn hung_up(stream: &UnixStream) -> bool {
+    // the hangup is reported without asking for any events
+    let mut fds = libc::pollfd { fd: stream.as_raw_fd(), events: 0, revents: 0 };
+    let res = unsafe { libc::poll(&mut fds, 1, 0) };
+    res < 0 || fds.revents & (libc::POLLHUP | libc::POLLERR) != 0
+}
+
+/// Serves one client, every line is a command, its result lines are followed by `ok` or `error: message`.
+fn serve(stream: UnixStream, control: Arc<Control>) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let response = match parse_command(&line) {
+            Ok(command) => control.execute(command, &mut writer)?,
+            Err(err) => Err(err),
+        };
+        match response {
+            Ok(lines) => {
+                for line in lines {
+                    writeln!(writer, "{}", line)?;
+                }
+                writeln!(writer, "ok")?;
+            }
+            Err(err) => writeln!(writer, "error: {}", err)?,
+        }
+    }
+    Ok(())
+}
+
+/// Directory of the control socket without the runtime directory, it's private to the user.
+fn fallback_dir() -> PathBuf {
+    PathBuf::from(format!("/tmp/game-kb-driver-{}", unsafe { libc::getuid() }))
+}
+
+/// Default path of the control socket, in the runtime directory of the user.
+pub fn default_socket_path() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("game-kb-driver.sock"),
+        None => fallback_dir().join("control.sock"),
+    }
+}
+
+/// Creates directory only the user can access, or checks that the existing one is such, not a symlink
+/// or a directory of another user.
+fn create_private_dir(dir: &Path) -> Result<()> {
+    match fs::DirBuilder::new().mode(0o700).create(dir) {
+        Ok(()) => return Ok(()),
+        Err(ref err) if err.kind() == ErrorKind::AlreadyExists => (),
+        Err(err) => return Err(err),
+    }
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() || metadata.uid() != unsafe { libc::getuid() } || metadata.mode() & 0o077 != 0 {
+        let msg = format!("{} is not a directory only the user can access", dir.display());
+        return Err(Error::new(ErrorKind::PermissionDenied, msg));
+    }
+    Ok(())
+}
+
+/// Listens on the control socket, every client is served by its own thread.
+pub fn start(path: &Path, control: Control) -> Result<()> {
+    let fallback = fallback_dir();
+    if path.parent() == Some(fallback.as_path()) {
+        create_private_dir(&fallback)?;
+    }
+    if path.exists() {
+        // socket left by a driver which didn't exit cleanly
+        if UnixStream::connect(path).is_ok() {
+            let msg = format!("Another driver is listening on {}", path.display());
+            return Err(Error::new(ErrorKind::AddrInUse, msg));
+        }
+        fs::remove_file(path)?;
+    }
+    // the socket is created accessible only by the user, others can't connect before it's restricted
+    let mask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(mask) };
+    let listener = listener?;
+    info!("Listening for control commands on {}", path.display());
+    let control = Arc::new(control);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let control = control.clone();
+                    thread::spawn(move || {
+                        if let Err(err) = serve(stream, control) {
+                            debug!("Control client disconnected: {}", err);
+                        }
+                    });
+                }
+                Err(err) => error!("Failed to accept control client: {}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Sends the command to the running driver and prints its result, returns false on failure.
+pub fn send(path: &Path, command: &str) -> Result<bool> {
+    let mut stream = UnixStream::connect(path)
+        .map_err(|err| Error::new(err.kind(), format!("Failed to connect to {}: {}", path.display(), err)))?;
+    writeln!(stream, "{}", command)?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line == "ok" {
+            return Ok(true);
+        }
+        if let Some(err) = line.strip_prefix("error: ") {
+            eprintln!("{}", err);
+            return Ok(false);
+        }
+        println!("{}", line);
+    }
+    Err(Error::new(ErrorKind::UnexpectedEof, "Driver closed the connection"))
+}
+
+#[test]
+fn test_parse_command() {
+    assert_eq!(parse_command("profile Deus Ex"), Ok(Command::Profile(Some("Deus Ex".to_owned()))));
+    assert_eq!(parse_command("mode"), Ok(Command::Mode(None)));
+    assert_eq!(parse_command("device all leds M1 MR"), Ok(Command::Device(None, DeviceCommand::Leds(9))));
+    assert_eq!(parse_command("device G13@left report 07 ff"),
+               Ok(Command::Device(Some("G13@left".to_owned()), DeviceCommand::FeatureReport(vec![7, 0xff]))));
+    assert!(parse_command("device all backlight red").is_err());
+    assert!(parse_command("jump").is_err());
+}
+
+#[test]
+fn test_handle_request() {
+    use output::RecordOutput;
+    use profile_definition::{Profile, Mode};
+
+    let mode = |name: &str| Mode { name: name.to_owned(), backlight: None, leds: None, output: Vec::new() };
+    let profile = |name: &str| Profile {
+        name: name.to_owned(), pattern: "*".to_owned(), backlight: None, leds: None, modes: vec![mode("Walk"), mode("Drive")],
+    };
+    let profiles = Profiles { profiles: vec![profile("Factorio"), profile("Deus-Ex")] };
+    let mut mapper = Mapper::new(profiles, None, RecordOutput::default());
+    let mut subscribers = Vec::new();
+    assert_eq!(handle_request(&mut mapper, Request::Profile(Some("deus".to_owned())), &mut subscribers), (Ok(vec!["Deus-Ex".to_owned()]), true));
+    assert_eq!(handle_request(&mut mapper, Request::Mode(Some("2".to_owned())), &mut subscribers), (Ok(vec!["Drive".to_owned()]), true));
+    assert!(handle_request(&mut mapper, Request::Mode(Some("fly".to_owned())), &mut subscribers).0.is_err());
+    assert_eq!(handle_request(&mut mapper, Request::Profiles, &mut subscribers).0, Ok(vec!["  Factorio".to_owned(), "* Deus-Ex".to_owned()]));
+    // the second mode lights the second led
+    assert_eq!(mapper.device_state()[0], DeviceCommand::Leds(2));
+    // reloaded profiles keep the active profile
+    let profiles = Profiles { profiles: vec![profile("Deus-Ex")] };
+    assert_eq!(handle_request(&mut mapper, Request::Reload(profiles), &mut subscribers).0, Ok(vec!["Deus-Ex".to_owned()]));
+}
+
+#[test]
+fn test_hung_up() {
+    let (stream, client) = UnixStream::pair().unwrap();
+    assert!(!hung_up(&stream));
+    drop(client);
+    assert!(hung_up(&stream));
+}
+
+#[test]
+fn test_private_dir() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = env::temp_dir().join(format!("game-kb-driver-test-{}", ::std::process::id()));
+    let _ = fs::remove_dir(&dir);
+    create_private_dir(&dir).unwrap();
+    assert_eq!(fs::metadata(&dir).unwrap().mode() & 0o777, 0o700);
+    // existing private directory is used again, one others can access is not
+    create_private_dir(&dir).unwrap();
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+    assert_eq!(create_private_dir(&dir).unwrap_err().kind(), ErrorKind::PermissionDenied);
+    fs::remove_dir(&dir).unwrap();
+}
@@ -1,83 +1,132 @@
 
-use std::io::{Result, Error, ErrorKind};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{Sender};
-use std::time::Duration;
 
-use libusb::{self,Context, Direction, TransferType};
+use libusb::{self,Context, Device, DeviceHandle, Direction, TransferType};
 
-use device_mapping::DeviceMap;
+use error::{Error, Result};
 use input::Input;
-use map_input::MapInput;
+use input_source::{InputSource, PacketSource, READ_TIMEOUT};
 
 
-pub fn run(bus_number: u8, address: u8, mapping: DeviceMap, input_sender: Sender<Input>) -> Result<()> {
-    let context = iotry!(Context::new());
-    let mut handle = None;
-    let mut iet = None;
-    for device in iotry!(context.devices()).iter() {
+/// Input endpoint of a device and the interface it belongs to.
+#[derive(Clone, Copy)]
+pub struct InputEndpoint {
+    /// Number of the interface with the endpoint.
+    pub interface: u8,
+    /// Address of the endpoint.
+    pub address: u8,
+    /// How the data is transferred from the endpoint.
+    pub transfer_type: TransferType,
+    /// Maximum size of one packet read from the endpoint.
+    pub max_packet_size: u16,
+    /// Whether a kernel driver was detached from the interface, it's attached again when the interface is released.
+    pub kernel_driver: bool,
+}
+
+/// Finds the first input endpoint of the device.
+pub fn find_input_endpoint(device: &Device) -> Result<Option<InputEndpoint>> {
+    let cfg = device.active_config_descriptor()?;
+    for interface in cfg.interfaces() {
+        for desc in interface.descriptors() {
+            for endpoint in desc.endpoint_descriptors() {
+                //if endpoint.direction() == Direction::In && endpoint.max_packet_size() == mapping.packet_size {
+                if endpoint.direction() == Direction::In {
+                    return Ok(Some(InputEndpoint {
+                        interface: interface.number(),
+                        address: endpoint.address(),
+                        transfer_type: endpoint.transfer_type(),
+                        max_packet_size: endpoint.max_packet_size(),
+                        kernel_driver: false,
+                    }));
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Opens the device at the bus and address, and claims the interface with its input endpoint.
+pub fn open<'a>(context: &'a Context, bus_number: u8, address: u8, name: &str) -> Result<(DeviceHandle<'a>, InputEndpoint)> {
+    for device in context.devices()?.iter() {
         if bus_number != device.bus_number() || address != device.address() {
             continue;
         }
 
         // find input interface
-        let cfg = iotry!(device.active_config_descriptor());
-        for interface in cfg.interfaces() {
-            for desc in interface.descriptors() {
-                for endpoint in desc.endpoint_descriptors() {
-                    //if endpoint.direction() == Direction::In && endpoint.max_packet_size() == mapping.packet_size {
-                    if endpoint.direction() == Direction::In {
-                        iet = Some((interface.number(), endpoint.address(), endpoint.transfer_type()));
-                        break;
-                    }
-                }
+        let mut endpoint = match find_input_endpoint(&device)? {
+            Some(value) => value,
+            None => {
+                let msg = format!("Device {} has no compatible endpoint", name);
+                return Err(Error::invalid(msg));
             }
+        };
+        let mut handle = device.open()?;
+        if handle.kernel_driver_active(endpoint.interface)? {
+            handle.detach_kernel_driver(endpoint.interface)?;
+            endpoint.kernel_driver = true;
         }
+        handle.claim_interface(endpoint.interface)?;
+        return Ok((handle, endpoint));
+    }
+    let msg = format!("Device {} not found at {}:{}", name, bus_number, address);
+    Err(io::Error::new(io::ErrorKind::NotFound, msg).into())
+}
+
+/// Releases the interface claimed by open, and attaches the kernel driver again when it was detached.
+pub fn close(handle: &mut DeviceHandle, endpoint: &InputEndpoint) -> Result<()> {
+    handle.release_interface(endpoint.interface)?;
+    if endpoint.kernel_driver {
+        handle.attach_kernel_driver(endpoint.interface)?;
+    }
+    Ok(())
+}
+
+/// Reads packets from the input endpoint of a device claimed through libusb.
+pub struct LibusbSource<'a, 'b: 'a> {
+    handle: &'a DeviceHandle<'b>,
+    endpoint: InputEndpoint,
+}
 
-        if iet.is_none() {
-            let msg = format!("Device {} has no compatible endpoint",mapping.name);
-            return Err(Error::new(ErrorKind::InvalidInput, msg));
+impl<'a, 'b> LibusbSource<'a, 'b> {
+    /// Creates source reading the endpoint of the opened device, the handle may be shared with outputs of the device.
+    pub fn new(handle: &'a DeviceHandle<'b>, endpoint: InputEndpoint) -> LibusbSource<'a, 'b> {
+        LibusbSource {
+            handle,
+            endpoint,
         }
-        handle = match device.open() {
-            Ok(handle) => Some(handle),
-            Err(err) => return Err(Error::new(ErrorKind::InvalidInput, err)),
-        };
-        break;
     }
-    match (handle, iet) {
-        (Some(mut handle), Some((i,e,t))) => {
-            if iotry!(handle.kernel_driver_active(i)) {
-                let _ = iotry!(handle.detach_kernel_driver(i));
-            }
-            let _ = iotry!(handle.claim_interface(i));
-            let mut input_buffer = vec![0u8;mapping.packet_size as usize];
-            let mut mapper = MapInput::new(mapping.digitals.len(), mapping.analogs.len());
-            loop {
-                match &t {
-                    &TransferType::Interrupt => {
-                        match handle.read_interrupt(e, &mut input_buffer, Duration::from_secs(4)) {
-                            Ok(_) => {},
-                            Err(libusb::Error::Timeout) => { continue; }
-                            Err(err) => {
-                                return Err(Error::new(ErrorKind::InvalidInput, err));
-                            }
-                        }
-                    }
-                    _ => {
-                        let msg = format!("Incompatible transfer method: {:?}", t);
-                        return Err(Error::new(ErrorKind::InvalidInput, msg));
-                    }
-                }
-                let mut res = String::new();
-                for b in &input_buffer {
-                    res = format!("{}{:08b} ",res, b);
-                }
-                for inp in mapper.generate_input(&mapping.digitals, &mapping.analogs, &input_buffer) {
-                    let _ = iotry!(input_sender.send(inp));
+}
+
+impl<'a, 'b> PacketSource for LibusbSource<'a, 'b> {
+    fn read_packet(&mut self, buffer: &mut [u8]) -> Result<Option<usize>> {
+        match &self.endpoint.transfer_type {
+            &TransferType::Interrupt => {
+                // short timeout, so the reading stops soon on shutdown
+                match self.handle.read_interrupt(self.endpoint.address, buffer, READ_TIMEOUT) {
+                    Ok(len) => Ok(Some(len)),
+                    Err(libusb::Error::Timeout) => Ok(None),
+                    Err(err) => Err(err.into()),
                 }
-                trace!("{}",res);
+            }
+            t => {
+                let msg = format!("Incompatible transfer method: {:?}", t);
+                Err(Error::invalid(msg))
+            }
+        }
+    }
+}
+
+/// Reads inputs from the source and sends them to the mapping, until the source fails or the shutdown is set.
+/// The shutdown is checked between the reads.
+pub fn run<S: InputSource, T: From<Input>>(source: &mut S, input_sender: &Sender<T>, shutdown: &AtomicBool) -> Result<()> {
+    while !shutdown.load(Ordering::SeqCst) {
+        for inp in source.read_inputs()? {
+            if input_sender.send(T::from(inp)).is_err() {
+                return Err(Error::disconnected("Mapping has stopped"));
             }
         }
-        _ => unreachable!(),
     }
     Ok(())
 }
@@ -1,111 +1,421 @@
-use std::io::{Result, Error, ErrorKind};
-use std::sync::mpsc::{Sender, Receiver};
+use std::sync::mpsc::{Sender, Receiver, RecvTimeoutError};
 use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::{thread};
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use std::collections::{HashMap, HashSet};
 use libxdo::XDo;
 
 use libusb::{Context, Direction};
 
-use device_mapping::{ DeviceMaps};
-use input::Input;
+use capture::CaptureWriter;
+use config::Source;
+use control::{self, Control, MappingMessage};
+use device_mapping::{DeviceInputUid, DeviceMap, DeviceMaps, DeviceIdentity, SourceKind, device_key};
+use device_output::{DeviceCommand, DeviceOutput, DeviceOutputs};
+use error::{Error, Recovery, Result};
+use evdev::{self, EvdevSource};
+use hidraw::HidrawSource;
+use hotplug::DeviceEvent;
+use input_source::PacketInput;
+use mapper::Mapper;
+use output::{HeldKeys, KeyOutput, OutputKind, PrintOutput};
 use profile_definition::{Profiles};
-use device_input::{self};
+use device_input::{self, LibusbSource};
+use sysfs;
 
 
+/// Where a mapped device is connected.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DeviceAddress {
+    /// Usb device at the bus number and address.
+    Usb(u8, u8),
+    /// Input device node.
+    Evdev(PathBuf),
+}
+
+impl fmt::Display for DeviceAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &DeviceAddress::Usb(bus_number, address) => write!(f, "{}-{}", bus_number, address),
+            &DeviceAddress::Evdev(ref path) => {
+                let name = path.file_name().map(|name| name.to_string_lossy().into_owned());
+                write!(f, "{}", name.unwrap_or(path.display().to_string()))
+            }
+        }
+    }
+}
+
+/// Connected device found by `list_devices`.
+pub struct ListedDevice {
+    pub address: DeviceAddress,
+    /// Key made from the vendor and product id of the device.
+    pub key: u32,
+    /// Name of the device reported by its driver, input devices only.
+    pub device_name: Option<String>,
+    /// Full name of the mapping the driver would use for the device.
+    pub mapping: Option<String>,
+    pub identity: DeviceIdentity,
+}
+
+/// Lists all connected usb and input devices with the mappings the driver would select for them.
+pub fn list_devices(mappings: &DeviceMaps) -> Result<Vec<ListedDevice>> {
+    let context = Context::new()?;
+    let mut res = Vec::new();
+    for device in context.devices()?.iter() {
+        let device_desc = device.device_descriptor()?;
+        let key = device_key(device_desc.vendor_id(), device_desc.product_id());
+        let identity = sysfs::identity(device.bus_number(), device.address());
+        let mapping = mappings.select(key, &identity)
+            .filter(|mapping| mapping.source != SourceKind::Evdev)
+            .map(|mapping| mapping.full_name());
+        res.push(ListedDevice {
+            address: DeviceAddress::Usb(device.bus_number(), device.address()),
+            key,
+            device_name: None,
+            mapping,
+            identity,
+        });
+    }
+    // the usb devices are listed also without the input devices
+    let input_devices = evdev::list().unwrap_or_else(|err| {
+        warn!("Failed to list input devices: {}", err);
+        Vec::new()
+    });
+    for device in input_devices {
+        let identity = sysfs::input_identity(&device.path);
+        let mapping = mappings.select(device.key, &identity)
+            .filter(|mapping| mapping.source == SourceKind::Evdev)
+            .filter(|mapping| mapping.device_name.as_ref().map(|name| device.name.contains(name.as_str())).unwrap_or(true))
+            .map(|mapping| mapping.full_name());
+        res.push(ListedDevice {
+            address: DeviceAddress::Evdev(device.path),
+            key: device.key,
+            device_name: Some(device.name),
+            mapping,
+            identity,
+        });
+    }
+    Ok(res)
+}
+
 pub struct DeviceManager {
     context: Context,
     mapping: DeviceMaps,
-    input_sender: Sender<Input>,
-    finished_sender: Sender<u16>,
-    finished_receiver: Receiver<u16>,
-    mapped: Vec<u16>,
+    input_sender: Sender<MappingMessage>,
+    finished_sender: Sender<(DeviceAddress, Result<()>)>,
+    finished_receiver: Receiver<(DeviceAddress, Result<()>)>,
+    mapped: Arc<Mutex<HashMap<DeviceAddress, String>>>,
+    skipped: HashSet<DeviceAddress>,
+    claimed: HashSet<DeviceAddress>,
+    retry: bool,
+    stops: HashMap<DeviceAddress, Arc<AtomicBool>>,
+    capture_dir: Option<PathBuf>,
+    outputs: Arc<Mutex<DeviceOutputs>>,
 }
 
-fn run_mappings(rcv: Receiver<Input>, profiles: Profiles, profile: Option<String>) {
-    let xdo = XDo::new(None).unwrap();
-    let (ref name, ref output) = match profile {
-        None => {
-            None
-        }
-        Some(name) => {
-            let name = name.to_lowercase();
-            let mut result = None;
-            for ref profile in profiles.profiles.iter() {
-                let pname = profile.name.to_lowercase();
-                if pname.starts_with(&name) {
-                    result = Some((&profile.name, &profile.modes[0].output));
-                    break;
+/// Maps the inputs of all devices to the output of the kind, until all the senders are dropped or it's stopped.
+/// Whether the key output was created is sent through the ready channel first.
+fn run_mappings(rcv: Receiver<MappingMessage>, profiles: Profiles, profile: Option<String>, output: OutputKind, outputs: Arc<Mutex<DeviceOutputs>>, ready: Sender<Result<()>>) {
+    let profile = profile.as_ref().map(|s| s.as_str());
+    match output {
+        OutputKind::Xdo => {
+            let xdo = match XDo::new(None) {
+                Ok(value) => value,
+                Err(err) => {
+                    let _ = ready.send(Err(Error::Output(Box::new(err))));
+                    return;
                 }
-            }
-            result
+            };
+            let _ = ready.send(Ok(()));
+            map_inputs(rcv, Mapper::new(profiles, profile, HeldKeys::new(xdo)), outputs);
         }
-    }.unwrap_or((&profiles.profiles[0].name, &profiles.profiles[0].modes[0].output));
-    info!("Profile selected: {}", name);
-    for inp in rcv.iter() {
-        // find name of the key
-        match inp {
-            Input::ButtonDown(uid) => {
-                output[uid as usize].execute(&xdo, 1.0, 0.0);
+        OutputKind::Print => {
+            let _ = ready.send(Ok(()));
+            map_inputs(rcv, Mapper::new(profiles, profile, HeldKeys::new(PrintOutput)), outputs);
+        }
+    }
+}
+
+/// Maps the received inputs with the mapper, until all the senders are dropped or it's stopped.
+fn map_inputs<O: KeyOutput>(rcv: Receiver<MappingMessage>, mut mapper: Mapper<HeldKeys<O>>, outputs: Arc<Mutex<DeviceOutputs>>) {
+    info!("Profile selected: {}", mapper.profile().name);
+    outputs.lock().unwrap().broadcast(mapper.device_state());
+    // control clients streaming the inputs
+    let mut subscribers = Vec::new();
+    for message in rcv.iter() {
+        match message {
+            MappingMessage::Input(inp) => {
+                subscribers.retain(|subscriber: &Sender<_>| subscriber.send(inp.clone()).is_ok());
+                mapper.handle(inp);
+            }
+            MappingMessage::Request(request, response_sender) => {
+                let (response, changed) = control::handle_request(&mut mapper, request, &mut subscribers);
+                if changed {
+                    outputs.lock().unwrap().broadcast(mapper.device_state());
+                }
+                let _ = response_sender.send(response);
             }
-            Input::ButtonUp(uid) => {
-                output[uid as usize].execute(&xdo, 0.0, 1.0);
+            MappingMessage::DeviceGone(uids) => {
+                mapper.release_inputs(&uids);
             }
-            Input::Axis(uid, value, old_value) => {
-                output[uid as usize].execute(&xdo, value, old_value);
+            MappingMessage::Stop(_done) => {
+                mapper.output().release_all();
+                break;
             }
         }
     }
 }
 
+/// Reads the device from the source selected in its mapping, until the device fails or the stop is set.
+/// Commands for outputs of the device are executed in parallel with the reading. The opened sender is dropped
+/// once the device is opened, or it has failed to open.
+fn run_device(address: &DeviceAddress, mapping: DeviceMap, input_sender: Sender<MappingMessage>, capture: Option<CaptureWriter>, commands: Option<Receiver<DeviceCommand>>, opened: Sender<()>, stop: &AtomicBool) -> Result<()> {
+    match (&mapping.source, address) {
+        (&SourceKind::Libusb, &DeviceAddress::Usb(bus_number, address)) => {
+            let context = Context::new()?;
+            let (mut handle, endpoint) = device_input::open(&context, bus_number, address, &mapping.name)?;
+            drop(opened);
+            let output_stop = AtomicBool::new(false);
+            let result = thread::scope(|scope| {
+                if let Some(commands) = commands {
+                    let mut output = DeviceOutput::new(&handle, &mapping);
+                    let output_stop = &output_stop;
+                    scope.spawn(move || output.run(&commands, output_stop));
+                }
+                let source = LibusbSource::new(&handle, endpoint);
+                let result = device_input::run(&mut PacketInput::new(source, mapping, capture), &input_sender, stop);
+                output_stop.store(true, Ordering::SeqCst);
+                result
+            });
+            // fails when the device was unplugged, there is nothing to give back then
+            if let Err(err) = device_input::close(&mut handle, &endpoint) {
+                debug!("Failed to release the interface of device at {}: {}", address, err);
+            }
+            result
+        }
+        (&SourceKind::Hidraw, &DeviceAddress::Usb(bus_number, address)) => {
+            let source = HidrawSource::open(bus_number, address, &mapping)?;
+            drop(opened);
+            device_input::run(&mut PacketInput::new(source, mapping, capture), &input_sender, stop)
+        }
+        (&SourceKind::Evdev, &DeviceAddress::Evdev(ref path)) => {
+            let mut source = EvdevSource::open(path, &mapping)?;
+            drop(opened);
+            device_input::run(&mut source, &input_sender, stop)
+        }
+        _ => {
+            let msg = format!("Device {} at {} can't be read with {:?} source", mapping.name, address, mapping.source);
+            Err(Error::invalid(msg))
+        }
+    }
+}
 
 impl DeviceManager {
-    pub fn new(mapping: DeviceMaps, profiles: Profiles, profile: Option<&str>) -> Result<DeviceManager> {
-        let context = iotry!(Context::new());
+    /// Creates the manager and starts the mapping, fails when the key output can't be created.
+    pub fn new(mapping: DeviceMaps, profiles: Profiles, profile: Option<&str>, output: OutputKind, capture_dir: Option<PathBuf>) -> Result<DeviceManager> {
+        let context = Context::new()?;
         let (input_sender, input_receiver) = mpsc::channel();
         let (finished_sender, finished_receiver) = mpsc::channel();
         let profile = profile.map(|s| s.to_owned());
         //let dev_maps = mapping.devices.values().map(|ref m| (*m).clone()).collect::<Vec<DeviceMap>>();
+        let outputs = Arc::new(Mutex::new(DeviceOutputs::default()));
+        let mapping_outputs = outputs.clone();
+        let (ready_sender, ready_receiver) = mpsc::channel();
         thread::spawn(move || {
-            run_mappings(input_receiver, profiles, profile);
+            run_mappings(input_receiver, profiles, profile, output, mapping_outputs, ready_sender);
         });
+        ready_receiver.recv().map_err(|_| Error::disconnected("Mapping has stopped"))??;
         Ok(DeviceManager {
             context, // context of usblib, which is used to find connected devices
             mapping, // definition of mapping raw data to keys and axes
             input_sender, // devices are sending input keys and axes using this channel
             finished_sender, // when a device is disconnected or some error, finishing thread will send its address through this
             finished_receiver, // when a device is disconnected or some error, finished threads addresses are received here
-            mapped: Vec::new(), // addresses of already mapped devices
+            mapped: Arc::new(Mutex::new(HashMap::new())), // addresses of already mapped devices and names of their mappings
+            skipped: HashSet::new(), // addresses of devices which failed and can't be used until they are reconnected
+            claimed: HashSet::new(), // addresses of running devices with interfaces claimed through libusb
+            retry: false, // some device failed and should be opened again
+            stops: HashMap::new(), // flags of the running device threads, set to stop them, they finish soon after
+            capture_dir, // when set, raw packets of every device are captured to this directory
+            outputs, // command channels of the devices with outputs, shared with the mapping
         })
     }
 
-    pub fn discover(&mut self) -> Result<()> {
+    /// Shared state for the control socket, it reloads the profiles from the source.
+    pub fn control(&self, profiles: Source, device_inputs: Vec<DeviceInputUid>) -> Control {
+        Control {
+            mapping_sender: self.input_sender.clone(),
+            mapped: self.mapped.clone(),
+            outputs: self.outputs.clone(),
+            profiles,
+            device_inputs,
+        }
+    }
+
+    /// Removes devices, whose threads have finished, from mapped. Fails when the driver can't continue.
+    fn remove_finished(&mut self) -> Result<()> {
         trace!("Removing finished devices");
-        // removing finished devices from mapped
-        for address in self.finished_receiver.try_iter() {
-            trace!("Device at {} has finished, removing it", address);
-            self.mapped.retain(|&a| a != address);
+        let finished: Vec<_> = self.finished_receiver.try_iter().collect();
+        for (address, result) in finished {
+            self.finish(address, result)?;
+        }
+        Ok(())
+    }
+
+    /// Forgets the device whose thread has finished. Devices which may work again are opened on the next
+    /// discovery, the others are skipped until reconnected. Fails when the driver can't continue.
+    fn finish(&mut self, address: DeviceAddress, result: Result<()>) -> Result<()> {
+        self.claimed.remove(&address);
+        self.stops.remove(&address);
+        self.outputs.lock().unwrap().remove(&address);
+        let name = self.mapped.lock().unwrap().remove(&address).unwrap_or_else(|| "device".to_owned());
+        let err = match result {
+            Ok(_) => return Ok(()),
+            Err(err) => err,
+        };
+        match err.recovery() {
+            Recovery::Retry => {
+                warn!("Device {} at {} failed, it will be opened again: {}", name, address, err);
+                self.retry = true;
+            }
+            Recovery::Skip => {
+                error!("Device {} at {} can't be used until it is reconnected: {}", name, address, err);
+                if let Some(hint) = err.hint() {
+                    error!("{}", hint);
+                }
+                self.skipped.insert(address);
+            }
+            Recovery::Exit => return Err(err),
+        }
+        Ok(())
+    }
+
+    /// Stops the thread of the removed device and waits a while until it has finished, so the device isn't
+    /// opened again before the thread has let it go. Fails when the driver can't continue.
+    fn stop(&mut self, address: &DeviceAddress) -> Result<()> {
+        let stop = match self.stops.get(address) {
+            Some(value) => value,
+            None => return Ok(()),
+        };
+        stop.store(true, Ordering::SeqCst);
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while self.stops.contains_key(address) {
+            let now = Instant::now();
+            if now >= deadline {
+                warn!("Device at {} was removed, but it has not stopped yet", address);
+                break;
+            }
+            // the removed device fails, it isn't retried or skipped
+            match self.finished_receiver.recv_timeout(deadline - now) {
+                Ok((ref finished, _)) if finished == address => self.finish(finished.clone(), Ok(()))?,
+                Ok((finished, result)) => self.finish(finished, result)?,
+                Err(_) => continue,
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes finished devices and discovers the devices again when polling or when some device should be retried.
+    pub fn poll(&mut self, polling: bool) -> Result<()> {
+        self.remove_finished()?;
+        if polling || self.retry {
+            self.retry = false;
+            self.discover()?;
+        }
+        Ok(())
+    }
+
+    /// Stops the device threads, waits a while for them to give the claimed interfaces back to the kernel drivers,
+    /// and releases the keys held by the mapping.
+    pub fn shutdown(&mut self) {
+        for stop in self.stops.values() {
+            stop.store(true, Ordering::SeqCst);
+        }
+        let deadline = Instant::now() + Duration::from_secs(3);
+        while !self.claimed.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                warn!("{} devices did not stop in time", self.claimed.len());
+                break;
+            }
+            if let Ok((address, _)) = self.finished_receiver.recv_timeout(deadline - now) {
+                trace!("Device at {} has stopped", address);
+                self.claimed.remove(&address);
+            }
+        }
+        let (done_sender, done_receiver) = mpsc::channel();
+        if self.input_sender.send(MappingMessage::Stop(done_sender)).is_ok() {
+            // the sender is dropped when the keys are released
+            let _ = done_receiver.recv_timeout(Duration::from_secs(1));
+        }
+    }
+
+    /// Handles connection or disconnection of a device.
+    pub fn handle_event(&mut self, event: DeviceEvent) -> Result<()> {
+        match event {
+            DeviceEvent::Arrived(address) => {
+                trace!("Device arrived at {}", address);
+                self.discover()
+            }
+            DeviceEvent::Removed(address) => {
+                let removed = self.mapped.lock().unwrap().get(&address).cloned();
+                if let Some(name) = removed {
+                    info!("Device {} at {} was removed", name, address);
+                }
+                self.stop(&address)?;
+                self.skipped.remove(&address);
+                self.remove_finished()
+            }
+            DeviceEvent::HidrawArrived(path) => {
+                trace!("Hidraw node arrived at {}", path.display());
+                self.discover()
+            }
+            DeviceEvent::HidrawRemoved(path) => {
+                // the device reading the node fails and finishes on its own
+                trace!("Hidraw node removed at {}", path.display());
+                self.remove_finished()
+            }
+        }
+    }
+
+    /// Finds the connected devices, which aren't mapped yet, and starts reading them. When it fails, the
+    /// discovery is tried again on the next poll.
+    pub fn discover(&mut self) -> Result<()> {
+        self.remove_finished()?;
+        let result = self.find_devices();
+        if result.is_err() {
+            self.retry = true;
         }
+        result
+    }
+
+    fn find_devices(&mut self) -> Result<()> {
         trace!("Finding devices");
         // search for new devices, which are not yet mapped
-        for device in iotry!(self.context.devices()).iter() {
-            let address = ((device.bus_number() as u16) << 8) + (device.address() as u16);
-            if self.mapped.contains(&address) {
-                continue; // this address is already mapped
+        let mut found = Vec::new();
+        for device in self.context.devices()?.iter() {
+            let address = DeviceAddress::Usb(device.bus_number(), device.address());
+            if self.mapped.lock().unwrap().contains_key(&address) || self.skipped.contains(&address) {
+                continue; // this address is already mapped, or it has failed
             }
 
-            let device_desc = iotry!(device.device_descriptor());
+            let device_desc = device.device_descriptor()?;
             // create key of the device, mapping definition is hashed by the vendor and product id
-            let key = ((device_desc.vendor_id() as u32) << 16) + (device_desc.product_id() as u32);
+            let key = device_key(device_desc.vendor_id(), device_desc.product_id());
 
-            let mapping = match self.mapping.devices.get(&key) {
-                Some(ref mapping) => (*mapping).clone(),
-                None => {
+            let mapping = match self.select(key, || sysfs::identity(device.bus_number(), device.address())) {
+                Some(ref mapping) if mapping.source != SourceKind::Evdev => (*mapping).clone(),
+                _ => {
                     continue;
                 }
             };
-            // find input interface
-            let cfg = iotry!(device.active_config_descriptor());
-            let mut ok = false;
+            // find input interface, hidraw reports don't have to match the usb packets
+            let cfg = device.active_config_descriptor()?;
+            let mut ok = mapping.source != SourceKind::Libusb;
             for interface in cfg.interfaces() {
                 for desc in interface.descriptors() {
                     for endpoint in desc.endpoint_descriptors() {
@@ -121,26 +431,98 @@ impl DeviceManager {
                 error!("Device {} has no compatible endpoint",mapping.name);
                 continue;
             }
+            found.push((address, key, mapping));
+        }
 
-            self.mapped.push(address);
-
-            let input_sender = self.input_sender.clone();
-            let finished_sender = self.finished_sender.clone();
-            let bus_number = device.bus_number();
-            let dev_address = device.address();
-            thread::spawn(move || {
-                info!("Running devices at {}", address);
-                match device_input::run(bus_number, dev_address, mapping, input_sender) {
-                    Ok(_) => {},
-                    Err(err) => {
-                        error!("Failed to run device input: {}", err);
+        // search for input devices, when some mapping wants them
+        if self.mapping.devices.values().any(|maps| maps[0].source == SourceKind::Evdev) {
+            for device in evdev::list()? {
+                let path = device.path;
+                let address = DeviceAddress::Evdev(path.clone());
+                if self.mapped.lock().unwrap().contains_key(&address) || self.skipped.contains(&address) {
+                    continue;
+                }
+                let mapping = match self.select(device.key, || sysfs::input_identity(&path)) {
+                    Some(ref mapping) if mapping.source == SourceKind::Evdev => (*mapping).clone(),
+                    _ => {
+                        continue;
                     }
+                };
+                let name_matches = match mapping.device_name {
+                    Some(ref name) => device.name.contains(name.as_str()),
+                    None => true,
+                };
+                if name_matches {
+                    found.push((address, device.key, mapping));
                 }
-                finished_sender.send(address).unwrap();
-            });
+            }
         }
 
+        // the devices are opened by their threads, they are waited for, so they are opened before
+        // the privileges are dropped
+        let opened: Vec<_> = found.into_iter().map(|(address, key, mapping)| self.spawn(address, key, mapping)).collect();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        for receiver in opened {
+            let now = Instant::now();
+            if now >= deadline || receiver.recv_timeout(deadline - now) == Err(RecvTimeoutError::Timeout) {
+                warn!("Some devices are still being opened");
+                break;
+            }
+        }
         Ok(())
     }
 
+    /// Finds mapping of the device, its identity is read only when the device has named instances.
+    fn select<F: FnOnce() -> DeviceIdentity>(&self, key: u32, identity: F) -> Option<&DeviceMap> {
+        let identity = if self.mapping.has_instances(key) { identity() } else { DeviceIdentity::default() };
+        let mapping = self.mapping.select(key, &identity)?;
+        if mapping.instance.is_none() && self.mapped.lock().unwrap().values().any(|name| *name == mapping.name) {
+            warn!("Another {} is connected, both share the same inputs, name their instances to tell them apart", mapping.name);
+        }
+        Some(mapping)
+    }
+
+    /// Starts thread reading the device and sending its inputs to the mapping. The returned channel
+    /// is disconnected when the thread has opened the device, or failed to open it.
+    fn spawn(&mut self, address: DeviceAddress, key: u32, mapping: DeviceMap) -> Receiver<()> {
+        self.mapped.lock().unwrap().insert(address.clone(), mapping.full_name());
+
+        let input_sender = self.input_sender.clone();
+        let finished_sender = self.finished_sender.clone();
+        let capture_dir = self.capture_dir.clone();
+        let stop = Arc::new(AtomicBool::new(false));
+        self.stops.insert(address.clone(), stop.clone());
+        // inputs of the device are released after it's gone, e.g. a key held while it was unplugged
+        let uids = mapping.uids();
+        if mapping.source == SourceKind::Libusb {
+            self.claimed.insert(address.clone());
+        }
+        // commands are routed only to the devices with outputs, the others don't need the channel
+        let commands = if mapping.lcd || mapping.backlight {
+            Some(self.outputs.lock().unwrap().register(address.clone(), &mapping.full_name()))
+        } else {
+            None
+        };
+        let (opened_sender, opened_receiver) = mpsc::channel();
+        thread::spawn(move || {
+            info!("Running device {} at {}", mapping.full_name(), address);
+            // only raw packets can be captured
+            let capture = capture_dir.filter(|_| mapping.source != SourceKind::Evdev).and_then(|dir| {
+                match CaptureWriter::create(&dir, key, &mapping, &address) {
+                    Ok(capture) => Some(capture),
+                    Err(err) => {
+                        error!("Failed to create capture of {}: {}", mapping.name, err);
+                        None
+                    }
+                }
+            });
+            let gone_sender = input_sender.clone();
+            let result = run_device(&address, mapping, input_sender, capture, commands, opened_sender, &stop);
+            let _ = gone_sender.send(MappingMessage::DeviceGone(uids));
+            // the manager is gone when the driver is exiting
+            let _ = finished_sender.send((address, result));
+        });
+        opened_receiver
+    }
+
 }
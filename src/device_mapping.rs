@@ -1,24 +1,99 @@
 extern crate serde_yaml;
-use std::io::Result;
-use std::fs::File;
-use std::io::{Error, ErrorKind};
-use std::collections::HashMap;
+use std::io;
+use std::collections::{BTreeMap, HashMap};
+use std::result;
+
+use config::{Format, FormatError, Source};
+use error::{Error, Result};
+use schema::{document, trace};
+use serde_json;
 
 /// All mapped devices hashed by their vendor_id and product_id.
 #[derive(Debug, Clone)]
 pub struct DeviceMaps {
-    /// All devices hashed by their (vendor_id << 8 + product_id)
-    pub devices: HashMap<u32, DeviceMap>, 
+    /// All devices hashed by their (vendor_id << 8 + product_id), the first mapping is used for
+    /// devices not matching any named instance, the others for each named instance.
+    pub devices: HashMap<u32, Vec<DeviceMap>>,
+}
+
+
+/// Where the input of a device is read from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SourceKind {
+    /// Interrupt transfers through libusb, the kernel driver is detached.
+    Libusb,
+    /// Linux hidraw device node, the kernel driver stays attached.
+    Hidraw,
+    /// Linux input device node, grabbed so its events reach only this driver.
+    Evdev,
 }
 
+impl SourceKind {
+    /// Parses the source name used in yaml files, libusb is the default.
+    fn new(name: Option<&str>) -> Option<SourceKind> {
+        match name {
+            None | Some("libusb") => Some(SourceKind::Libusb),
+            Some("hidraw") => Some(SourceKind::Hidraw),
+            Some("evdev") => Some(SourceKind::Evdev),
+            _ => None,
+        }
+    }
+}
+
+/// Named instance of a device, when more identical devices are connected.
+#[derive(Debug, Clone)]
+pub struct DeviceInstance {
+    /// Name of the instance, profiles refer to it as device@instance.
+    pub name: String,
+    /// Serial number of the device.
+    pub serial: Option<String>,
+    /// Usb port path of the device, e.g. 3-1.2 (see /sys/bus/usb/devices).
+    pub port: Option<String>,
+}
+
+/// Identity of a connected device, used to find its instance.
+#[derive(Debug, Default)]
+pub struct DeviceIdentity {
+    /// Serial number of the device, if it has one.
+    pub serial: Option<String>,
+    /// Usb port path of the device.
+    pub port: Option<String>,
+}
+
+impl DeviceInstance {
+    /// Returns true, when all the set properties of the instance match the device.
+    fn matches(&self, identity: &DeviceIdentity) -> bool {
+        let serial = match (&self.serial, &identity.serial) {
+            (&Some(ref expected), &Some(ref serial)) => expected == serial,
+            (&Some(_), &None) => false,
+            (&None, _) => true,
+        };
+        let port = match (&self.port, &identity.port) {
+            (&Some(ref expected), &Some(ref port)) => expected == port,
+            (&Some(_), &None) => false,
+            (&None, _) => true,
+        };
+        serial && port
+    }
+}
 
 /// Mapping of a device.
 #[derive(Debug, Clone)]
 pub struct DeviceMap {
     /// Name of the device.
     pub name: String,
+    /// Named instance of the device, none for devices not matching any named instance.
+    pub instance: Option<DeviceInstance>,
     /// Size of the input stream from the device. This is used as a chceck if everything is ok.
     pub packet_size: u16,
+    /// Where the input of the device is read from.
+    pub source: SourceKind,
+    /// Part of the name of the input device to grab, evdev source only.
+    pub device_name: Option<String>,
+    /// The device has the G13 display, written through libusb.
+    pub lcd: bool,
+    /// The device has the G13 backlight and mode LEDs, set through libusb.
+    pub backlight: bool,
     /// All the mapped digital inputs on the device.
     pub digitals: Vec<DeviceDigitalInput>,
     /// All the mapped analog inputs on the device.
@@ -36,6 +111,8 @@ pub struct DeviceDigitalInput {
     pub index: u8,
     /// Bit mask representing this input's pressed state.
     pub mask: u8,
+    /// Key code of the input, evdev source only.
+    pub code: Option<u16>,
 }
 
 /// Mapped analog input on a device. This have an interval of current state.
@@ -49,6 +126,36 @@ pub struct DeviceAnalogInput {
     pub index: u8,
     /// Interval of values to map the input byte from 0 to 255. (This will usually be -1 to +1)
     pub output: (f32, f32),
+    /// Absolute axis code of the input, evdev source only.
+    pub code: Option<u16>,
+}
+
+impl DeviceMap {
+    /// Name of the device including its instance, e.g. G13@left.
+    pub fn full_name(&self) -> String {
+        match self.instance {
+            Some(ref instance) => format!("{}@{}", self.name, instance.name),
+            None => self.name.to_owned(),
+        }
+    }
+
+    /// Uids of all the inputs of the device.
+    pub fn uids(&self) -> Vec<u16> {
+        self.digitals.iter().map(|digital| digital.uid).chain(self.analogs.iter().map(|analog| analog.uid)).collect()
+    }
+
+    /// Copy of the mapping for a named instance, with the uids of all inputs moved by the offset.
+    fn with_instance(&self, instance: DeviceInstance, uid_offset: u16) -> DeviceMap {
+        let mut res = self.clone();
+        res.instance = Some(instance);
+        for digital in &mut res.digitals {
+            digital.uid += uid_offset;
+        }
+        for analog in &mut res.analogs {
+            analog.uid += uid_offset;
+        }
+        res
+    }
 }
 
 impl DeviceAnalogInput {
@@ -61,40 +168,75 @@ impl DeviceAnalogInput {
 
 #[test]
 fn test_analog_convert() {
-    let input = DeviceAnalogInput { name: "Test".to_owned(), uid: 0, index: 0, output: (-1.0, 1.0) };
+    let input = DeviceAnalogInput { name: "Test".to_owned(), uid: 0, index: 0, output: (-1.0, 1.0), code: None };
 
     for i in 0..256 {
         println!("{} = {}", i, input.convert(i as u8));
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum DeviceInputUid {
     Digital(String, String, u16),
     Analog(String, String, u16),
 }
 
+impl DeviceInputUid {
+    /// Name of the device including its instance, name of the input and its unique id.
+    pub fn parts(&self) -> (&str, &str, u16) {
+        match self {
+            &DeviceInputUid::Digital(ref device, ref name, uid) => (device, name, uid),
+            &DeviceInputUid::Analog(ref device, ref name, uid) => (device, name, uid),
+        }
+    }
+}
+
 
 // These structs are used to read define mapping in yaml files.
 
 /// Device mapping read form yaml files.
 #[derive(Serialize, Deserialize)]
-struct DeviceMapDefinition {
+pub struct DeviceMapDefinition {
     /// Name of the device, the name is used to map profiles to this device.
     pub name: String,
-    /// Number of bytes in the input stream expected to read from the usb.
+    /// Number of bytes in the input stream expected to read from the usb, not used by evdev source.
+    #[serde(default)]
     pub packet_size: u16,
+    /// Where to read the input from, `libusb` (default), `hidraw` or `evdev`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// Part of the name of the input device to grab, evdev source only. The device must match the key too.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_name: Option<String>,
     /// Definition of individual digitals on the device mapped to individual bytes and mask
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub digitals: Option<Vec<DeviceButtonDefinition>>,
     /// Definition of individual bytes in the device input, mapped to digitals in bit order.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub bytes: Option<Vec<DeviceByteDefinition>>,
     /// Definition of individual analog inputs on the divece mapped to individual bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub analogs: Option<Vec<DeviceAnalogDefinition>>,
+    /// Definition of keys mapped to their event codes, evdev source only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keys: Option<Vec<DeviceKeyDefinition>>,
+    /// Definition of absolute axes mapped to their event codes, evdev source only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub axes: Option<Vec<DeviceAxisDefinition>>,
+    /// The device has the G13 display, libusb source only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lcd: Option<bool>,
+    /// The device has the G13 backlight and mode LEDs, libusb source only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backlight: Option<bool>,
+    /// Named instances of the device, each gets its own inputs, so profiles can tell them apart.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instances: Option<Vec<DeviceInstanceDefinition>>,
 }
 
 /// Individual button mapped to a byte in the input stream on a device.
 #[derive(Serialize, Deserialize)]
-struct DeviceButtonDefinition {
+pub struct DeviceButtonDefinition {
     /// Name of the digital.
     pub name: String,
     /// Index of the byte in device input stream.
@@ -105,7 +247,7 @@ struct DeviceButtonDefinition {
 
 /// Individual byte in the device input stream mapped to max of 8 digitals in bit order.
 #[derive(Serialize, Deserialize)]
-struct DeviceByteDefinition {
+pub struct DeviceByteDefinition {
     /// Index of the byte in the input stream.
     pub index: u8,
     /// Names of the digitals in bit order.
@@ -113,7 +255,7 @@ struct DeviceByteDefinition {
 }
 
 #[derive(Serialize, Deserialize)]
-struct DeviceAnalogDefinition {
+pub struct DeviceAnalogDefinition {
     /// Name of the analog input.
     pub name: String,
     /// Index of the byte in device input stream.
@@ -122,30 +264,148 @@ struct DeviceAnalogDefinition {
     pub output: (f32, f32),
 }
 
+/// Key of an input device mapped to its event code.
+#[derive(Serialize, Deserialize)]
+pub struct DeviceKeyDefinition {
+    /// Name of the key.
+    pub name: String,
+    /// Event code of the key, e.g. 30 for KEY_A (see linux/input-event-codes.h).
+    pub code: u16,
+}
+
+/// Absolute axis of an input device mapped to its event code.
+#[derive(Serialize, Deserialize)]
+pub struct DeviceAxisDefinition {
+    /// Name of the axis.
+    pub name: String,
+    /// Event code of the axis, e.g. 0 for ABS_X (see linux/input-event-codes.h).
+    pub code: u16,
+    /// Interval of values to map the axis range to. (This will usually be -1 to +1)
+    pub output: (f32, f32),
+}
+
+/// Named instance of a device, matched by serial number, port path or both.
+#[derive(Serialize, Deserialize)]
+pub struct DeviceInstanceDefinition {
+    /// Name of the instance.
+    pub name: String,
+    /// Serial number of the device.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    /// Usb port path of the device, e.g. 3-1.2 (see /sys/bus/usb/devices).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<String>,
+}
+
 impl DeviceMapDefinition {
-    /// Creates the device mapping definition from yaml file.
-    fn new(file_path: &str) -> Result<HashMap<u32, DeviceMapDefinition>> {
-        let mut file = File::open(file_path)?;
-        match serde_yaml::from_reader(&mut file) {
+    /// Creates the device mapping definition from the file in any format, or the bundled definition.
+    fn new(source: &Source) -> Result<HashMap<u32, DeviceMapDefinition>> {
+        let text = source.read_to_string()?;
+        match DeviceMapDefinition::parse(&text, source.format()) {
             Ok(value) => Ok(value),
-            Err(err) => Err(Error::new(ErrorKind::InvalidData, err)),
+            Err(err) => Err(Error::ConfigParse(source.to_string(), err)),
+        }
+    }
+
+    /// Parses the definitions hashed by the device key. Toml and json can't have numeric keys, the devices
+    /// are given as VID:PID in hex there.
+    pub fn parse(text: &str, format: Format) -> result::Result<HashMap<u32, DeviceMapDefinition>, FormatError> {
+        if format == Format::Yaml {
+            return format.parse(text);
+        }
+        let defs: HashMap<String, DeviceMapDefinition> = format.parse(text)?;
+        defs.into_iter().map(|(device, def)| match parse_vid_pid(&device) {
+            Some((vid, pid)) => Ok((device_key(vid, pid), def)),
+            None => Err(From::from(format!("invalid device {}, expected VID:PID in hex", device))),
+        }).collect()
+    }
+
+    /// Converts the definitions of the devices from one format to another, sorted by the device key.
+    pub fn convert(text: &str, from: Format, to: Format) -> result::Result<String, FormatError> {
+        let defs = DeviceMapDefinition::parse(text, from)?;
+        if to == Format::Yaml {
+            let mut keys: Vec<&u32> = defs.keys().collect();
+            keys.sort();
+            let entries = keys.into_iter().map(|key| defs[key].to_yaml_entry(*key)).collect::<io::Result<Vec<_>>>()?;
+            return Ok(entries.join("\n"));
+        }
+        let defs: BTreeMap<String, &DeviceMapDefinition> = defs.iter()
+            .map(|(key, def)| (format!("{:04x}:{:04x}", key >> 16, key & 0xffff), def))
+            .collect();
+        to.write(&defs)
+    }
+
+    /// Json schema of a devices file, the definitions keyed by the device, as 0xVVVVPPPP in yaml or VID:PID.
+    pub fn schema() -> result::Result<serde_json::Value, String> {
+        let mut definitions = serde_json::Map::new();
+        let device = trace::<DeviceMapDefinition>(&mut definitions)?;
+        let root = json!({
+            "type": "object",
+            "propertyNames": {"pattern": "^(0x[0-9a-fA-F]{1,8}|[0-9]+|[0-9a-fA-F]{4}:[0-9a-fA-F]{4})$"},
+            "additionalProperties": device,
+        });
+        Ok(document("game-kb-driver devices", root, definitions))
+    }
+
+    /// Renders the definition as an entry of devices.yaml under the key of the device.
+    pub fn to_yaml_entry(&self, key: u32) -> io::Result<String> {
+        let body = match serde_yaml::to_string(self) {
+            Ok(value) => value,
+            Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+        };
+        let mut res = format!("0x{:08x}:\n", key);
+        for line in body.lines().filter(|line| *line != "---") {
+            res = format!("{}  {}\n", res, line.trim_end());
+        }
+        Ok(res)
+    }
+}
+
+/// Creates the key of a device, mapping definitions are hashed by the vendor and product id.
+pub fn device_key(vendor_id: u16, product_id: u16) -> u32 {
+    ((vendor_id as u32) << 16) + (product_id as u32)
+}
+
+/// Parses vendor and product id written as hexadecimal `VID:PID`, e.g. `046d:c21c`.
+pub fn parse_vid_pid(value: &str) -> Option<(u16, u16)> {
+    let mut parts = value.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(vid), Some(pid)) => {
+            match (u16::from_str_radix(vid, 16), u16::from_str_radix(pid, 16)) {
+                (Ok(vid), Ok(pid)) => Some((vid, pid)),
+                _ => None,
+            }
         }
+        _ => None,
     }
 }
 
 impl DeviceMaps {
-    /// Creates the device mapping from yaml file.
-    pub fn new(file_path: &str) -> Result<DeviceMaps> {
+    /// Creates the device mapping from yaml file or the bundled definition.
+    pub fn new(source: &Source) -> Result<DeviceMaps> {
         // read the file
-        let mut def = DeviceMapDefinition::new(&file_path)?;
+        let def = DeviceMapDefinition::new(source)?;
+        DeviceMaps::from_definition(def)
+    }
+
+    /// Creates the device mapping from the definitions hashed by the device key.
+    pub fn from_definition(mut def: HashMap<u32, DeviceMapDefinition>) -> Result<DeviceMaps> {
         // convert the definition in to list of digitals and axis with unique id mapped to device
         // name and digital name
         let mut uid = 0;
         let mut devices = HashMap::new();
         for (product_key, mapping) in def.drain() {
+            let first_uid = uid;
             let mut digitals = Vec::new();
             let packet_size = mapping.packet_size;
             let name = mapping.name;
+            let source = match SourceKind::new(mapping.source.as_ref().map(|s| s.as_str())) {
+                Some(value) => value,
+                None => {
+                    let msg = format!("Mapping for device: {} has unknown source: {}", name, mapping.source.unwrap());
+                    return Err(Error::invalid(msg));
+                }
+            };
             // convert individual digitals
             match mapping.digitals {
                 Some(mut mdigitals) => {
@@ -156,6 +416,7 @@ impl DeviceMaps {
                                 uid,
                                 index: digital.index,
                                 mask: digital.mask,
+                                code: None,
                             });
                         uid += 1;
                     }
@@ -170,7 +431,7 @@ impl DeviceMaps {
                         let index = byte.index;
                         if byte.names.len() > 8 {
                             let msg = format!("Mapping for device: {} has invalid number of names in byte: {}", name, index);
-                            return Err(Error::new(ErrorKind::InvalidData, msg));
+                            return Err(Error::invalid(msg));
                         }
                         for name in byte.names.drain(..) {
                             if &name != "-" {
@@ -180,6 +441,7 @@ impl DeviceMaps {
                                         uid,
                                         index,
                                         mask,
+                                        code: None,
                                     });
                                 uid += 1;
                             }
@@ -198,36 +460,145 @@ impl DeviceMaps {
                             uid,
                             index: analog.index,
                             output: analog.output,
+                            code: None,
                         });
                         uid += 1;
                     }
                 }
                 None => (),
             }
-            devices.insert( product_key,
-                DeviceMap {
-                    name,
-                    packet_size,
-                    digitals,
-                    analogs,
+            // convert keys and axes of input devices
+            let evdev_only = mapping.keys.is_some() || mapping.axes.is_some();
+            let packets_only = !digitals.is_empty() || !analogs.is_empty();
+            if (source == SourceKind::Evdev && packets_only) || (source != SourceKind::Evdev && evdev_only) {
+                let msg = format!("Mapping for device: {} must use keys and axes with evdev source, digitals, bytes and analogs otherwise", name);
+                return Err(Error::invalid(msg));
+            }
+            for key in mapping.keys.unwrap_or(Vec::new()) {
+                digitals.push(
+                    DeviceDigitalInput {
+                        name: key.name,
+                        uid,
+                        index: 0,
+                        mask: 0,
+                        code: Some(key.code),
+                    });
+                uid += 1;
+            }
+            for axis in mapping.axes.unwrap_or(Vec::new()) {
+                analogs.push( DeviceAnalogInput {
+                    name: axis.name,
+                    uid,
+                    index: 0,
+                    output: axis.output,
+                    code: Some(axis.code),
                 });
+                uid += 1;
+            }
+            let lcd = mapping.lcd.unwrap_or(false);
+            let backlight = mapping.backlight.unwrap_or(false);
+            if (lcd || backlight) && source != SourceKind::Libusb {
+                let msg = format!("Mapping for device: {} can use the display and backlight only with libusb source", name);
+                return Err(Error::invalid(msg));
+            }
+            let generic = DeviceMap {
+                name,
+                instance: None,
+                packet_size,
+                source,
+                device_name: mapping.device_name,
+                lcd,
+                backlight,
+                digitals,
+                analogs,
+            };
+            // every instance gets its own range of uids
+            let uid_count = uid - first_uid;
+            let mut maps = Vec::new();
+            for instance in mapping.instances.unwrap_or(Vec::new()) {
+                if instance.serial.is_none() && instance.port.is_none() {
+                    let msg = format!("Instance {} of device: {} needs serial or port", instance.name, generic.name);
+                    return Err(Error::invalid(msg));
+                }
+                let instance = DeviceInstance {
+                    name: instance.name,
+                    serial: instance.serial,
+                    port: instance.port,
+                };
+                maps.push(generic.with_instance(instance, uid - first_uid));
+                uid += uid_count;
+            }
+            maps.insert(0, generic);
+            devices.insert(product_key, maps);
         }
         Ok(DeviceMaps{
             devices
         })
     }
 
-    /// Returns list of all digital and analog inputs and their unique ids.
+    /// Returns true, when the device with the key has named instances.
+    pub fn has_instances(&self, key: u32) -> bool {
+        self.devices.get(&key).map(|maps| maps.len() > 1).unwrap_or(false)
+    }
+
+    /// Finds mapping of the device, the named instance matching its identity or the generic mapping.
+    pub fn select(&self, key: u32, identity: &DeviceIdentity) -> Option<&DeviceMap> {
+        let maps = self.devices.get(&key)?;
+        maps.iter()
+            .skip(1)
+            .find(|map| map.instance.as_ref().map(|instance| instance.matches(identity)).unwrap_or(false))
+            .or(maps.first())
+    }
+
+    /// Returns list of all digital and analog inputs and their unique ids, device names include the instance.
     pub fn get_inputs(&self) -> Vec<DeviceInputUid> {
         let mut res = Vec::new();
-        for ref device in self.devices.values() {
+        for device in self.devices.values().flat_map(|maps| maps.iter()) {
+            let name = device.full_name();
             for digital in &device.digitals {
-                res.push(DeviceInputUid::Digital(device.name.to_owned(), digital.name.to_owned(), digital.uid));
+                res.push(DeviceInputUid::Digital(name.to_owned(), digital.name.to_owned(), digital.uid));
             }
             for analog in &device.analogs {
-                res.push(DeviceInputUid::Analog(device.name.to_owned(), analog.name.to_owned(), analog.uid));
+                res.push(DeviceInputUid::Analog(name.to_owned(), analog.name.to_owned(), analog.uid));
             }
         }
         res
     }
 }
+
+#[test]
+fn test_instances() {
+    let yaml = "0x046dc21c:
+  name: G13
+  bytes:
+    - index: 3
+      names: [G1, G2]
+  instances:
+    - name: left
+      serial: A1
+    - name: right
+      port: 3-1.2
+";
+    let def: HashMap<u32, DeviceMapDefinition> = serde_yaml::from_str(yaml).unwrap();
+    let maps = DeviceMaps::from_definition(def).unwrap();
+    let uids = |identity: DeviceIdentity| {
+        let map = maps.select(0x046dc21c, &identity).unwrap();
+        (map.full_name(), map.digitals.iter().map(|d| d.uid).collect::<Vec<u16>>())
+    };
+    assert_eq!(uids(DeviceIdentity::default()), ("G13".to_owned(), vec![0, 1]));
+    assert_eq!(uids(DeviceIdentity { serial: Some("A1".to_owned()), port: None }), ("G13@left".to_owned(), vec![2, 3]));
+    assert_eq!(uids(DeviceIdentity { serial: Some("B2".to_owned()), port: Some("3-1.2".to_owned()) }), ("G13@right".to_owned(), vec![4, 5]));
+    assert_eq!(maps.get_inputs().len(), 6);
+}
+
+#[test]
+fn test_convert_devices() {
+    let yaml = include_str!("../devices.yaml");
+    let expected = DeviceMapDefinition::convert(yaml, Format::Yaml, Format::Yaml).unwrap();
+    for &format in &[Format::Toml, Format::Json] {
+        let text = DeviceMapDefinition::convert(yaml, Format::Yaml, format).unwrap();
+        assert!(text.contains("046d:c21c"));
+        assert_eq!(DeviceMapDefinition::convert(&text, format, Format::Yaml).unwrap(), expected);
+    }
+    assert!(DeviceMapDefinition::parse("{\"G13\": {\"name\": \"G13\"}}", Format::Json).is_err());
+}
@@ -0,0 +1,348 @@
+use std::io;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use libusb::DeviceHandle;
+
+use device_manager::DeviceAddress;
+use device_mapping::DeviceMap;
+use error::Result;
+use lcd::{G13Lcd, Image, LcdSink};
+
+/// Class request to the interface, from host to device.
+const HID_SET_REPORT_TYPE: u8 = 0x21;
+/// HID SET_REPORT request.
+const HID_SET_REPORT: u8 = 0x09;
+/// Report type of the feature reports in the value of SET_REPORT.
+const HID_FEATURE_REPORT: u16 = 0x300;
+/// Feature report setting the G13 mode LEDs.
+const G13_LEDS_REPORT: u16 = 0x05;
+/// Feature report setting the G13 backlight colour.
+const G13_BACKLIGHT_REPORT: u16 = 0x07;
+
+/// Mode LEDs of the G13, in the bit order of the LEDs report.
+pub const LED_NAMES: [&str; 4] = ["M1", "M2", "M3", "MR"];
+
+/// Colour of a backlight.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Color {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+impl Color {
+    /// Parses colour written as `#rrggbb`.
+    pub fn parse(text: &str) -> Option<Color> {
+        if !text.starts_with('#') || text.len() != 7 {
+            return None;
+        }
+        let component = |i: usize| u8::from_str_radix(text.get(i..i + 2)?, 16).ok();
+        Some(Color {
+            red: component(1)?,
+            green: component(3)?,
+            blue: component(5)?,
+        })
+    }
+}
+
+/// Parses names of the mode LEDs into the bits of the LEDs report.
+pub fn parse_leds<S: AsRef<str>>(names: &[S]) -> Option<u8> {
+    let mut res = 0;
+    for name in names {
+        let index = LED_NAMES.iter().position(|led| led.eq_ignore_ascii_case(name.as_ref()))?;
+        res |= 1 << index;
+    }
+    Some(res)
+}
+
+/// Command for the outputs of a device.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceCommand {
+    /// Sets colour of the backlight.
+    Backlight(Color),
+    /// Turns on the mode LEDs with the bits set, the others off.
+    Leds(u8),
+    /// Shows the frame on the display.
+    Frame(Image),
+    /// Sends the feature report, the data start with the report id.
+    FeatureReport(Vec<u8>),
+}
+
+impl DeviceCommand {
+    /// Kind of the state the command sets, the last command of each kind is kept for devices connected later.
+    /// Raw feature reports don't set any known state, so they are not kept.
+    fn state_kind(&self) -> Option<u8> {
+        match self {
+            &DeviceCommand::Backlight(_) => Some(0),
+            &DeviceCommand::Leds(_) => Some(1),
+            &DeviceCommand::Frame(_) => Some(2),
+            &DeviceCommand::FeatureReport(_) => None,
+        }
+    }
+}
+
+/// Sends control and interrupt transfers to a device.
+pub trait OutputTransport {
+    /// Writes the data with a control transfer to the device.
+    fn write_control(&self, request_type: u8, request: u8, value: u16, index: u16, data: &[u8]) -> Result<()>;
+    /// Writes the data to the interrupt output endpoint.
+    fn write_interrupt(&self, endpoint: u8, data: &[u8]) -> Result<()>;
+}
+
+/// Checks all the data were written.
+fn check_written(len: usize, data: &[u8]) -> Result<()> {
+    if len != data.len() {
+        let msg = format!("Only {} of {} bytes were written", len, data.len());
+        return Err(io::Error::new(io::ErrorKind::WriteZero, msg).into());
+    }
+    Ok(())
+}
+
+impl<'a> OutputTransport for DeviceHandle<'a> {
+    fn write_control(&self, request_type: u8, request: u8, value: u16, index: u16, data: &[u8]) -> Result<()> {
+        let len = DeviceHandle::write_control(self, request_type, request, value, index, data, Duration::from_secs(1))?;
+        check_written(len, data)
+    }
+
+    fn write_interrupt(&self, endpoint: u8, data: &[u8]) -> Result<()> {
+        let len = DeviceHandle::write_interrupt(self, endpoint, data, Duration::from_secs(1))?;
+        check_written(len, data)
+    }
+}
+
+/// Records the transfers instead of sending them, used in tests.
+#[cfg(test)]
+#[derive(Default)]
+pub struct RecordTransport {
+    /// Request type, request, value, index and data of the control transfers.
+    pub transfers: ::std::cell::RefCell<Vec<(u8, u8, u16, u16, Vec<u8>)>>,
+    /// Endpoint and data of the interrupt transfers.
+    pub interrupts: ::std::cell::RefCell<Vec<(u8, Vec<u8>)>>,
+}
+
+#[cfg(test)]
+impl OutputTransport for RecordTransport {
+    fn write_control(&self, request_type: u8, request: u8, value: u16, index: u16, data: &[u8]) -> Result<()> {
+        self.transfers.borrow_mut().push((request_type, request, value, index, data.to_vec()));
+        Ok(())
+    }
+
+    fn write_interrupt(&self, endpoint: u8, data: &[u8]) -> Result<()> {
+        self.interrupts.borrow_mut().push((endpoint, data.to_vec()));
+        Ok(())
+    }
+}
+
+/// Sends G13 feature report, its data always start with 5 whatever the report id is.
+fn set_g13_report<T: OutputTransport>(transport: &T, report: u16, data: [u8; 4]) -> Result<()> {
+    let data = [5, data[0], data[1], data[2], data[3]];
+    transport.write_control(HID_SET_REPORT_TYPE, HID_SET_REPORT, HID_FEATURE_REPORT | report, 0, &data)
+}
+
+/// Outputs of one connected device, executes the commands the device supports.
+pub struct DeviceOutput<'a, T: OutputTransport + 'a> {
+    transport: &'a T,
+    name: String,
+    lcd: Option<G13Lcd<'a, T>>,
+    backlight: bool,
+}
+
+impl<'a, T: OutputTransport> DeviceOutput<'a, T> {
+    /// Creates outputs of the device described by its mapping, its display is switched on.
+    pub fn new(transport: &'a T, mapping: &DeviceMap) -> DeviceOutput<'a, T> {
+        let name = mapping.full_name();
+        let lcd = if mapping.lcd {
+            // the display is not essential, the other outputs are used anyway
+            match G13Lcd::new(transport) {
+                Ok(value) => Some(value),
+                Err(err) => {
+                    error!("Failed to initialize display of {}: {}", name, err);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        DeviceOutput {
+            transport,
+            name,
+            lcd,
+            backlight: mapping.backlight,
+        }
+    }
+
+    /// Executes the command, commands for outputs the device doesn't have are ignored.
+    pub fn execute(&mut self, command: &DeviceCommand) -> Result<()> {
+        match command {
+            &DeviceCommand::Backlight(color) if self.backlight => {
+                set_g13_report(self.transport, G13_BACKLIGHT_REPORT, [color.red, color.green, color.blue, 0])
+            }
+            &DeviceCommand::Leds(leds) if self.backlight => {
+                set_g13_report(self.transport, G13_LEDS_REPORT, [leds, 0, 0, 0])
+            }
+            &DeviceCommand::Frame(ref frame) => match self.lcd {
+                Some(ref mut lcd) => lcd.write_frame(frame),
+                None => Ok(()),
+            },
+            &DeviceCommand::FeatureReport(ref data) if !data.is_empty() => {
+                let value = HID_FEATURE_REPORT | data[0] as u16;
+                self.transport.write_control(HID_SET_REPORT_TYPE, HID_SET_REPORT, value, 0, data)
+            }
+            _ => {
+                trace!("{} ignores {:?}", self.name, command);
+                Ok(())
+            }
+        }
+    }
+
+    /// Executes received commands, until the stop flag is set.
+    pub fn run(&mut self, commands: &Receiver<DeviceCommand>, stop: &AtomicBool) {
+        while !stop.load(Ordering::SeqCst) {
+            match commands.recv_timeout(Duration::from_millis(200)) {
+                Ok(command) => {
+                    if let Err(err) = self.execute(&command) {
+                        error!("Failed to execute {:?} on {}: {}", command, self.name, err);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    }
+}
+
+/// Command channel of a connected device.
+struct ConnectedOutput {
+    /// Name of the device including its instance.
+    name: String,
+    sender: Sender<DeviceCommand>,
+}
+
+/// Returns true when the device is the target, either by its full name or by the name without the instance.
+fn is_target(target: &str, name: &str) -> bool {
+    name == target || name.split('@').next() == Some(target)
+}
+
+/// Command channels of the connected devices with outputs, commands are routed to the devices by their names.
+#[derive(Default)]
+pub struct DeviceOutputs {
+    devices: HashMap<DeviceAddress, ConnectedOutput>,
+    /// The last state commands sent to all the devices (no target) or to a named device,
+    /// they are sent again to devices when they connect.
+    state: Vec<(Option<String>, DeviceCommand)>,
+}
+
+impl DeviceOutputs {
+    /// Creates command channel of the device, the kept state commands are already queued in it.
+    pub fn register(&mut self, address: DeviceAddress, name: &str) -> Receiver<DeviceCommand> {
+        let (sender, receiver) = mpsc::channel();
+        for &(ref target, ref command) in &self.state {
+            if target.as_ref().map(|target| is_target(target, name)).unwrap_or(true) {
+                let _ = sender.send(command.clone());
+            }
+        }
+        self.devices.insert(address, ConnectedOutput {
+            name: name.to_owned(),
+            sender,
+        });
+        receiver
+    }
+
+    /// Removes command channel of a disconnected device.
+    pub fn remove(&mut self, address: &DeviceAddress) {
+        self.devices.remove(address);
+    }
+
+    /// Sends the command to the devices with the name, or to all devices without a target. The command
+    /// is kept for the devices connected later, commands for devices whose thread has finished are dropped.
+    /// Returns the number of devices the command was sent to.
+    pub fn send(&mut self, target: Option<&str>, command: DeviceCommand) -> usize {
+        let mut sent = 0;
+        let mut finished = Vec::new();
+        for (address, device) in &self.devices {
+            if !target.map(|target| is_target(target, &device.name)).unwrap_or(true) {
+                continue;
+            }
+            match device.sender.send(command.clone()) {
+                Ok(_) => sent += 1,
+                // the device thread has already finished, its address is removed later too
+                Err(_) => finished.push(address.clone()),
+            }
+        }
+        for address in finished {
+            trace!("Dropping {:?} for finished device at {}", command, address);
+            self.devices.remove(&address);
+        }
+        // the new state replaces the same state of the target, the state for all devices replaces every one
+        if let Some(kind) = command.state_kind() {
+            self.state.retain(|&(ref state_target, ref state)| {
+                state.state_kind() != Some(kind) || (target.is_some() && state_target.as_ref().map(|t| t.as_str()) != target)
+            });
+            self.state.push((target.map(|target| target.to_owned()), command));
+        }
+        sent
+    }
+
+    /// Sends the commands to all the devices.
+    pub fn broadcast(&mut self, commands: Vec<DeviceCommand>) {
+        for command in commands {
+            self.send(None, command);
+        }
+    }
+}
+
+#[test]
+fn test_execute_commands() {
+    use device_mapping::SourceKind;
+
+    let transport = RecordTransport::default();
+    let mapping = DeviceMap {
+        name: "G13".to_owned(),
+        instance: None,
+        packet_size: 8,
+        source: SourceKind::Libusb,
+        device_name: None,
+        lcd: false,
+        backlight: true,
+        digitals: Vec::new(),
+        analogs: Vec::new(),
+    };
+    let mut output = DeviceOutput::new(&transport, &mapping);
+    let color = Color::parse("#ff8000").unwrap();
+    output.execute(&DeviceCommand::Backlight(color)).unwrap();
+    output.execute(&DeviceCommand::Leds(parse_leds(&["M2", "mr"]).unwrap())).unwrap();
+    output.execute(&DeviceCommand::Frame(Image::frame())).unwrap();
+    output.execute(&DeviceCommand::FeatureReport(vec![7, 1, 2])).unwrap();
+    assert_eq!(*transport.transfers.borrow(), vec![
+        (0x21, 9, 0x307, 0, vec![5, 0xff, 0x80, 0x00, 0]),
+        (0x21, 9, 0x305, 0, vec![5, 0x0a, 0, 0, 0]),
+        (0x21, 9, 0x307, 0, vec![7, 1, 2]),
+    ]);
+    // device without the display ignores the frames
+    assert!(transport.interrupts.borrow().is_empty());
+    assert_eq!(Color::parse("ff8000"), None);
+    assert_eq!(Color::parse("#ff80zz"), None);
+    assert_eq!(parse_leds(&["M4"]), None);
+}
+
+#[test]
+fn test_device_outputs() {
+    let mut outputs = DeviceOutputs::default();
+    let left = outputs.register(DeviceAddress::Usb(1, 2), "G13@left");
+    outputs.broadcast(vec![DeviceCommand::Leds(1)]);
+    // state for a disconnected device is kept until it connects
+    assert_eq!(outputs.send(Some("G13@right"), DeviceCommand::Leds(2)), 0);
+    assert_eq!(outputs.send(Some("G13"), DeviceCommand::Backlight(Color { red: 1, green: 2, blue: 3 })), 1);
+    // device connected later gets the kept state, its own after the one for all devices
+    let right = outputs.register(DeviceAddress::Usb(1, 3), "G13@right");
+    outputs.remove(&DeviceAddress::Usb(1, 2));
+    outputs.broadcast(vec![DeviceCommand::Leds(4)]);
+    let color = DeviceCommand::Backlight(Color { red: 1, green: 2, blue: 3 });
+    assert_eq!(left.try_iter().collect::<Vec<_>>(), vec![DeviceCommand::Leds(1), color.clone()]);
+    assert_eq!(right.try_iter().collect::<Vec<_>>(), vec![DeviceCommand::Leds(1), DeviceCommand::Leds(2), color.clone(), DeviceCommand::Leds(4)]);
+    // the state for all devices replaced the named one
+    assert_eq!(outputs.state, vec![(Some("G13".to_owned()), color), (None, DeviceCommand::Leds(4))]);
+}
@@ -0,0 +1,132 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+
+use libusb;
+
+use config::FormatError;
+
+/// Errors of the driver, the source error is kept so callers can tell what failed.
+#[derive(Debug)]
+pub enum Error {
+    /// Libusb call or usb transfer failed.
+    Usb(libusb::Error),
+    /// Reading or writing a file or a device node failed.
+    Io(io::Error),
+    /// Configuration file isn't valid yaml, toml or json, or doesn't have the expected structure.
+    ConfigParse(String, FormatError),
+    /// Configuration was read, but its content is invalid.
+    ConfigValidation(String),
+    /// Backend simulating the key presses failed.
+    Output(Box<dyn error::Error + Send + Sync>),
+    /// Control socket failed or a thread receiving messages has stopped.
+    Ipc(io::Error),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// How to get the permission to open the devices.
+const PERMISSION_HINT: &str = "The user can't open the device, run `sudo game-kb-driver install-udev-rules` and reconnect \
+the device, or start the driver as root with --user to drop the privileges after the devices are opened";
+
+/// What the device manager does with a device whose thread has failed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Recovery {
+    /// The device was probably unplugged or is busy, it is opened again when found.
+    Retry,
+    /// The device can't be used as it is, e.g. without permission, it is skipped until it is reconnected.
+    Skip,
+    /// The driver can't continue.
+    Exit,
+}
+
+impl Error {
+    /// Creates validation error of the configuration.
+    pub fn invalid<S: Into<String>>(msg: S) -> Error {
+        Error::ConfigValidation(msg.into())
+    }
+
+    /// Creates error when a channel to another thread was closed.
+    pub fn disconnected(msg: &str) -> Error {
+        Error::Ipc(io::Error::new(io::ErrorKind::BrokenPipe, msg))
+    }
+
+    /// Advice how to fix the error, for the errors the user can fix.
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            &Error::Usb(libusb::Error::Access) => Some(PERMISSION_HINT),
+            &Error::Io(ref err) if err.kind() == io::ErrorKind::PermissionDenied => Some(PERMISSION_HINT),
+            _ => None,
+        }
+    }
+
+    /// How to recover from the error in a device thread.
+    pub fn recovery(&self) -> Recovery {
+        match self {
+            &Error::Usb(libusb::Error::Access) | &Error::Usb(libusb::Error::NotSupported) => Recovery::Skip,
+            &Error::Usb(_) => Recovery::Retry,
+            &Error::Io(ref err) => match err.kind() {
+                io::ErrorKind::PermissionDenied | io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => Recovery::Skip,
+                _ => Recovery::Retry,
+            },
+            &Error::ConfigParse(..) | &Error::ConfigValidation(_) => Recovery::Skip,
+            &Error::Output(_) | &Error::Ipc(_) => Recovery::Exit,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Error::Usb(ref err) => write!(f, "usb: {}", err),
+            &Error::Io(ref err) => write!(f, "{}", err),
+            &Error::ConfigParse(ref file_path, ref err) => write!(f, "{}: {}", file_path, err),
+            &Error::ConfigValidation(ref msg) => write!(f, "{}", msg),
+            &Error::Output(ref err) => write!(f, "key output: {}", err),
+            &Error::Ipc(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            &Error::Usb(ref err) => Some(err),
+            &Error::Io(ref err) | &Error::Ipc(ref err) => Some(err),
+            &Error::ConfigParse(_, ref err) => Some(err.as_ref()),
+            &Error::ConfigValidation(_) => None,
+            &Error::Output(ref err) => Some(err.as_ref()),
+        }
+    }
+}
+
+impl From<libusb::Error> for Error {
+    fn from(err: libusb::Error) -> Error {
+        Error::Usb(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+#[test]
+fn test_recovery() {
+    assert_eq!(Error::from(libusb::Error::NoDevice).recovery(), Recovery::Retry);
+    assert_eq!(Error::from(libusb::Error::Busy).recovery(), Recovery::Retry);
+    assert_eq!(Error::from(libusb::Error::Access).recovery(), Recovery::Skip);
+    assert_eq!(Error::from(io::Error::new(io::ErrorKind::PermissionDenied, "denied")).recovery(), Recovery::Skip);
+    assert_eq!(Error::from(io::Error::new(io::ErrorKind::NotConnected, "unplugged")).recovery(), Recovery::Retry);
+    assert_eq!(Error::invalid("bad source").recovery(), Recovery::Skip);
+    assert_eq!(Error::disconnected("Mapping has stopped").recovery(), Recovery::Exit);
+}
+
+#[test]
+fn test_hint() {
+    assert_eq!(Error::from(libusb::Error::Access).hint(), Some(PERMISSION_HINT));
+    assert_eq!(Error::from(io::Error::new(io::ErrorKind::PermissionDenied, "denied")).hint(), Some(PERMISSION_HINT));
+    assert_eq!(Error::from(libusb::Error::NoDevice).hint(), None);
+}
@@ -0,0 +1,275 @@
+use std::io::{Result, Error, ErrorKind, Read};
+use std::fs::{self, File};
+use std::collections::HashMap;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use libc;
+
+use error;
+use device_mapping::{DeviceMap, device_key};
+use input::Input;
+use input_source::{self, InputSource};
+
+const EV_KEY: u16 = 0x01;
+const EV_ABS: u16 = 0x03;
+
+/// Event read from an input device node, `struct input_event` in linux/input.h.
+#[repr(C)]
+struct InputEvent {
+    time: libc::timeval,
+    event_type: u16,
+    code: u16,
+    value: i32,
+}
+
+/// Identity of an input device, `struct input_id` in linux/input.h.
+#[repr(C)]
+#[derive(Default)]
+struct InputId {
+    bustype: u16,
+    vendor: u16,
+    product: u16,
+    version: u16,
+}
+
+/// Range of an absolute axis, `struct input_absinfo` in linux/input.h.
+#[repr(C)]
+#[derive(Default)]
+struct AbsInfo {
+    value: i32,
+    minimum: i32,
+    maximum: i32,
+    fuzz: i32,
+    flat: i32,
+    resolution: i32,
+}
+
+// ioctl request numbers from linux/input.h
+const IOC_WRITE: u32 = 1;
+const IOC_READ: u32 = 2;
+
+fn ioc(dir: u32, nr: u32, size: usize) -> u32 {
+    (dir << 30) | ((size as u32) << 16) | ((b'E' as u32) << 8) | nr
+}
+
+fn eviocgid() -> u32 {
+    ioc(IOC_READ, 0x02, mem::size_of::<InputId>())
+}
+
+fn eviocgname(len: usize) -> u32 {
+    ioc(IOC_READ, 0x06, len)
+}
+
+fn eviocgabs(code: u16) -> u32 {
+    ioc(IOC_READ, 0x40 + code as u32, mem::size_of::<AbsInfo>())
+}
+
+fn eviocgrab() -> u32 {
+    ioc(IOC_WRITE, 0x90, mem::size_of::<libc::c_int>())
+}
+
+/// Input device node found in /dev/input.
+#[derive(Debug)]
+pub struct EvdevDevice {
+    /// Path of the device node, e.g. /dev/input/event5.
+    pub path: PathBuf,
+    /// Key of the device made from its vendor and product id, the same as usb devices use.
+    pub key: u32,
+    /// Name of the device reported by its driver.
+    pub name: String,
+}
+
+fn read_id(file: &File) -> Result<InputId> {
+    let mut id = InputId::default();
+    if unsafe { libc::ioctl(file.as_raw_fd(), eviocgid() as _, &mut id as *mut InputId) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(id)
+}
+
+fn read_name(file: &File) -> Result<String> {
+    let mut name = vec![0u8; 256];
+    let len = unsafe { libc::ioctl(file.as_raw_fd(), eviocgname(name.len()) as _, name.as_mut_ptr()) };
+    if len < 0 {
+        return Err(Error::last_os_error());
+    }
+    name.truncate(len as usize);
+    // the name is terminated by zero
+    while name.last() == Some(&0) {
+        name.pop();
+    }
+    Ok(String::from_utf8_lossy(&name).into_owned())
+}
+
+fn read_abs_info(file: &File, code: u16) -> Result<AbsInfo> {
+    let mut info = AbsInfo::default();
+    if unsafe { libc::ioctl(file.as_raw_fd(), eviocgabs(code) as _, &mut info as *mut AbsInfo) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(info)
+}
+
+/// Lists all input device nodes which can be opened.
+pub fn list() -> Result<Vec<EvdevDevice>> {
+    let mut res = Vec::new();
+    for entry in fs::read_dir("/dev/input")? {
+        let path = match entry {
+            Ok(value) => value.path(),
+            Err(err) => {
+                warn!("Failed to list input devices: {}", err);
+                continue;
+            }
+        };
+        let is_event = path.file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| name.starts_with("event"))
+            .unwrap_or(false);
+        if !is_event {
+            continue;
+        }
+        // nodes without permission are skipped, they can't be grabbed anyway
+        let file = match File::open(&path) {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        // a node which fails doesn't stop the listing of the others
+        let (id, name) = match read_id(&file).and_then(|id| read_name(&file).map(|name| (id, name))) {
+            Ok(value) => value,
+            Err(err) => {
+                warn!("Failed to read input device {}: {}", path.display(), err);
+                continue;
+            }
+        };
+        res.push(EvdevDevice {
+            path,
+            key: device_key(id.vendor, id.product),
+            name,
+        });
+    }
+    res.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(res)
+}
+
+/// Absolute axis mapped to an analog input.
+struct EvdevAxis {
+    uid: u16,
+    minimum: i32,
+    maximum: i32,
+    output: (f32, f32),
+    value: f32,
+}
+
+impl EvdevAxis {
+    /// Convert axis value to output interval.
+    fn convert(&self, value: i32) -> f32 {
+        let range = (self.maximum - self.minimum) as f32;
+        if range == 0.0 {
+            return self.output.0;
+        }
+        (value - self.minimum) as f32 * (self.output.1 - self.output.0) / range + self.output.0
+    }
+}
+
+/// Grabbed input device node, its key and axis events are mapped directly to inputs.
+pub struct EvdevSource {
+    file: File,
+    keys: HashMap<u16, u16>,
+    axes: HashMap<u16, EvdevAxis>,
+}
+
+impl EvdevSource {
+    /// Opens and grabs the device node, so its events are no longer seen by other programs.
+    pub fn open(path: &Path, mapping: &DeviceMap) -> Result<EvdevSource> {
+        let file = File::open(path)?;
+        let grab: libc::c_int = 1;
+        if unsafe { libc::ioctl(file.as_raw_fd(), eviocgrab() as _, grab) } < 0 {
+            let err = Error::last_os_error();
+            let msg = format!("Failed to grab {} at {}: {}", mapping.name, path.display(), err);
+            return Err(Error::new(err.kind(), msg));
+        }
+        let mut keys = HashMap::new();
+        for digital in &mapping.digitals {
+            if let Some(code) = digital.code {
+                keys.insert(code, digital.uid);
+            }
+        }
+        let mut axes = HashMap::new();
+        for analog in &mapping.analogs {
+            if let Some(code) = analog.code {
+                let info = read_abs_info(&file, code)?;
+                let mut axis = EvdevAxis {
+                    uid: analog.uid,
+                    minimum: info.minimum,
+                    maximum: info.maximum,
+                    output: analog.output,
+                    value: 0.0,
+                };
+                axis.value = axis.convert(info.value);
+                axes.insert(code, axis);
+            }
+        }
+        Ok(EvdevSource {
+            file,
+            keys,
+            axes,
+        })
+    }
+
+    fn read_event(&mut self) -> Result<InputEvent> {
+        let mut buffer = vec![0u8; mem::size_of::<InputEvent>()];
+        self.file.read_exact(&mut buffer).map_err(|err| {
+            match err.kind() {
+                ErrorKind::UnexpectedEof => Error::new(ErrorKind::NotConnected, "Input device disconnected"),
+                _ => err,
+            }
+        })?;
+        // the bytes of the buffer are not aligned as the event
+        Ok(unsafe { ptr::read_unaligned(buffer.as_ptr() as *const InputEvent) })
+    }
+}
+
+impl InputSource for EvdevSource {
+    fn read_inputs(&mut self) -> error::Result<Vec<Input>> {
+        if !input_source::wait_readable(&self.file, input_source::READ_TIMEOUT)? {
+            return Ok(Vec::new());
+        }
+        let event = self.read_event()?;
+        let mut res = Vec::new();
+        match event.event_type {
+            EV_KEY => {
+                if let Some(&uid) = self.keys.get(&event.code) {
+                    // value 2 is autorepeat, the key is already down
+                    match event.value {
+                        0 => res.push(Input::ButtonUp(uid)),
+                        1 => res.push(Input::ButtonDown(uid)),
+                        _ => (),
+                    }
+                }
+            }
+            EV_ABS => {
+                if let Some(axis) = self.axes.get_mut(&event.code) {
+                    let value = axis.convert(event.value);
+                    if value != axis.value {
+                        res.push(Input::Axis(axis.uid, value));
+                        axis.value = value;
+                    }
+                }
+            }
+            _ => (),
+        }
+        Ok(res)
+    }
+}
+
+#[test]
+fn test_axis_convert() {
+    let axis = EvdevAxis { uid: 0, minimum: -32768, maximum: 32767, output: (-1.0, 1.0), value: 0.0 };
+    assert_eq!(axis.convert(-32768), -1.0);
+    assert_eq!(axis.convert(32767), 1.0);
+    let axis = EvdevAxis { uid: 0, minimum: 0, maximum: 255, output: (0.0, 1.0), value: 0.0 };
+    assert_eq!(axis.convert(255), 1.0);
+    assert_eq!(axis.convert(0), 0.0);
+}
@@ -0,0 +1,505 @@
+use std::io::{Result, Error, ErrorKind, Read};
+use std::fs::File;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use libusb::{self, Context, DeviceHandle, Direction, RequestType, Recipient};
+
+use device_mapping::{DeviceMapDefinition, DeviceButtonDefinition, DeviceAnalogDefinition};
+use error;
+
+/// Usage page of generic desktop controls (axes, hat switches, ...).
+const PAGE_GENERIC_DESKTOP: u16 = 0x01;
+/// Usage page of keyboard keys.
+const PAGE_KEYBOARD: u16 = 0x07;
+/// Usage page of buttons, usage id is the button number.
+const PAGE_BUTTON: u16 = 0x09;
+
+/// One main item field of a report, as described by the HID report descriptor.
+#[derive(Debug, Clone)]
+pub struct ReportField {
+    /// Report id the field belongs to, 0 when the device does not use report ids.
+    pub report_id: u8,
+    /// Usages of the individual reports in the field, already combined with their usage page.
+    pub usages: Vec<u32>,
+    /// Offset of the field in bits from the start of the report (not counting the report id byte).
+    pub bit_offset: u32,
+    /// Size of one report in bits.
+    pub size: u32,
+    /// Number of reports in the field.
+    pub count: u32,
+    /// Smallest value the field reports.
+    pub logical_minimum: i32,
+    /// Largest value the field reports.
+    pub logical_maximum: i32,
+    /// Data bits of the main item (constant, variable, relative, ...).
+    pub flags: u32,
+}
+
+impl ReportField {
+    /// Constant fields are padding and carry no input.
+    pub fn is_constant(&self) -> bool {
+        self.flags & 0x01 != 0
+    }
+
+    /// Variable fields have one report per usage, otherwise the field is an array of usage indexes.
+    pub fn is_variable(&self) -> bool {
+        self.flags & 0x02 != 0
+    }
+
+    /// Usage of the i-th report in the field, the last usage repeats for the remaining reports.
+    pub fn usage(&self, i: u32) -> Option<u32> {
+        self.usages.get(i as usize).or(self.usages.last()).cloned()
+    }
+}
+
+/// Parsed HID report descriptor, only input reports are kept.
+#[derive(Debug)]
+pub struct ReportDescriptor {
+    /// All input fields in the order they appear in the descriptor.
+    pub inputs: Vec<ReportField>,
+    /// When set, every report is prefixed with one byte of its report id.
+    pub uses_report_ids: bool,
+}
+
+/// Global items state, this can be pushed and popped on the descriptor stack.
+#[derive(Debug, Clone, Default)]
+struct GlobalState {
+    usage_page: u16,
+    logical_minimum: i32,
+    logical_maximum: i32,
+    report_size: u32,
+    report_count: u32,
+    report_id: u8,
+}
+
+/// Local items state, this is reset after every main item.
+#[derive(Debug, Default)]
+struct LocalState {
+    usages: Vec<u32>,
+    usage_minimum: Option<u32>,
+    usage_maximum: Option<u32>,
+}
+
+/// Reads unsigned value of a short item.
+fn unsigned(data: &[u8]) -> u32 {
+    data.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u32)
+}
+
+/// Reads signed value of a short item, the sign is taken from the highest byte.
+fn signed(data: &[u8]) -> i32 {
+    match data.len() {
+        1 => data[0] as i8 as i32,
+        2 => unsigned(data) as u16 as i16 as i32,
+        4 => unsigned(data) as i32,
+        _ => 0,
+    }
+}
+
+/// Combines usage with the usage page, unless the usage is extended and carries its own page.
+fn full_usage(usage: u32, size: usize, usage_page: u16) -> u32 {
+    if size == 4 {
+        usage
+    } else {
+        ((usage_page as u32) << 16) | usage
+    }
+}
+
+/// Parses HID report descriptor.
+pub fn parse(data: &[u8]) -> Result<ReportDescriptor> {
+    let mut global = GlobalState::default();
+    let mut stack = Vec::new();
+    let mut local = LocalState::default();
+    let mut inputs = Vec::new();
+    let mut uses_report_ids = false;
+    // bit offsets of the next field per report type (input, output, feature) and report id
+    let mut offsets: HashMap<(u8, u8), u32> = HashMap::new();
+    let mut depth = 0;
+
+    let mut pos = 0;
+    while pos < data.len() {
+        let prefix = data[pos];
+        if prefix == 0xfe {
+            // long item, the data length is in the next byte, these are reserved and skipped
+            if pos + 1 >= data.len() {
+                let msg = format!("Truncated long item at byte {}", pos);
+                return Err(Error::new(ErrorKind::InvalidData, msg));
+            }
+            pos += 3 + data[pos + 1] as usize;
+            continue;
+        }
+        let size = match prefix & 0x03 {
+            3 => 4,
+            s => s as usize,
+        };
+        let item_type = (prefix >> 2) & 0x03;
+        let tag = prefix >> 4;
+        if pos + 1 + size > data.len() {
+            let msg = format!("Truncated item 0x{:02x} at byte {}", prefix, pos);
+            return Err(Error::new(ErrorKind::InvalidData, msg));
+        }
+        let item = &data[pos + 1..pos + 1 + size];
+        pos += 1 + size;
+
+        match item_type {
+            // main items
+            0 => {
+                match tag {
+                    // input, output, feature
+                    0x08 | 0x09 | 0x0b => {
+                        let kind = tag;
+                        let offset = offsets.entry((kind, global.report_id)).or_insert(0);
+                        let mut usages = local.usages.clone();
+                        if let (Some(min), Some(max)) = (local.usage_minimum, local.usage_maximum) {
+                            let page = min & 0xffff0000;
+                            for usage in (min & 0xffff)..((max & 0xffff) + 1) {
+                                usages.push(page | usage);
+                            }
+                        }
+                        let field = ReportField {
+                            report_id: global.report_id,
+                            usages,
+                            bit_offset: *offset,
+                            size: global.report_size,
+                            count: global.report_count,
+                            logical_minimum: global.logical_minimum,
+                            logical_maximum: global.logical_maximum,
+                            flags: unsigned(item),
+                        };
+                        *offset += global.report_size * global.report_count;
+                        if kind == 0x08 {
+                            inputs.push(field);
+                        }
+                    }
+                    // collection
+                    0x0a => depth += 1,
+                    // end collection
+                    0x0c => {
+                        if depth == 0 {
+                            let msg = format!("Unbalanced end collection at byte {}", pos - 1);
+                            return Err(Error::new(ErrorKind::InvalidData, msg));
+                        }
+                        depth -= 1;
+                    }
+                    _ => {
+                        let msg = format!("Unknown main item 0x{:02x} at byte {}", prefix, pos - 1 - size);
+                        return Err(Error::new(ErrorKind::InvalidData, msg));
+                    }
+                }
+                local = LocalState::default();
+            }
+            // global items
+            1 => {
+                match tag {
+                    0x00 => global.usage_page = unsigned(item) as u16,
+                    0x01 => global.logical_minimum = signed(item),
+                    0x02 => global.logical_maximum = signed(item),
+                    0x07 => global.report_size = unsigned(item),
+                    0x08 => {
+                        global.report_id = unsigned(item) as u8;
+                        uses_report_ids = true;
+                    }
+                    0x09 => global.report_count = unsigned(item),
+                    0x0a => stack.push(global.clone()),
+                    0x0b => {
+                        global = match stack.pop() {
+                            Some(value) => value,
+                            None => {
+                                let msg = format!("Pop without push at byte {}", pos - 1 - size);
+                                return Err(Error::new(ErrorKind::InvalidData, msg));
+                            }
+                        };
+                    }
+                    // physical range, units and exponents do not affect the layout
+                    _ => (),
+                }
+            }
+            // local items
+            2 => {
+                let usage = full_usage(unsigned(item), size, global.usage_page);
+                match tag {
+                    0x00 => local.usages.push(usage),
+                    0x01 => local.usage_minimum = Some(usage),
+                    0x02 => local.usage_maximum = Some(usage),
+                    // designators, strings and delimiters do not affect the layout
+                    _ => (),
+                }
+            }
+            _ => {
+                let msg = format!("Reserved item type 0x{:02x} at byte {}", prefix, pos - 1 - size);
+                return Err(Error::new(ErrorKind::InvalidData, msg));
+            }
+        }
+    }
+
+    if depth != 0 {
+        return Err(Error::new(ErrorKind::InvalidData, "Unterminated collection"));
+    }
+
+    Ok(ReportDescriptor {
+        inputs,
+        uses_report_ids,
+    })
+}
+
+/// Human readable name of a usage, used to name the mapped inputs.
+fn usage_name(usage: u32) -> Option<String> {
+    let page = (usage >> 16) as u16;
+    let id = usage & 0xffff;
+    match page {
+        PAGE_GENERIC_DESKTOP => {
+            let name = match id {
+                0x30 => "X",
+                0x31 => "Y",
+                0x32 => "Z",
+                0x33 => "Rx",
+                0x34 => "Ry",
+                0x35 => "Rz",
+                0x36 => "Slider",
+                0x37 => "Dial",
+                0x38 => "Wheel",
+                0x39 => "Hat",
+                _ => return None,
+            };
+            Some(name.to_owned())
+        }
+        PAGE_KEYBOARD => {
+            let name = match id {
+                0xe0 => "LeftControl",
+                0xe1 => "LeftShift",
+                0xe2 => "LeftAlt",
+                0xe3 => "LeftGui",
+                0xe4 => "RightControl",
+                0xe5 => "RightShift",
+                0xe6 => "RightAlt",
+                0xe7 => "RightGui",
+                _ => return Some(format!("Key{:02X}", id)),
+            };
+            Some(name.to_owned())
+        }
+        PAGE_BUTTON => Some(format!("B{}", id)),
+        _ => None,
+    }
+}
+
+/// Draft of a device mapping generated from a report descriptor.
+pub struct Draft {
+    /// The mapping, ready to be written to devices.yaml.
+    pub definition: DeviceMapDefinition,
+    /// Fields which could not be mapped, and why.
+    pub skipped: Vec<String>,
+}
+
+impl ReportDescriptor {
+    /// Size of the largest input report in bytes, including the report id.
+    pub fn packet_size(&self) -> u16 {
+        let mut sizes: HashMap<u8, u32> = HashMap::new();
+        for field in &self.inputs {
+            let end = field.bit_offset + field.size * field.count;
+            let size = sizes.entry(field.report_id).or_insert(0);
+            if end > *size {
+                *size = end;
+            }
+        }
+        let bits = sizes.values().cloned().max().unwrap_or(0);
+        let id_byte = if self.uses_report_ids { 1 } else { 0 };
+        ((bits + 7) / 8 + id_byte) as u16
+    }
+
+    /// Lays out single bit fields as digitals and byte fields as analogs.
+    pub fn draft(&self, name: &str) -> Draft {
+        let mut digitals = Vec::new();
+        let mut analogs = Vec::new();
+        let mut skipped = Vec::new();
+        let id_byte = if self.uses_report_ids { 1 } else { 0 };
+        for field in &self.inputs {
+            if field.is_constant() {
+                continue;
+            }
+            if self.uses_report_ids && field.report_id != self.inputs[0].report_id {
+                skipped.push(format!("report {}: only the first report id can be mapped", field.report_id));
+                continue;
+            }
+            if !field.is_variable() {
+                skipped.push(format!("array of {} x {} bits at bit {}: array fields have no fixed position",
+                                     field.count, field.size, field.bit_offset));
+                continue;
+            }
+            for i in 0..field.count {
+                let bit = field.bit_offset + i * field.size;
+                let index = bit / 8 + id_byte;
+                if index > u8::MAX as u32 {
+                    skipped.push(format!("{} bit value at byte {}: only the first 256 bytes can be mapped", field.size, index));
+                    continue;
+                }
+                let index = index as u8;
+                let usage = field.usage(i).unwrap_or(0);
+                if field.size == 1 {
+                    let name = usage_name(usage).unwrap_or_else(|| format!("D{}_{}", index, bit % 8));
+                    digitals.push(DeviceButtonDefinition {
+                        name,
+                        index,
+                        mask: 1 << (bit % 8),
+                    });
+                } else if field.size == 8 && bit % 8 == 0 {
+                    let name = usage_name(usage).unwrap_or_else(|| format!("A{}", index));
+                    if field.logical_minimum < 0 || field.logical_maximum > 255 {
+                        skipped.push(format!("axis {} reports values out of byte range, check its output interval", name));
+                    }
+                    analogs.push(DeviceAnalogDefinition {
+                        name,
+                        index,
+                        output: (-1.0, 1.0),
+                    });
+                } else {
+                    skipped.push(format!("{} bit value at bit {}: only bits and whole bytes can be mapped",
+                                         field.size, bit));
+                }
+            }
+        }
+        Draft {
+            definition: DeviceMapDefinition {
+                name: name.to_owned(),
+                packet_size: self.packet_size(),
+                source: None,
+                device_name: None,
+                digitals: if digitals.is_empty() { None } else { Some(digitals) },
+                bytes: None,
+                analogs: if analogs.is_empty() { None } else { Some(analogs) },
+                keys: None,
+                axes: None,
+                lcd: None,
+                backlight: None,
+                instances: None,
+            },
+            skipped,
+        }
+    }
+}
+
+/// Reads report descriptor saved in a binary file.
+pub fn read_from_file(file_path: &str) -> error::Result<Vec<u8>> {
+    let mut file = File::open(file_path)?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Reads report descriptor of an interface directly from the device. The hid driver of the kernel holds the
+/// interface, so it's detached while the interface is claimed, and attached again also when the reading fails.
+pub fn read_from_device(vendor_id: u16, product_id: u16, interface: u8) -> error::Result<Vec<u8>> {
+    let context = Context::new()?;
+    let mut handle = match context.open_device_with_vid_pid(vendor_id, product_id) {
+        Some(handle) => handle,
+        None => {
+            let msg = format!("Device {:04x}:{:04x} not found or cannot be opened", vendor_id, product_id);
+            return Err(Error::new(ErrorKind::NotFound, msg).into());
+        }
+    };
+    // not supported on some platforms, there is no driver to detach then
+    let kernel_driver = handle.kernel_driver_active(interface).unwrap_or(false);
+    if kernel_driver {
+        handle.detach_kernel_driver(interface)?;
+    }
+    let result = match handle.claim_interface(interface) {
+        Ok(()) => {
+            let result = read_descriptor(&handle, interface);
+            if let Err(err) = handle.release_interface(interface) {
+                warn!("Failed to release interface {}: {}", interface, err);
+            }
+            result
+        }
+        Err(err) => Err(err.into()),
+    };
+    if kernel_driver {
+        if let Err(err) = handle.attach_kernel_driver(interface) {
+            warn!("Failed to attach the kernel driver to interface {} again, reconnect the device: {}", interface, err);
+        }
+    }
+    result
+}
+
+/// Reads report descriptor of the claimed interface.
+fn read_descriptor(handle: &DeviceHandle, interface: u8) -> error::Result<Vec<u8>> {
+    let mut data = vec![0u8; 4096];
+    // GET_DESCRIPTOR request for the report descriptor (type 0x22) of the interface
+    let request_type = libusb::request_type(Direction::In, RequestType::Standard, Recipient::Interface);
+    let len = handle.read_control(request_type, 0x06, 0x2200, interface as u16, &mut data, Duration::from_secs(1))?;
+    data.truncate(len);
+    Ok(data)
+}
+
+#[test]
+fn test_parse_keyboard() {
+    let descriptor = parse(include_bytes!("../testdata/hid/keyboard.bin")).unwrap();
+    assert!(!descriptor.uses_report_ids);
+    assert_eq!(descriptor.packet_size(), 8);
+    // modifiers, reserved byte and the key array
+    assert_eq!(descriptor.inputs.len(), 3);
+    let keys = &descriptor.inputs[2];
+    assert_eq!((keys.bit_offset, keys.size, keys.count), (16, 8, 6));
+    assert_eq!((keys.logical_minimum, keys.logical_maximum), (0, 101));
+    assert!(!keys.is_variable());
+
+    let draft = descriptor.draft("Keyboard");
+    let digitals = draft.definition.digitals.unwrap();
+    assert_eq!(digitals.len(), 8);
+    assert_eq!((digitals[0].name.as_str(), digitals[0].index, digitals[0].mask), ("LeftControl", 0, 1));
+    assert_eq!((digitals[7].name.as_str(), digitals[7].index, digitals[7].mask), ("RightGui", 0, 128));
+    assert!(draft.definition.analogs.is_none());
+    assert_eq!(draft.skipped.len(), 1);
+}
+
+#[test]
+fn test_parse_gamepad() {
+    let descriptor = parse(include_bytes!("../testdata/hid/gamepad.bin")).unwrap();
+    assert!(descriptor.uses_report_ids);
+    assert_eq!(descriptor.packet_size(), 5);
+    let axes = &descriptor.inputs[1];
+    assert_eq!(axes.report_id, 1);
+    assert_eq!((axes.logical_minimum, axes.logical_maximum), (0, 255));
+
+    let draft = descriptor.draft("Gamepad");
+    let digitals = draft.definition.digitals.unwrap();
+    assert_eq!(digitals.len(), 16);
+    assert_eq!((digitals[0].name.as_str(), digitals[0].index, digitals[0].mask), ("B1", 1, 1));
+    assert_eq!((digitals[8].name.as_str(), digitals[8].index, digitals[8].mask), ("B9", 2, 1));
+    let analogs = draft.definition.analogs.unwrap();
+    assert_eq!((analogs[0].name.as_str(), analogs[0].index), ("X", 3));
+    assert_eq!((analogs[1].name.as_str(), analogs[1].index), ("Y", 4));
+    assert!(draft.skipped.is_empty());
+}
+
+#[test]
+fn test_parse_truncated() {
+    let data = include_bytes!("../testdata/hid/gamepad.bin");
+    // cut in the middle of the two byte logical maximum
+    assert!(parse(&data[..33]).is_err());
+    // missing end collection
+    assert!(parse(&data[..data.len() - 1]).is_err());
+}
+
+#[test]
+fn test_draft_far_fields() {
+    let field = |bit_offset: u32, size: u32| ReportField {
+        report_id: 0,
+        usages: Vec::new(),
+        bit_offset,
+        size,
+        count: 2,
+        logical_minimum: 0,
+        logical_maximum: 255,
+        flags: 0x02,
+    };
+    let descriptor = ReportDescriptor {
+        inputs: vec![field(254 * 8, 8), field(255 * 8 + 7, 1)],
+        uses_report_ids: false,
+    };
+    let draft = descriptor.draft("Far");
+    // the fields past the last byte index are reported, not wrapped around to the start of the packet
+    let analogs = draft.definition.analogs.unwrap();
+    assert_eq!(analogs.iter().map(|analog| analog.index).collect::<Vec<_>>(), vec![254, 255]);
+    let digitals = draft.definition.digitals.unwrap();
+    assert_eq!(digitals.iter().map(|digital| (digital.index, digital.mask)).collect::<Vec<_>>(), vec![(255, 0x80)]);
+    assert_eq!(draft.skipped, vec!["1 bit value at byte 256: only the first 256 bytes can be mapped"]);
+}
@@ -0,0 +1,125 @@
+use std::io::{Result, Error, ErrorKind, Read};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use device_mapping::DeviceMap;
+use error;
+use hid_descriptor::{self, ReportDescriptor};
+use input_source::{self, PacketSource};
+use sysfs;
+
+/// Hidraw device node of one hid interface, with its report descriptor when it could be read and parsed.
+struct HidrawNode {
+    path: PathBuf,
+    descriptor: Option<ReportDescriptor>,
+}
+
+/// Finds hidraw device nodes of the usb device at the bus and address, one for each of its hid interfaces.
+fn nodes(bus_number: u8, address: u8) -> Result<Vec<HidrawNode>> {
+    let device = match sysfs::usb_device(bus_number, address) {
+        Some(value) => value,
+        None => return Ok(Vec::new()),
+    };
+    let mut entries = fs::read_dir("/sys/class/hidraw")?.collect::<Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    let mut res = Vec::new();
+    for entry in entries {
+        let hid_device = entry.path().join("device");
+        if sysfs::usb_parent(&hid_device) == fs::canonicalize(&device).ok() {
+            let descriptor = fs::read(hid_device.join("report_descriptor")).ok()
+                .and_then(|data| hid_descriptor::parse(&data).ok());
+            res.push(HidrawNode {
+                path: Path::new("/dev").join(entry.file_name()),
+                descriptor,
+            });
+        }
+    }
+    Ok(res)
+}
+
+/// Selects the node of the interface whose input reports have the packet size of the mapping, like the endpoint
+/// read through libusb. Without such node, the first one with input reports is used.
+fn select(nodes: Vec<HidrawNode>, packet_size: u16) -> Option<HidrawNode> {
+    let matching = nodes.iter().position(|node| {
+        node.descriptor.as_ref().map(|descriptor| descriptor.packet_size() == packet_size).unwrap_or(false)
+    });
+    let with_inputs = nodes.iter().position(|node| {
+        node.descriptor.as_ref().map(|descriptor| !descriptor.inputs.is_empty()).unwrap_or(true)
+    });
+    let index = matching.or(with_inputs)?;
+    nodes.into_iter().nth(index)
+}
+
+/// Keeps the report read into the buffer, when it has the report id read by the mapping.
+fn accept_report(buffer: &[u8], report_id: Option<u8>) -> bool {
+    report_id.is_none() || buffer.first().cloned() == report_id
+}
+
+/// Reads reports from a hidraw device node, the kernel driver stays attached.
+pub struct HidrawSource {
+    file: File,
+    /// Report id of the input reports read by the mapping, when the device numbers its reports. The reports
+    /// start with the id byte, as the packets read through libusb, so the indexes of the mapping are the same.
+    report_id: Option<u8>,
+}
+
+impl HidrawSource {
+    /// Opens the hidraw node of the interface of the usb device at the bus and address, which sends the input
+    /// reports of the mapping.
+    pub fn open(bus_number: u8, address: u8, mapping: &DeviceMap) -> Result<HidrawSource> {
+        let node = match select(nodes(bus_number, address)?, mapping.packet_size) {
+            Some(value) => value,
+            None => {
+                let msg = format!("Device {} at {}:{} has no hidraw node", mapping.name, bus_number, address);
+                return Err(Error::new(ErrorKind::NotFound, msg));
+            }
+        };
+        info!("Reading {} from {}", mapping.name, node.path.display());
+        // only the first numbered report can be mapped, as in the drafts of describe
+        let report_id = node.descriptor
+            .filter(|descriptor| descriptor.uses_report_ids)
+            .and_then(|descriptor| descriptor.inputs.first().map(|field| field.report_id));
+        Ok(HidrawSource {
+            file: File::open(node.path)?,
+            report_id,
+        })
+    }
+}
+
+impl PacketSource for HidrawSource {
+    fn read_packet(&mut self, buffer: &mut [u8]) -> error::Result<Option<usize>> {
+        if !input_source::wait_readable(&self.file, input_source::READ_TIMEOUT)? {
+            return Ok(None);
+        }
+        // every read returns exactly one report
+        let len = self.file.read(buffer)?;
+        if len == 0 {
+            return Err(Error::new(ErrorKind::NotConnected, "Hidraw device disconnected").into());
+        }
+        Ok(Some(len).filter(|_| accept_report(buffer, self.report_id)))
+    }
+}
+
+#[test]
+fn test_accept_report() {
+    assert!(accept_report(&[1, 4], Some(1)));
+    // reports with other ids are not decoded
+    assert!(!accept_report(&[2, 8], Some(1)));
+    assert!(accept_report(&[2, 8], None));
+}
+
+#[test]
+fn test_select_node() {
+    let node = |name: &str, data: Option<&[u8]>| HidrawNode {
+        path: PathBuf::from(name),
+        descriptor: data.map(|data| hid_descriptor::parse(data).unwrap()),
+    };
+    let keyboard = include_bytes!("../testdata/hid/keyboard.bin");
+    let gamepad = include_bytes!("../testdata/hid/gamepad.bin");
+    let nodes = vec![node("hidraw0", Some(keyboard)), node("hidraw1", Some(gamepad))];
+    // the interface with the reports of the mapping, not the first interface of the device
+    assert_eq!(select(nodes, 5).unwrap().path, PathBuf::from("hidraw1"));
+    let nodes = vec![node("hidraw0", None), node("hidraw1", Some(gamepad))];
+    assert_eq!(select(nodes, 64).unwrap().path, PathBuf::from("hidraw0"));
+    assert!(select(Vec::new(), 8).is_none());
+}
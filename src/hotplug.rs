@@ -0,0 +1,181 @@
+use std::io::{Result, Error};
+use std::collections::HashMap;
+use std::mem;
+use std::path::PathBuf;
+
+use libc;
+
+use device_manager::DeviceAddress;
+
+/// Netlink multicast group of the events sent by udev, after its rules set the device permissions.
+const UDEV_GROUP: u32 = 2;
+/// Udev messages start with this prefix, followed by a binary header.
+const UDEV_PREFIX: &[u8] = b"libudev\0";
+/// Magic number in the udev message header, in network byte order.
+const UDEV_MAGIC: u32 = 0xfeedcafe;
+
+/// Connection or disconnection of a device.
+#[derive(Debug, PartialEq)]
+pub enum DeviceEvent {
+    /// Device was connected.
+    Arrived(DeviceAddress),
+    /// Device was disconnected.
+    Removed(DeviceAddress),
+    /// Hidraw node was created, often after its usb device has arrived, when the hid driver is bound.
+    HidrawArrived(PathBuf),
+    /// Hidraw node was removed, e.g. when the hid driver was unbound.
+    HidrawRemoved(PathBuf),
+}
+
+/// Listens to udev events of usb, hidraw and input devices.
+pub struct Monitor {
+    fd: libc::c_int,
+}
+
+impl Monitor {
+    /// Opens netlink socket receiving udev events.
+    pub fn new() -> Result<Monitor> {
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::NETLINK_KOBJECT_UEVENT) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = UDEV_GROUP;
+        let res = unsafe {
+            libc::bind(fd, &addr as *const libc::sockaddr_nl as *const libc::sockaddr, mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t)
+        };
+        if res < 0 {
+            let err = Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(err);
+        }
+        Ok(Monitor {
+            fd,
+        })
+    }
+
+    /// Waits for the next event of a usb, hidraw or input device.
+    pub fn next_event(&mut self) -> Result<DeviceEvent> {
+        let mut buffer = vec![0u8; 8192];
+        loop {
+            let len = unsafe { libc::recv(self.fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len(), 0) };
+            if len < 0 {
+                return Err(Error::last_os_error());
+            }
+            if let Some(event) = parse_message(&buffer[..len as usize]) {
+                return Ok(event);
+            }
+        }
+    }
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    if offset + 4 > data.len() {
+        return None;
+    }
+    Some(data[offset..offset + 4].iter().fold(0, |acc, &b| (acc << 8) | b as u32))
+}
+
+/// Reads the properties of an udev message, or of a kernel uevent.
+fn properties(data: &[u8]) -> Option<HashMap<String, String>> {
+    let body = if data.starts_with(UDEV_PREFIX) {
+        // header: prefix, magic, header size, properties offset, properties length (native byte order)
+        if read_u32(data, 8)? != UDEV_MAGIC {
+            return None;
+        }
+        let native = |offset: usize| -> Option<usize> {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(data.get(offset..offset + 4)?);
+            Some(u32::from_ne_bytes(bytes) as usize)
+        };
+        let offset = native(16)?;
+        let len = native(20)?;
+        data.get(offset..offset + len)?
+    } else {
+        // kernel uevent starts with action@devpath
+        let start = data.iter().position(|&b| b == 0)? + 1;
+        &data[start..]
+    };
+    let mut res = HashMap::new();
+    for property in body.split(|&b| b == 0) {
+        let property = String::from_utf8_lossy(property);
+        let mut parts = property.splitn(2, '=');
+        if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
+            res.insert(key.to_owned(), value.to_owned());
+        }
+    }
+    Some(res)
+}
+
+/// Path of the device node, the kernel sends its name relative to /dev.
+fn node_path(name: &str) -> PathBuf {
+    if name.starts_with('/') { PathBuf::from(name) } else { PathBuf::from("/dev").join(name) }
+}
+
+/// Parses netlink message into an event, when it's about a usb device, a hidraw node or an input device node.
+pub fn parse_message(data: &[u8]) -> Option<DeviceEvent> {
+    let properties = properties(data)?;
+    let get = |key: &str| properties.get(key).map(|value| value.as_str());
+    if get("SUBSYSTEM") == Some("hidraw") {
+        let path = node_path(get("DEVNAME")?);
+        return match get("ACTION")? {
+            "add" => Some(DeviceEvent::HidrawArrived(path)),
+            "remove" => Some(DeviceEvent::HidrawRemoved(path)),
+            _ => None,
+        };
+    }
+    let address = match (get("SUBSYSTEM"), get("DEVTYPE")) {
+        (Some("usb"), Some("usb_device")) => {
+            let bus_number = get("BUSNUM")?.parse().ok()?;
+            let address = get("DEVNUM")?.parse().ok()?;
+            DeviceAddress::Usb(bus_number, address)
+        }
+        (Some("input"), _) => {
+            let name = get("DEVNAME")?;
+            if !name.contains("input/event") {
+                return None;
+            }
+            DeviceAddress::Evdev(node_path(name))
+        }
+        _ => return None,
+    };
+    match get("ACTION")? {
+        "add" => Some(DeviceEvent::Arrived(address)),
+        "remove" => Some(DeviceEvent::Removed(address)),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_parse_message() {
+    let kernel = b"add@/devices/pci0000:00/0000:00:14.0/usb3/3-1\0ACTION=add\0DEVPATH=/devices/pci0000:00/0000:00:14.0/usb3/3-1\0\
+                   SUBSYSTEM=usb\0DEVNAME=bus/usb/003/005\0DEVTYPE=usb_device\0PRODUCT=46d/c21c/203\0BUSNUM=003\0DEVNUM=005\0";
+    assert_eq!(parse_message(kernel), Some(DeviceEvent::Arrived(DeviceAddress::Usb(3, 5))));
+
+    let properties = b"ACTION=remove\0SUBSYSTEM=input\0DEVNAME=/dev/input/event7\0";
+    let mut udev = Vec::new();
+    udev.extend_from_slice(UDEV_PREFIX);
+    udev.extend_from_slice(&[0xfe, 0xed, 0xca, 0xfe]);
+    udev.extend_from_slice(&40u32.to_ne_bytes());
+    udev.extend_from_slice(&40u32.to_ne_bytes());
+    udev.extend_from_slice(&(properties.len() as u32).to_ne_bytes());
+    udev.extend_from_slice(&[0u8; 16]);
+    udev.extend_from_slice(properties);
+    assert_eq!(parse_message(&udev), Some(DeviceEvent::Removed(DeviceAddress::Evdev(PathBuf::from("/dev/input/event7")))));
+
+    let hidraw = b"add@/x/hidraw/hidraw2\0ACTION=add\0SUBSYSTEM=hidraw\0DEVNAME=hidraw2\0";
+    assert_eq!(parse_message(hidraw), Some(DeviceEvent::HidrawArrived(PathBuf::from("/dev/hidraw2"))));
+    let hidraw = b"remove@/x/hidraw/hidraw2\0ACTION=remove\0SUBSYSTEM=hidraw\0DEVNAME=hidraw2\0";
+    assert_eq!(parse_message(hidraw), Some(DeviceEvent::HidrawRemoved(PathBuf::from("/dev/hidraw2"))));
+
+    // interfaces of usb devices and other subsystems are ignored
+    assert_eq!(parse_message(b"add@/x\0ACTION=add\0SUBSYSTEM=usb\0DEVTYPE=usb_interface\0"), None);
+    assert_eq!(parse_message(b"add@/x\0ACTION=add\0SUBSYSTEM=block\0DEVNAME=sda\0"), None);
+}
@@ -0,0 +1,428 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::result;
+
+use xml::common::Position;
+use xml::reader::{EventReader, XmlEvent};
+use xml::attribute::OwnedAttribute;
+
+use config::FormatError;
+use device_output::LED_NAMES;
+use keysyms;
+use profile_definition::{AxisDef, DeviceDef, Issue, ModeDef, ProfileDef, SingleDef};
+
+/// Device the imported bindings are for, both formats are for the G13.
+const DEVICE: &str = "G13";
+
+/// Keys of the G13 named differently in the other drivers than in devices.yaml, the G keys are named the same.
+const KEY_NAMES: &[(&str, &str)] = &[
+    ("BD", "Menu"),
+    ("L1", "Menu1"),
+    ("L2", "Menu2"),
+    ("L3", "Menu3"),
+    ("L4", "Menu4"),
+    ("M1", "Mode1"),
+    ("M2", "Mode2"),
+    ("M3", "Mode3"),
+    ("MR", "Mode4"),
+    ("LEFT", "G23"),
+    ("DOWN", "G24"),
+    ("TOP", "G25"),
+    ("LIGHT", "MenuLight"),
+];
+
+/// Stick zones of the g13 driver, with the axis and its side they are mapped to, and the key they press by default.
+const STICK_ZONES: &[(&str, &str, usize, &str)] = &[
+    ("STICK_LEFT", "X", 0, "Left"),
+    ("STICK_RIGHT", "X", 1, "Right"),
+    ("STICK_UP", "Y", 0, "Up"),
+    ("STICK_DOWN", "Y", 1, "Down"),
+];
+
+/// Keys of the Logitech profiles named differently than the Linux keys.
+const LOGITECH_KEYS: &[(&str, &str)] = &[
+    ("ESCAPE", "ESC"),
+    ("RETURN", "ENTER"),
+    ("LSHIFT", "LEFTSHIFT"),
+    ("RSHIFT", "RIGHTSHIFT"),
+    ("LCTRL", "LEFTCTRL"),
+    ("RCTRL", "RIGHTCTRL"),
+    ("LALT", "LEFTALT"),
+    ("RALT", "RIGHTALT"),
+    ("LWIN", "LEFTMETA"),
+    ("RWIN", "RIGHTMETA"),
+    ("APPS", "COMPOSE"),
+    ("CAPS_LOCK", "CAPSLOCK"),
+    ("NUM_LOCK", "NUMLOCK"),
+    ("SCROLL_LOCK", "SCROLLLOCK"),
+    ("PRINTSCREEN", "SYSRQ"),
+    ("PAGE_UP", "PAGEUP"),
+    ("PRIOR", "PAGEUP"),
+    ("PAGE_DOWN", "PAGEDOWN"),
+    ("NEXT", "PAGEDOWN"),
+    ("EQUALS", "EQUAL"),
+    ("LBRACKET", "LEFTBRACE"),
+    ("RBRACKET", "RIGHTBRACE"),
+    ("TILDE", "GRAVE"),
+    ("PERIOD", "DOT"),
+    ("NUM_PLUS", "KPPLUS"),
+    ("NUM_MINUS", "KPMINUS"),
+    ("NUM_ASTERISK", "KPASTERISK"),
+    ("NUM_SLASH", "KPSLASH"),
+    ("NUM_PERIOD", "KPDOT"),
+    ("NUM_ENTER", "KPENTER"),
+];
+
+/// Bindings of a mode being imported, a key bound again replaces the earlier binding.
+#[derive(Default)]
+struct Bindings {
+    single: Vec<SingleDef>,
+    axes: Vec<AxisDef>,
+}
+
+impl Bindings {
+    fn bind(&mut self, button: String, cmd: String) {
+        match self.single.iter_mut().find(|single| single.button == button) {
+            Some(single) => single.cmd = cmd,
+            None => self.single.push(SingleDef { button, cmd }),
+        }
+    }
+
+    fn bind_axis(&mut self, axis: &str, side: usize, cmd: String) {
+        if let Some(axis_def) = self.axes.iter_mut().find(|axis_def| axis_def.axis == axis) {
+            axis_def.simple[side] = cmd;
+        }
+    }
+
+    fn into_mode(self, name: String) -> ModeDef {
+        ModeDef {
+            name,
+            extends: None,
+            backlight: None,
+            leds: None,
+            devices: vec![DeviceDef {
+                device: DEVICE.to_owned(),
+                single: self.single,
+                singles: Vec::new(),
+                axes: self.axes,
+            }],
+        }
+    }
+}
+
+/// Name of the mode imported from the mode or shift state of the other driver, counted from 1.
+fn mode_name(number: u8) -> String {
+    format!("Mode-{}", number)
+}
+
+fn new_profile(name: String) -> ProfileDef {
+    ProfileDef {
+        name,
+        extends: None,
+        pattern: Some("*".to_owned()),
+        backlight: None,
+        leds: None,
+        modes: Vec::new(),
+    }
+}
+
+/// Input of the G13 in devices.yaml of the key named by the other driver.
+fn input_name(key: &str) -> Option<String> {
+    if let Some(&(_, input)) = KEY_NAMES.iter().find(|entry| entry.0 == key) {
+        return Some(input.to_owned());
+    }
+    match key.trim_start_matches('G').parse::<u8>() {
+        Ok(number) if key.starts_with('G') && (1..=22).contains(&number) => Some(key.to_owned()),
+        _ => None,
+    }
+}
+
+fn rgb(red: &str, green: &str, blue: &str) -> Option<String> {
+    match (red.parse::<u8>(), green.parse::<u8>(), blue.parse::<u8>()) {
+        (Ok(red), Ok(green), Ok(blue)) => Some(format!("#{:02x}{:02x}{:02x}", red, green, blue)),
+        _ => None,
+    }
+}
+
+/// Keysyms pressed together by the keys of the action joined by `+`, e.g. KEY_LEFTSHIFT+KEY_A.
+fn bind_action(action: &str) -> result::Result<String, String> {
+    if action.starts_with('!') || action.starts_with('>') {
+        return Err(format!("pipe output {} is not supported", action));
+    }
+    let keysyms = action.split('+').map(|key| keysyms::from_linux_name(key.trim()).ok_or_else(|| format!("unknown key {}", key)));
+    Ok(keysyms.collect::<result::Result<Vec<_>, String>>()?.join("+"))
+}
+
+/// Imports the bindings of the ecraven/g13 driver, binds of the keys, the backlight, the mode LEDs and the actions
+/// of the stick zones. The stick presses the keys past half of the axis, the bounds of the zones are not imported.
+pub fn import_bind(name: &str, text: &str) -> (ProfileDef, Vec<Issue>) {
+    let mut issues = Vec::new();
+    let mut profile = new_profile(name.to_owned());
+    let mut bindings = Bindings {
+        single: Vec::new(),
+        axes: ["X", "Y"].iter().map(|axis| AxisDef {
+            axis: (*axis).to_owned(),
+            simple: STICK_ZONES.iter().filter(|zone| zone.1 == *axis).map(|zone| zone.3.to_owned()).collect(),
+        }).collect(),
+    };
+    let mut stick_keys = true;
+    for (index, line) in text.lines().enumerate() {
+        let line_number = Some(index + 1);
+        let mut issue = |message: String| issues.push(Issue { line: line_number, message });
+        let line = line.split('#').next().unwrap_or_default().trim();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => (),
+            ["bind", key, action @ ..] => match (input_name(key), bind_action(&action.join(" "))) {
+                (Some(input), Ok(cmd)) => bindings.bind(input, cmd),
+                (None, _) => issue(format!("unknown key {}", key)),
+                (_, Err(err)) => issue(format!("{}: {}", key, err)),
+            },
+            ["rgb", red, green, blue] => match rgb(red, green, blue) {
+                Some(color) => profile.backlight = Some(color),
+                None => issue(format!("invalid colour {} {} {}", red, green, blue)),
+            },
+            ["mod", mask] => match mask.parse::<u8>() {
+                Ok(mask) if mask < 1 << LED_NAMES.len() => {
+                    profile.leds = Some(LED_NAMES.iter().enumerate().filter(|led| mask & 1 << led.0 != 0).map(|led| (*led.1).to_owned()).collect());
+                }
+                _ => issue(format!("invalid mode LEDs {}", mask)),
+            },
+            ["stickmode", "KEYS"] => stick_keys = true,
+            ["stickmode", mode] => {
+                issue(format!("stick mode {} is not supported, the stick is not imported", mode));
+                stick_keys = false;
+            }
+            ["stickzone", "action", zone, action @ ..] => match (STICK_ZONES.iter().find(|entry| entry.0 == *zone), bind_action(&action.join(" "))) {
+                (Some(&(_, axis, side, _)), Ok(cmd)) => bindings.bind_axis(axis, side, cmd),
+                (None, _) => issue(format!("stick zone {} is not supported, only {}", zone, STICK_ZONES.iter().map(|zone| zone.0).collect::<Vec<_>>().join(", "))),
+                (_, Err(err)) => issue(format!("{}: {}", zone, err)),
+            },
+            ["stickzone", ..] => issue(format!("{} is not supported, the stick presses the keys past half of the axis", line)),
+            [command @ "font", ..] | [command @ "textmode", ..] | [command @ "pos", ..] | [command @ "out", ..] | [command @ "clear", ..] => {
+                issue(format!("display command {} is not imported", command))
+            }
+            [command, ..] => issue(format!("{} is not supported", command)),
+        }
+    }
+    if !stick_keys {
+        bindings.axes.clear();
+    }
+    profile.modes.push(bindings.into_mode(mode_name(1)));
+    (profile, issues)
+}
+
+/// Keysym of the key of the Logitech profiles.
+fn logitech_keysym(key: &str) -> Option<String> {
+    let key = key.to_uppercase();
+    let key = match LOGITECH_KEYS.iter().find(|entry| entry.0 == key) {
+        Some(&(_, name)) => name.to_owned(),
+        None if key.starts_with("NUM_") => format!("KP{}", &key[4..]),
+        None => key,
+    };
+    keysyms::from_linux_name(&key)
+}
+
+fn attribute<'a>(attributes: &'a [OwnedAttribute], name: &str) -> Option<&'a str> {
+    attributes.iter().find(|attr| attr.name.local_name == name).map(|attr| attr.value.as_str())
+}
+
+/// Macro of a Logitech profile, the keys pressed together or why it can't be imported.
+struct Macro {
+    name: String,
+    keys: Vec<String>,
+    unsupported: Option<String>,
+}
+
+/// Profile of the Logitech profiles being read, the assignments refer to the macros by their guid.
+struct LogitechProfile {
+    profile: ProfileDef,
+    macros: HashMap<String, Macro>,
+    /// Key, shift state, guid of the macro and the line of the assignment.
+    assignments: Vec<(String, u8, String, usize)>,
+}
+
+impl LogitechProfile {
+    fn finish(self, issues: &mut Vec<Issue>) -> ProfileDef {
+        let mut profile = self.profile;
+        let mut modes: BTreeMap<u8, Bindings> = BTreeMap::new();
+        for (key, shift_state, guid, line) in self.assignments {
+            let mut issue = |message: String| issues.push(Issue { line: Some(line), message: format!("{}: {}", profile.name, message) });
+            let input = match input_name(&key) {
+                Some(value) => value,
+                None => {
+                    issue(format!("unknown key {}", key));
+                    continue;
+                }
+            };
+            match self.macros.get(&guid) {
+                Some(&Macro { ref name, unsupported: Some(ref reason), .. }) => issue(format!("{}: macro {} {}", key, name, reason)),
+                Some(&Macro { ref name, ref keys, .. }) if keys.is_empty() => issue(format!("{}: macro {} presses no keys", key, name)),
+                Some(&Macro { ref keys, .. }) => modes.entry(shift_state).or_default().bind(input, keys.join("+")),
+                None => issue(format!("{}: there is no macro {}", key, guid)),
+            }
+        }
+        profile.modes = modes.into_iter().map(|(number, bindings)| bindings.into_mode(mode_name(number))).collect();
+        profile
+    }
+}
+
+/// Imports the profiles exported by the Logitech Gaming Software, the keystroke macros assigned to the keys of
+/// the G13 in each shift state. Other kinds of macros are reported.
+pub fn import_logitech(text: &str) -> result::Result<(Vec<ProfileDef>, Vec<Issue>), FormatError> {
+    let mut reader = EventReader::from_str(text);
+    let mut issues = Vec::new();
+    let mut profiles = Vec::new();
+    let mut current: Option<LogitechProfile> = None;
+    let mut current_macro: Option<(String, Macro)> = None;
+    let mut g13_assignments = false;
+    loop {
+        let event = reader.next()?;
+        let line = reader.position().row as usize + 1;
+        match event {
+            XmlEvent::StartElement { name, attributes, .. } => {
+                let element = name.local_name.as_str();
+                if element == "profile" {
+                    let name = attribute(&attributes, "name").unwrap_or("Imported").to_owned();
+                    current = Some(LogitechProfile { profile: new_profile(name), macros: HashMap::new(), assignments: Vec::new() });
+                }
+                let profile = match current.as_mut() {
+                    Some(value) => value,
+                    None => continue,
+                };
+                if let Some((_, ref mut macro_def)) = current_macro {
+                    match element {
+                        "keystroke" | "down" | "up" => (),
+                        "key" => match attribute(&attributes, "value").map(|key| (key, logitech_keysym(key))) {
+                            Some((_, Some(keysym))) if !macro_def.keys.contains(&keysym) => macro_def.keys.push(keysym),
+                            Some((_, Some(_))) => (),
+                            Some((key, None)) => macro_def.unsupported = Some(format!("presses unknown key {}", key)),
+                            None => (),
+                        },
+                        other => if macro_def.unsupported.is_none() {
+                            macro_def.unsupported = Some(format!("is a {} macro, only keystrokes are supported", other));
+                        },
+                    }
+                    continue;
+                }
+                match element {
+                    "color" if profile.profile.backlight.is_none() => {
+                        let model = attribute(&attributes, "devicemodel").unwrap_or(DEVICE);
+                        let color = (attribute(&attributes, "red"), attribute(&attributes, "green"), attribute(&attributes, "blue"));
+                        if let (true, (Some(red), Some(green), Some(blue))) = (model.contains(DEVICE), color) {
+                            profile.profile.backlight = rgb(red, green, blue);
+                        }
+                    }
+                    "macro" => {
+                        let guid = attribute(&attributes, "guid").unwrap_or_default().to_owned();
+                        let name = attribute(&attributes, "name").unwrap_or(&guid).to_owned();
+                        current_macro = Some((guid, Macro { name, keys: Vec::new(), unsupported: None }));
+                    }
+                    "assignments" => {
+                        g13_assignments = attribute(&attributes, "devicecategory").map(|category| category.contains(DEVICE)).unwrap_or(true);
+                    }
+                    "assignment" if g13_assignments => {
+                        let key = attribute(&attributes, "contextid").unwrap_or_default().to_owned();
+                        let shift_state = attribute(&attributes, "shiftstate").unwrap_or("1");
+                        match (attribute(&attributes, "macroguid"), shift_state.parse::<u8>()) {
+                            (Some(guid), Ok(number)) if (1..=3).contains(&number) => profile.assignments.push((key, number, guid.to_owned(), line)),
+                            (Some(_), _) => issues.push(Issue { line: Some(line), message: format!("{}: invalid shift state {}", key, shift_state) }),
+                            (None, _) => (),
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            XmlEvent::EndElement { name } => match name.local_name.as_str() {
+                "macro" => if let (Some((guid, macro_def)), Some(profile)) = (current_macro.take(), current.as_mut()) {
+                    profile.macros.insert(guid, macro_def);
+                },
+                "profile" => if let Some(profile) = current.take() {
+                    profiles.push(profile.finish(&mut issues));
+                },
+                _ => (),
+            },
+            XmlEvent::EndDocument => break,
+            _ => (),
+        }
+    }
+    if profiles.is_empty() {
+        return Err(From::from("there is no profile"));
+    }
+    Ok((profiles, issues))
+}
+
+/// Imports the profiles from a file of another driver, the format is given by the extension, `.bind` for the
+/// ecraven/g13 driver and `.xml` for the Logitech Gaming Software. Bindings which can't be imported are reported.
+pub fn import(path: &Path, text: &str) -> result::Result<(Vec<ProfileDef>, Vec<Issue>), FormatError> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("bind") => {
+            let name = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+            let (profile, issues) = import_bind(&name, text);
+            Ok((vec![profile], issues))
+        }
+        Some("xml") => import_logitech(text),
+        _ => Err(From::from("unknown format, expected .bind or .xml")),
+    }
+}
+
+#[test]
+fn test_import_bind() {
+    let text = "
+# comment
+rgb 255 128 0
+mod 9
+bind G1 KEY_ESC
+bind G2 KEY_LEFTSHIFT+KEY_A
+bind G1 KEY_F5
+bind L1 KEY_KP7
+bind G3 !pipe
+bind G30 KEY_A
+stickzone action STICK_UP KEY_W
+stickzone bounds STICK_UP 0 0 1 0.1
+font 8x8
+";
+    let (profile, issues) = import_bind("game", text);
+    assert_eq!((profile.name.as_str(), profile.backlight), ("game", Some("#ff8000".to_owned())));
+    assert_eq!(profile.leds, Some(vec!["M1".to_owned(), "MR".to_owned()]));
+    let device = &profile.modes[0].devices[0];
+    let single: Vec<(&str, &str)> = device.single.iter().map(|single| (single.button.as_str(), single.cmd.as_str())).collect();
+    assert_eq!(single, vec![("G1", "F5"), ("G2", "Shift_L+a"), ("Menu1", "KP_7")]);
+    let axes: Vec<(&str, Vec<&str>)> = device.axes.iter().map(|axis| (axis.axis.as_str(), axis.simple.iter().map(|key| key.as_str()).collect())).collect();
+    assert_eq!(axes, vec![("X", vec!["Left", "Right"]), ("Y", vec!["w", "Down"])]);
+    let lines: Vec<Option<usize>> = issues.iter().map(|issue| issue.line).collect();
+    assert_eq!(lines, vec![Some(9), Some(10), Some(12), Some(13)]);
+}
+
+#[test]
+fn test_import_logitech() {
+    let text = r#"<?xml version="1.0" encoding="utf-8"?>
+<profiles>
+  <profile guid="{P}" name="Game">
+    <backlight><color devicemodel="G13" red="0" green="0" blue="255"/></backlight>
+    <macros>
+      <macro guid="{A}" name="Jump"><keystroke><key value="SPACE"/></keystroke></macro>
+      <macro guid="{B}" name="Copy"><keystroke><down><key value="LCTRL"/><key value="C"/></down><up><key value="C"/><key value="LCTRL"/></up></keystroke></macro>
+      <macro guid="{C}" name="Hello"><textblock>hello</textblock></macro>
+    </macros>
+    <assignments devicecategory="LCD_KB_G13">
+      <assignment contextid="G1" shiftstate="1" macroguid="{A}"/>
+      <assignment contextid="G2" shiftstate="2" macroguid="{B}"/>
+      <assignment contextid="G3" shiftstate="1" macroguid="{C}"/>
+    </assignments>
+    <assignments devicecategory="MOUSE">
+      <assignment contextid="Button4" shiftstate="1" macroguid="{A}"/>
+    </assignments>
+  </profile>
+</profiles>
+"#;
+    let (profiles, issues) = import_logitech(text).unwrap();
+    assert_eq!((profiles[0].name.as_str(), &profiles[0].backlight), ("Game", &Some("#0000ff".to_owned())));
+    let modes: Vec<(&str, &str, &str)> = profiles[0].modes.iter()
+        .flat_map(|mode| mode.devices[0].single.iter().map(move |single| (mode.name.as_str(), single.button.as_str(), single.cmd.as_str())))
+        .collect();
+    assert_eq!(modes, vec![("Mode-1", "G1", "space"), ("Mode-2", "G2", "Control_L+c")]);
+    assert_eq!(issues.len(), 1);
+    assert!(issues[0].message.contains("textblock"));
+    assert!(import_logitech("<profiles>").is_err());
+}
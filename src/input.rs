@@ -1,8 +1,8 @@
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Input {
     ButtonDown (u16),
     ButtonUp (u16),
-    Axis(u16, f32, f32),
+    Axis(u16, f32),
 }
@@ -0,0 +1,148 @@
+use std::fs::File;
+use std::io;
+#[cfg(test)]
+use std::io::ErrorKind;
+#[cfg(test)]
+use std::collections::VecDeque;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+use libc;
+
+use capture::CaptureWriter;
+use device_mapping::DeviceMap;
+use error::Result;
+use input::Input;
+use map_input::MapInput;
+
+/// Source of raw packets read from a device.
+pub trait PacketSource {
+    /// Reads next packet into the buffer, returns its length, or none when nothing arrived in time.
+    fn read_packet(&mut self, buffer: &mut [u8]) -> Result<Option<usize>>;
+}
+
+/// Source of inputs of one device.
+pub trait InputSource {
+    /// Reads next inputs, returns no inputs when nothing arrived in time.
+    fn read_inputs(&mut self) -> Result<Vec<Input>>;
+}
+
+/// Time a read waits for the device, so the reading stops soon on shutdown.
+pub const READ_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Waits until the device node can be read, returns false when nothing arrived in the timeout.
+pub fn wait_readable(file: &File, timeout: Duration) -> io::Result<bool> {
+    let mut fds = libc::pollfd { fd: file.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+    let res = unsafe { libc::poll(&mut fds, 1, timeout.as_millis() as libc::c_int) };
+    if res < 0 {
+        let err = io::Error::last_os_error();
+        // interrupted waiting counts as nothing arrived
+        return if err.kind() == io::ErrorKind::Interrupted { Ok(false) } else { Err(err) };
+    }
+    // errors and hangups are reported by the following read
+    Ok(res > 0)
+}
+
+/// Clears the rest of the buffer after a short packet, so nothing of the previous packet is left there.
+pub fn clear_tail(buffer: &mut [u8], len: usize) {
+    for byte in &mut buffer[len..] {
+        *byte = 0;
+    }
+}
+
+/// Maps raw packets of a device to inputs using the device mapping.
+pub struct PacketInput<S: PacketSource> {
+    source: S,
+    mapping: DeviceMap,
+    buffer: Vec<u8>,
+    map_input: MapInput,
+    capture: Option<CaptureWriter>,
+}
+
+impl<S: PacketSource> PacketInput<S> {
+    /// Creates input from the packet source, optionally capturing every packet read.
+    pub fn new(source: S, mapping: DeviceMap, capture: Option<CaptureWriter>) -> PacketInput<S> {
+        PacketInput {
+            source,
+            buffer: vec![0u8; mapping.packet_size as usize],
+            map_input: MapInput::new(mapping.digitals.len(), mapping.analogs.len()),
+            mapping,
+            capture,
+        }
+    }
+}
+
+impl<S: PacketSource> InputSource for PacketInput<S> {
+    fn read_inputs(&mut self) -> Result<Vec<Input>> {
+        let len = match self.source.read_packet(&mut self.buffer)? {
+            Some(value) => value,
+            None => return Ok(Vec::new()),
+        };
+        clear_tail(&mut self.buffer, len);
+        // only the bytes sent by the device are captured
+        let packet = &self.buffer[..len];
+        if let Some(err) = self.capture.as_mut().and_then(|c| c.write(packet).err()) {
+            error!("Failed to capture packet of {}, stopping the capture: {}", self.mapping.name, err);
+            self.capture = None;
+        }
+        Ok(self.map_input.generate_input(&self.mapping.digitals, &self.mapping.analogs, &self.buffer))
+    }
+}
+
+/// In memory packets, used in tests. When all the packets are read, the device disconnects.
+#[cfg(test)]
+pub struct FakeSource {
+    packets: VecDeque<Vec<u8>>,
+}
+
+#[cfg(test)]
+impl FakeSource {
+    pub fn new(packets: Vec<Vec<u8>>) -> FakeSource {
+        FakeSource {
+            packets: packets.into_iter().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl PacketSource for FakeSource {
+    fn read_packet(&mut self, buffer: &mut [u8]) -> Result<Option<usize>> {
+        match self.packets.pop_front() {
+            Some(packet) => {
+                buffer[..packet.len()].copy_from_slice(&packet);
+                Ok(Some(packet.len()))
+            }
+            None => Err(io::Error::new(ErrorKind::NotConnected, "Fake device disconnected").into()),
+        }
+    }
+}
+
+#[test]
+fn test_fake_source() {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::mpsc;
+    use device_input;
+    use device_mapping::DeviceDigitalInput;
+    use error::Recovery;
+
+    let mapping = DeviceMap {
+        name: "Fake".to_owned(),
+        packet_size: 2,
+        instance: None,
+        source: ::device_mapping::SourceKind::Libusb,
+        device_name: None,
+        lcd: false,
+        backlight: false,
+        digitals: vec![DeviceDigitalInput { name: "G1".to_owned(), uid: 3, index: 1, mask: 4, code: None }],
+        analogs: Vec::new(),
+    };
+    // the short packet releases the key held in the previous one
+    let source = FakeSource::new(vec![vec![0, 0], vec![0, 4], vec![0, 4], vec![0], vec![0, 4]]);
+    let mut input = PacketInput::new(source, mapping, None);
+    let (sender, receiver) = mpsc::channel();
+    let result = device_input::run(&mut input, &sender, &AtomicBool::new(false));
+    // unplugged device is opened again when it is found
+    assert_eq!(result.unwrap_err().recovery(), Recovery::Retry);
+    let inputs: Vec<String> = receiver.try_iter().map(|inp: Input| format!("{:?}", inp)).collect();
+    assert_eq!(inputs, vec!["ButtonDown(3)", "ButtonUp(3)", "ButtonDown(3)"]);
+}
@@ -0,0 +1,331 @@
+use std::cmp;
+
+/// Names of all the keysyms X knows, one on each line, generated from the X11 headers.
+const X_KEYSYMS: &str = include_str!("../xkeysyms.txt");
+
+/// Key names known to the driver, the keysym, the Linux input key without the KEY_ prefix
+/// (see linux/input-event-codes.h), its code and whether the key is typed with shift on a US layout.
+/// The first keysym of a key is the one it types without shift.
+const KEYSYMS: &[(&str, &str, u16, bool)] = &[
+    ("Escape", "ESC", 1, false),
+    ("1", "1", 2, false),
+    ("2", "2", 3, false),
+    ("3", "3", 4, false),
+    ("4", "4", 5, false),
+    ("5", "5", 6, false),
+    ("6", "6", 7, false),
+    ("7", "7", 8, false),
+    ("8", "8", 9, false),
+    ("9", "9", 10, false),
+    ("0", "0", 11, false),
+    ("a", "A", 30, false),
+    ("b", "B", 48, false),
+    ("c", "C", 46, false),
+    ("d", "D", 32, false),
+    ("e", "E", 18, false),
+    ("f", "F", 33, false),
+    ("g", "G", 34, false),
+    ("h", "H", 35, false),
+    ("i", "I", 23, false),
+    ("j", "J", 36, false),
+    ("k", "K", 37, false),
+    ("l", "L", 38, false),
+    ("m", "M", 50, false),
+    ("n", "N", 49, false),
+    ("o", "O", 24, false),
+    ("p", "P", 25, false),
+    ("q", "Q", 16, false),
+    ("r", "R", 19, false),
+    ("s", "S", 31, false),
+    ("t", "T", 20, false),
+    ("u", "U", 22, false),
+    ("v", "V", 47, false),
+    ("w", "W", 17, false),
+    ("x", "X", 45, false),
+    ("y", "Y", 21, false),
+    ("z", "Z", 44, false),
+    ("minus", "MINUS", 12, false),
+    ("equal", "EQUAL", 13, false),
+    ("BackSpace", "BACKSPACE", 14, false),
+    ("Tab", "TAB", 15, false),
+    ("bracketleft", "LEFTBRACE", 26, false),
+    ("bracketright", "RIGHTBRACE", 27, false),
+    ("Return", "ENTER", 28, false),
+    ("Control_L", "LEFTCTRL", 29, false),
+    ("semicolon", "SEMICOLON", 39, false),
+    ("apostrophe", "APOSTROPHE", 40, false),
+    ("grave", "GRAVE", 41, false),
+    ("Shift_L", "LEFTSHIFT", 42, false),
+    ("backslash", "BACKSLASH", 43, false),
+    ("comma", "COMMA", 51, false),
+    ("period", "DOT", 52, false),
+    ("slash", "SLASH", 53, false),
+    ("Shift_R", "RIGHTSHIFT", 54, false),
+    ("KP_Multiply", "KPASTERISK", 55, false),
+    ("Alt_L", "LEFTALT", 56, false),
+    ("space", "SPACE", 57, false),
+    ("Caps_Lock", "CAPSLOCK", 58, false),
+    ("F1", "F1", 59, false),
+    ("F2", "F2", 60, false),
+    ("F3", "F3", 61, false),
+    ("F4", "F4", 62, false),
+    ("F5", "F5", 63, false),
+    ("F6", "F6", 64, false),
+    ("F7", "F7", 65, false),
+    ("F8", "F8", 66, false),
+    ("F9", "F9", 67, false),
+    ("F10", "F10", 68, false),
+    ("Num_Lock", "NUMLOCK", 69, false),
+    ("Scroll_Lock", "SCROLLLOCK", 70, false),
+    ("KP_7", "KP7", 71, false),
+    ("KP_8", "KP8", 72, false),
+    ("KP_9", "KP9", 73, false),
+    ("KP_Subtract", "KPMINUS", 74, false),
+    ("KP_4", "KP4", 75, false),
+    ("KP_5", "KP5", 76, false),
+    ("KP_6", "KP6", 77, false),
+    ("KP_Add", "KPPLUS", 78, false),
+    ("KP_1", "KP1", 79, false),
+    ("KP_2", "KP2", 80, false),
+    ("KP_3", "KP3", 81, false),
+    ("KP_0", "KP0", 82, false),
+    ("KP_Decimal", "KPDOT", 83, false),
+    ("F11", "F11", 87, false),
+    ("F12", "F12", 88, false),
+    ("KP_Enter", "KPENTER", 96, false),
+    ("Control_R", "RIGHTCTRL", 97, false),
+    ("KP_Divide", "KPSLASH", 98, false),
+    ("Print", "SYSRQ", 99, false),
+    ("Alt_R", "RIGHTALT", 100, false),
+    ("Home", "HOME", 102, false),
+    ("Up", "UP", 103, false),
+    ("Prior", "PAGEUP", 104, false),
+    ("Left", "LEFT", 105, false),
+    ("Right", "RIGHT", 106, false),
+    ("End", "END", 107, false),
+    ("Down", "DOWN", 108, false),
+    ("Next", "PAGEDOWN", 109, false),
+    ("Insert", "INSERT", 110, false),
+    ("Delete", "DELETE", 111, false),
+    ("XF86AudioMute", "MUTE", 113, false),
+    ("XF86AudioLowerVolume", "VOLUMEDOWN", 114, false),
+    ("XF86AudioRaiseVolume", "VOLUMEUP", 115, false),
+    ("XF86PowerOff", "POWER", 116, false),
+    ("KP_Equal", "KPEQUAL", 117, false),
+    ("Pause", "PAUSE", 119, false),
+    ("Super_L", "LEFTMETA", 125, false),
+    ("Super_R", "RIGHTMETA", 126, false),
+    ("Menu", "COMPOSE", 127, false),
+    ("XF86Calculator", "CALC", 140, false),
+    ("XF86Sleep", "SLEEP", 142, false),
+    ("XF86Mail", "MAIL", 155, false),
+    ("XF86Back", "BACK", 158, false),
+    ("XF86Forward", "FORWARD", 159, false),
+    ("XF86AudioNext", "NEXTSONG", 163, false),
+    ("XF86AudioPlay", "PLAYPAUSE", 164, false),
+    ("XF86AudioPrev", "PREVIOUSSONG", 165, false),
+    ("XF86AudioStop", "STOPCD", 166, false),
+    ("XF86HomePage", "HOMEPAGE", 172, false),
+    ("XF86Reload", "REFRESH", 173, false),
+    ("F13", "F13", 183, false),
+    ("F14", "F14", 184, false),
+    ("F15", "F15", 185, false),
+    ("F16", "F16", 186, false),
+    ("F17", "F17", 187, false),
+    ("F18", "F18", 188, false),
+    ("F19", "F19", 189, false),
+    ("F20", "F20", 190, false),
+    ("F21", "F21", 191, false),
+    ("F22", "F22", 192, false),
+    ("F23", "F23", 193, false),
+    ("F24", "F24", 194, false),
+    ("XF86Search", "SEARCH", 217, false),
+    // other names of the keys above
+    ("Page_Up", "PAGEUP", 104, false),
+    ("Page_Down", "PAGEDOWN", 109, false),
+    ("Meta_L", "LEFTALT", 56, false),
+    ("ISO_Level3_Shift", "RIGHTALT", 100, false),
+    // typed with shift on a US layout
+    ("exclam", "1", 2, true),
+    ("at", "2", 3, true),
+    ("numbersign", "3", 4, true),
+    ("dollar", "4", 5, true),
+    ("percent", "5", 6, true),
+    ("asciicircum", "6", 7, true),
+    ("ampersand", "7", 8, true),
+    ("asterisk", "8", 9, true),
+    ("parenleft", "9", 10, true),
+    ("parenright", "0", 11, true),
+    ("underscore", "MINUS", 12, true),
+    ("plus", "EQUAL", 13, true),
+    ("braceleft", "LEFTBRACE", 26, true),
+    ("braceright", "RIGHTBRACE", 27, true),
+    ("colon", "SEMICOLON", 39, true),
+    ("quotedbl", "APOSTROPHE", 40, true),
+    ("asciitilde", "GRAVE", 41, true),
+    ("bar", "BACKSLASH", 43, true),
+    ("less", "COMMA", 51, true),
+    ("greater", "DOT", 52, true),
+    ("question", "SLASH", 53, true),
+    ("A", "A", 30, true),
+    ("B", "B", 48, true),
+    ("C", "C", 46, true),
+    ("D", "D", 32, true),
+    ("E", "E", 18, true),
+    ("F", "F", 33, true),
+    ("G", "G", 34, true),
+    ("H", "H", 35, true),
+    ("I", "I", 23, true),
+    ("J", "J", 36, true),
+    ("K", "K", 37, true),
+    ("L", "L", 38, true),
+    ("M", "M", 50, true),
+    ("N", "N", 49, true),
+    ("O", "O", 24, true),
+    ("P", "P", 25, true),
+    ("Q", "Q", 16, true),
+    ("R", "R", 19, true),
+    ("S", "S", 31, true),
+    ("T", "T", 20, true),
+    ("U", "U", 22, true),
+    ("V", "V", 47, true),
+    ("W", "W", 17, true),
+    ("X", "X", 45, true),
+    ("Y", "Y", 21, true),
+    ("Z", "Z", 44, true),
+];
+
+/// Other names of the modifiers accepted in the key sequences, with their keysyms.
+const ALIASES: &[(&str, &str)] = &[
+    ("shift", "Shift_L"),
+    ("ctrl", "Control_L"),
+    ("control", "Control_L"),
+    ("alt", "Alt_L"),
+    ("meta", "Meta_L"),
+    ("super", "Super_L"),
+];
+
+/// Key name of the catalogue.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Keysym {
+    pub name: &'static str,
+    /// Linux input key name without the KEY_ prefix.
+    pub key: &'static str,
+    /// Linux input key code, e.g. for uinput.
+    pub code: u16,
+    /// The key is typed with shift.
+    pub shift: bool,
+}
+
+impl Keysym {
+    fn from_entry(entry: &(&'static str, &'static str, u16, bool)) -> Keysym {
+        Keysym {
+            name: entry.0,
+            key: entry.1,
+            code: entry.2,
+            shift: entry.3,
+        }
+    }
+}
+
+/// All the key names of the catalogue, without the aliases of the modifiers.
+pub fn all() -> Vec<Keysym> {
+    KEYSYMS.iter().map(Keysym::from_entry).collect()
+}
+
+/// Finds the keysym by its name or the alias of a modifier, case sensitive as X keysyms are. Its Linux key code
+/// and shift are what a uinput device has to send to type it.
+pub fn find(name: &str) -> Option<Keysym> {
+    let name = ALIASES.iter().find(|alias| alias.0 == name).map(|alias| alias.1).unwrap_or(name);
+    KEYSYMS.iter().find(|entry| entry.0 == name).map(Keysym::from_entry)
+}
+
+/// Keysym of the Linux input key name, with or without the KEY_ prefix, e.g. KEY_ESC is Escape.
+pub fn from_linux_name(name: &str) -> Option<String> {
+    let name = name.trim_start_matches("KEY_");
+    KEYSYMS.iter().find(|entry| entry.1 == name && !entry.3).map(|entry| entry.0.to_owned())
+}
+
+/// Whether X knows the keysym by the name, also when it's not in the catalogue.
+fn is_x_keysym(name: &str) -> bool {
+    X_KEYSYMS.lines().any(|line| line == name && !line.starts_with('#'))
+}
+
+/// Number of the single character edits between the names.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let replaced = previous + if ca == *cb { 0 } else { 1 };
+            previous = row[j + 1];
+            row[j + 1] = cmp::min(replaced, cmp::min(row[j], row[j + 1]) + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Key name the unknown name was probably meant to be, the name in another case, the keysym of a Linux key name,
+/// e.g. ENTER, or the closest name with at most a third of the characters wrong.
+pub fn suggest(name: &str) -> Option<&'static str> {
+    let names = || KEYSYMS.iter().map(|entry| entry.0).chain(ALIASES.iter().map(|alias| alias.0));
+    if let Some(found) = names().find(|known| known.eq_ignore_ascii_case(name)) {
+        return Some(found);
+    }
+    let upper = name.to_uppercase();
+    if let Some(entry) = KEYSYMS.iter().find(|entry| entry.1 == upper.trim_start_matches("KEY_") && !entry.3) {
+        return Some(entry.0);
+    }
+    let lower = name.to_lowercase();
+    names()
+        .map(|known| (distance(&lower, &known.to_lowercase()), known))
+        .filter(|&(distance, _)| distance <= cmp::max(1, name.chars().count() / 3))
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, known)| known)
+}
+
+/// Checks the keys of a key sequence, e.g. `ctrl+c`, are in the catalogue or are other keysyms X knows.
+/// Unicode keysyms like U20AC and hex codes are taken as they are.
+pub fn check(keys: &str) -> Result<(), String> {
+    for key in keys.split('+') {
+        if key.is_empty() {
+            return Err(format!("empty key in {}", keys));
+        }
+        let unicode = (key.starts_with('U') || key.starts_with("0x")) && key.len() > 2
+            && key.trim_start_matches("0x").trim_start_matches('U').chars().all(|c| c.is_ascii_hexdigit());
+        if unicode || find(key).is_some() || is_x_keysym(key) {
+            continue;
+        }
+        return Err(match suggest(key) {
+            Some(known) => format!("unknown key {}, did you mean {}?", key, known),
+            None => format!("unknown key {}", key),
+        });
+    }
+    Ok(())
+}
+
+#[test]
+fn test_keysyms() {
+    let code = |name| find(name).map(|keysym| (keysym.code, keysym.shift));
+    assert_eq!(code("a"), Some((30, false)));
+    assert_eq!(code("A"), Some((30, true)));
+    assert_eq!(code("ctrl"), Some((29, false)));
+    assert_eq!(code("KP_Enter"), Some((96, false)));
+    assert_eq!(code("F24"), Some((194, false)));
+    assert_eq!(from_linux_name("KEY_1"), Some("1".to_owned()));
+    assert_eq!(from_linux_name("PAGEUP"), Some("Prior".to_owned()));
+    assert_eq!(from_linux_name("KEY_FOO"), None);
+    assert_eq!(check("ctrl+shift+Escape"), Ok(()));
+    assert_eq!(check("U20AC"), Ok(()));
+    // keysyms X knows, which aren't in the catalogue
+    for name in &["KP_Home", "Hyper_L", "shift+ISO_Left_Tab", "XF86AudioMicMute", "eacute"] {
+        assert_eq!(check(name), Ok(()));
+    }
+    assert_eq!(check("ctrl+Escpe"), Err("unknown key Escpe, did you mean Escape?".to_owned()));
+    assert_eq!(check("kp_enter"), Err("unknown key kp_enter, did you mean KP_Enter?".to_owned()));
+    assert_eq!(check("ENTER"), Err("unknown key ENTER, did you mean Return?".to_owned()));
+    assert_eq!(check("Unknown"), Err("unknown key Unknown".to_owned()));
+    assert!(check("ctrl+").is_err());
+}
@@ -0,0 +1,426 @@
+use std::io::{Result, Error, ErrorKind, Read, BufRead, BufReader, Write};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use libusb::Context;
+use png::{self, HasParameters};
+
+use device_input;
+use device_output::OutputTransport;
+use error;
+use font::{self, GLYPH_WIDTH, GLYPH_HEIGHT};
+
+/// Width of the G13 display in pixels.
+pub const WIDTH: usize = 160;
+/// Height of the G13 display in pixels.
+pub const HEIGHT: usize = 43;
+
+/// Output endpoint of the G13 receiving the display frames.
+const G13_LCD_ENDPOINT: u8 = 0x02;
+/// Size of the header preceding the pixels in a G13 frame.
+const G13_HEADER_SIZE: usize = 32;
+/// The display is written in rows of 8 pixels, each byte is a column of one row.
+const G13_ROWS: usize = (HEIGHT + 7) / 8;
+/// Largest image read from a file, in pixels, a few times the display, the image can come from the control socket.
+const MAX_PIXELS: usize = 4 * WIDTH * HEIGHT;
+
+/// Checks the size of an image being read before its pixels are allocated.
+fn check_size(width: usize, height: usize) -> Result<()> {
+    match width.checked_mul(height) {
+        Some(pixels) if pixels <= MAX_PIXELS => Ok(()),
+        _ => {
+            let msg = format!("Image {}x{} is too large, at most {} pixels can be shown", width, height, MAX_PIXELS);
+            Err(Error::new(ErrorKind::InvalidData, msg))
+        }
+    }
+}
+
+/// Monochrome image, set pixels are dark.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pixels: Vec<bool>,
+}
+
+impl Image {
+    /// Creates blank image.
+    pub fn new(width: usize, height: usize) -> Image {
+        Image {
+            width,
+            height,
+            pixels: vec![false; width * height],
+        }
+    }
+
+    /// Creates blank image of the size of the G13 display.
+    pub fn frame() -> Image {
+        Image::new(WIDTH, HEIGHT)
+    }
+
+    /// Returns true when the pixel is set, pixels outside the image are not set.
+    pub fn get(&self, x: i32, y: i32) -> bool {
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height {
+            return false;
+        }
+        self.pixels[y as usize * self.width + x as usize]
+    }
+
+    /// Sets the pixel, pixels outside the image are ignored.
+    pub fn set(&mut self, x: i32, y: i32, value: bool) {
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height {
+            return;
+        }
+        self.pixels[y as usize * self.width + x as usize] = value;
+    }
+
+    /// Draws line between the two points, both included.
+    pub fn line(&mut self, x0: i32, y0: i32, x1: i32, y1: i32) {
+        let dx = (x1 - x0).abs();
+        let dy = -(y1 - y0).abs();
+        let sx = if x0 < x1 { 1 } else { -1 };
+        let sy = if y0 < y1 { 1 } else { -1 };
+        let (mut x, mut y, mut err) = (x0, y0, dx + dy);
+        loop {
+            self.set(x, y, true);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+        }
+    }
+
+    /// Draws text with the top left corner at the point, returns x following the last character.
+    pub fn text(&mut self, x: i32, y: i32, text: &str) -> i32 {
+        let mut x = x;
+        for c in text.chars() {
+            for (column, bits) in font::glyph(c).iter().enumerate() {
+                for row in 0..GLYPH_HEIGHT {
+                    if bits & (1 << row) != 0 {
+                        self.set(x + column as i32, y + row as i32, true);
+                    }
+                }
+            }
+            x += text_width(" ");
+        }
+        x
+    }
+
+    /// Draws text horizontally centered in the image.
+    pub fn text_centered(&mut self, y: i32, text: &str) {
+        let x = (self.width as i32 - text_width(text)) / 2;
+        self.text(x.max(0), y, text);
+    }
+
+    /// Draws the image with its top left corner at the point, only its set pixels are drawn.
+    pub fn draw(&mut self, x: i32, y: i32, image: &Image) {
+        for iy in 0..image.height as i32 {
+            for ix in 0..image.width as i32 {
+                if image.get(ix, iy) {
+                    self.set(x + ix, y + iy, true);
+                }
+            }
+        }
+    }
+
+    /// Reads image from a PBM (plain or raw) or a PNG file, chosen by the extension.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Image> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("png") => Image::read_png(file),
+            _ => Image::read_pbm(BufReader::new(file)),
+        }
+    }
+
+    /// Reads PBM image, both plain (P1) and raw (P4) formats.
+    pub fn read_pbm<R: BufRead>(mut reader: R) -> Result<Image> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let mut pos = 0;
+        let magic = pbm_token(&data, &mut pos)?;
+        let width = pbm_number(&data, &mut pos)?;
+        let height = pbm_number(&data, &mut pos)?;
+        check_size(width, height)?;
+        let mut image = Image::new(width, height);
+        match magic.as_str() {
+            "P1" => {
+                // pixels don't have to be separated by whitespace
+                for i in 0..width * height {
+                    skip_pbm_whitespace(&data, &mut pos);
+                    image.pixels[i] = match data.get(pos) {
+                        Some(&b'0') => false,
+                        Some(&b'1') => true,
+                        Some(_) => return Err(Error::new(ErrorKind::InvalidData, "Invalid PBM pixel")),
+                        None => return Err(Error::new(ErrorKind::UnexpectedEof, "PBM image is truncated")),
+                    };
+                    pos += 1;
+                }
+            }
+            "P4" => {
+                // single whitespace separates the header and the packed rows
+                pos += 1;
+                let row_size = (width + 7) / 8;
+                if data.len() < pos + row_size * height {
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "PBM image is truncated"));
+                }
+                for y in 0..height {
+                    for x in 0..width {
+                        let byte = data[pos + y * row_size + x / 8];
+                        image.pixels[y * width + x] = byte & (0x80 >> (x % 8)) != 0;
+                    }
+                }
+            }
+            _ => {
+                let msg = format!("Unsupported PBM format {}", magic);
+                return Err(Error::new(ErrorKind::InvalidData, msg));
+            }
+        }
+        Ok(image)
+    }
+
+    /// Reads PNG image, pixels darker than a half of the full intensity are set. Palettes and bit depths below 8
+    /// are expanded and 16 bit samples are stripped to 8 bits.
+    pub fn read_png<R: Read>(reader: R) -> Result<Image> {
+        let mut decoder = png::Decoder::new(reader);
+        decoder.set(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let (info, mut reader) = decoder.read_info()?;
+        check_size(info.width as usize, info.height as usize)?;
+        let (color_type, bit_depth) = reader.output_color_type();
+        if bit_depth != png::BitDepth::Eight {
+            let msg = format!("PNG with {} bit samples is not supported", bit_depth as u8);
+            return Err(Error::new(ErrorKind::InvalidData, msg));
+        }
+        let mut data = vec![0u8; reader.output_buffer_size()];
+        reader.next_frame(&mut data)?;
+        let samples = match color_type {
+            png::ColorType::Grayscale => 1,
+            png::ColorType::GrayscaleAlpha => 2,
+            png::ColorType::RGB => 3,
+            png::ColorType::RGBA => 4,
+            png::ColorType::Indexed => {
+                return Err(Error::new(ErrorKind::InvalidData, "PNG palette was not expanded"));
+            }
+        };
+        let (width, height) = (info.width as usize, info.height as usize);
+        let line_size = reader.output_line_size(info.width);
+        let mut image = Image::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let pixel = &data[y * line_size + x * samples..];
+                let luma = if samples < 3 { pixel[0] as u32 } else { (pixel[0] as u32 + pixel[1] as u32 + pixel[2] as u32) / 3 };
+                // transparent pixels stay clear
+                let opaque = match samples {
+                    2 => pixel[1] >= 0x80,
+                    4 => pixel[3] >= 0x80,
+                    _ => true,
+                };
+                image.pixels[y * width + x] = opaque && luma < 0x80;
+            }
+        }
+        Ok(image)
+    }
+
+    /// Writes the image as raw PBM (P4).
+    pub fn to_pbm(&self) -> Vec<u8> {
+        let mut res = format!("P4\n{} {}\n", self.width, self.height).into_bytes();
+        let row_size = (self.width + 7) / 8;
+        for y in 0..self.height {
+            let mut row = vec![0u8; row_size];
+            for x in 0..self.width {
+                if self.pixels[y * self.width + x] {
+                    row[x / 8] |= 0x80 >> (x % 8);
+                }
+            }
+            res.extend(row);
+        }
+        res
+    }
+
+    /// Encodes the image as a G13 frame, the image is cropped to the display.
+    pub fn to_g13(&self) -> Vec<u8> {
+        let mut res = vec![0u8; G13_HEADER_SIZE + G13_ROWS * WIDTH];
+        res[0] = 0x03;
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                if self.get(x as i32, y as i32) {
+                    res[G13_HEADER_SIZE + (y / 8) * WIDTH + x] |= 1 << (y % 8);
+                }
+            }
+        }
+        res
+    }
+}
+
+/// Width of the text in pixels, including the space after the last character.
+pub fn text_width(text: &str) -> i32 {
+    (text.chars().count() * (GLYPH_WIDTH + 1)) as i32
+}
+
+/// Reads next whitespace separated token of PBM header, skipping comments.
+fn pbm_token(data: &[u8], pos: &mut usize) -> Result<String> {
+    skip_pbm_whitespace(data, pos);
+    if *pos >= data.len() {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "PBM image is truncated"));
+    }
+    let start = *pos;
+    while data.get(*pos).map(|b| !b.is_ascii_whitespace()).unwrap_or(false) {
+        *pos += 1;
+    }
+    Ok(String::from_utf8_lossy(&data[start..*pos]).into_owned())
+}
+
+/// Skips whitespace and comments in PBM file.
+fn skip_pbm_whitespace(data: &[u8], pos: &mut usize) {
+    loop {
+        match data.get(*pos) {
+            Some(&b'#') => {
+                while data.get(*pos).map(|&b| b != b'\n').unwrap_or(false) {
+                    *pos += 1;
+                }
+            }
+            Some(b) if b.is_ascii_whitespace() => *pos += 1,
+            _ => break,
+        }
+    }
+}
+
+fn pbm_number(data: &[u8], pos: &mut usize) -> Result<usize> {
+    let token = pbm_token(data, pos)?;
+    token.parse().map_err(|_| Error::new(ErrorKind::InvalidData, format!("Invalid PBM size {}", token)))
+}
+
+/// Default screen showing the active profile and mode.
+pub fn status_screen(profile: &str, mode: &str) -> Image {
+    let mut frame = Image::frame();
+    frame.text_centered(6, profile);
+    frame.line(8, 20, WIDTH as i32 - 9, 20);
+    frame.text_centered(28, mode);
+    frame
+}
+
+/// Where the display frames are sent to.
+pub trait LcdSink {
+    /// Shows the frame on the display.
+    fn write_frame(&mut self, frame: &Image) -> error::Result<()>;
+}
+
+/// Display of a G13 claimed through libusb.
+pub struct G13Lcd<'a, T: OutputTransport + 'a> {
+    transport: &'a T,
+}
+
+impl<'a, T: OutputTransport> G13Lcd<'a, T> {
+    /// Switches the display of the device to accept frames, the interface must be claimed.
+    pub fn new(transport: &'a T) -> error::Result<G13Lcd<'a, T>> {
+        transport.write_control(0, 9, 1, 0, &[])?;
+        Ok(G13Lcd {
+            transport,
+        })
+    }
+}
+
+impl<'a, T: OutputTransport> LcdSink for G13Lcd<'a, T> {
+    fn write_frame(&mut self, frame: &Image) -> error::Result<()> {
+        self.transport.write_interrupt(G13_LCD_ENDPOINT, &frame.to_g13())
+    }
+}
+
+/// Shows the frame on the display of the first G13 found, the device must not be used by the driver.
+pub fn write_to_device(vendor_id: u16, product_id: u16, frame: &Image) -> error::Result<()> {
+    let context = Context::new()?;
+    for device in context.devices()?.iter() {
+        let device_desc = device.device_descriptor()?;
+        if device_desc.vendor_id() == vendor_id && device_desc.product_id() == product_id {
+            let name = format!("{:04x}:{:04x}", vendor_id, product_id);
+            let (mut handle, endpoint) = device_input::open(&context, device.bus_number(), device.address(), &name)?;
+            let result = G13Lcd::new(&handle).and_then(|mut lcd| lcd.write_frame(frame));
+            // the kernel driver gets the device back even when the writing failed
+            device_input::close(&mut handle, &endpoint)?;
+            return result;
+        }
+    }
+    let msg = format!("Device {:04x}:{:04x} not found", vendor_id, product_id);
+    Err(Error::new(ErrorKind::NotFound, msg).into())
+}
+
+/// Writes every frame into a numbered PBM file, for testing without the device.
+pub struct PbmSink {
+    dir: PathBuf,
+    count: usize,
+}
+
+impl PbmSink {
+    /// Creates sink writing to the directory, the directory is created when missing.
+    pub fn new<P: AsRef<Path>>(dir: P) -> error::Result<PbmSink> {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(PbmSink {
+            dir: dir.as_ref().to_owned(),
+            count: 0,
+        })
+    }
+}
+
+impl LcdSink for PbmSink {
+    fn write_frame(&mut self, frame: &Image) -> error::Result<()> {
+        self.count += 1;
+        let path = self.dir.join(format!("frame-{:04}.pbm", self.count));
+        let mut file = File::create(&path)?;
+        file.write_all(&frame.to_pbm())?;
+        debug!("Frame written to {}", path.display());
+        Ok(())
+    }
+}
+
+#[test]
+fn test_g13_frame() {
+    let mut frame = Image::frame();
+    frame.set(0, 0, true);
+    frame.set(1, 9, true);
+    frame.set(159, 42, true);
+    frame.set(160, 0, true); // outside, ignored
+    let data = frame.to_g13();
+    assert_eq!(data.len(), 992);
+    assert_eq!(data[0], 0x03);
+    assert_eq!(data[32], 0x01);
+    assert_eq!(data[32 + 160 + 1], 0x02);
+    assert_eq!(data[32 + 5 * 160 + 159], 0x04);
+    assert_eq!(data.iter().filter(|&&b| b != 0).count(), 4);
+}
+
+#[test]
+fn test_pbm() {
+    let mut frame = Image::frame();
+    frame.line(0, 0, 159, 42);
+    frame.text(10, 30, "G13");
+    let read = Image::read_pbm(&frame.to_pbm()[..]).unwrap();
+    assert_eq!(read, frame);
+    assert!(read.get(0, 0) && read.get(159, 42));
+
+    let plain = Image::read_pbm(&b"P1\n# comment\n3 2\n1 0 1\n010\n"[..]).unwrap();
+    assert_eq!(plain.pixels, vec![true, false, true, false, true, false]);
+    // sizes which overflow or are too large are rejected before the pixels are allocated
+    assert!(Image::read_pbm(&b"P1 4294967295 4294967295\n"[..]).is_err());
+    assert!(Image::read_pbm(&b"P1 18446744073709551615 2\n"[..]).is_err());
+    assert!(Image::read_pbm(&b"P4 4000 4000\n"[..]).is_err());
+}
+
+#[test]
+fn test_read_png() {
+    // the same checkerboard, dark pixels are set
+    let expected = vec![true, false, true, false, false, true, false, true];
+    for data in &[&include_bytes!("../testdata/lcd/gray8.png")[..], &include_bytes!("../testdata/lcd/gray16.png")[..],
+                  &include_bytes!("../testdata/lcd/rgb16.png")[..], &include_bytes!("../testdata/lcd/gray1.png")[..]] {
+        let image = Image::read_png(*data).unwrap();
+        assert_eq!((image.width, image.height), (4, 2));
+        assert_eq!(image.pixels, expected);
+    }
+}
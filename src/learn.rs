@@ -0,0 +1,320 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use libusb::{self, Context, DeviceHandle, TransferType};
+
+use device_input::{self, InputEndpoint};
+use input_source;
+use device_mapping::{DeviceMapDefinition, DeviceButtonDefinition, DeviceAnalogDefinition};
+use error::{Error, Result};
+
+/// How long to record the baseline packets while the device is left untouched.
+const BASELINE_DURATION: Duration = Duration::from_millis(1500);
+/// Minimal change of a byte from its baseline to be recognised as a moved axis.
+const AXIS_THRESHOLD: u8 = 64;
+/// An axis is considered back at rest, when it is this close to its baseline.
+const AXIS_REST: u8 = 16;
+
+/// How long to wait for the user to press a button or move an axis, after that the input is skipped.
+const INPUT_TIMEOUT: Duration = Duration::from_secs(15);
+/// How long to wait for an input to come back to rest, a stuck key or a noisy axis is taken as it is after that.
+const REST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Device which sends nothing while untouched is back at rest, when it hasn't sent anything for this long.
+const QUIET_DURATION: Duration = Duration::from_secs(1);
+
+/// Device opened for learning, reading its raw packets.
+struct Learner<'a> {
+    handle: DeviceHandle<'a>,
+    endpoint: InputEndpoint,
+    buffer: Vec<u8>,
+    /// Packet read while the device is untouched.
+    baseline: Vec<u8>,
+    /// Bytes which change even when the device is untouched, these can't hold digitals.
+    noisy: Vec<bool>,
+    /// Bytes already recognised as axes.
+    axes: Vec<bool>,
+}
+
+fn distance(a: u8, b: u8) -> u8 {
+    if a > b { a - b } else { b - a }
+}
+
+/// The only bit of the packet changed from the baseline, none when nothing or more than one input is pressed.
+/// Noisy bytes and axes are not looked at.
+fn changed_bit(buffer: &[u8], baseline: &[u8], noisy: &[bool], axes: &[bool]) -> Option<(u8, u8)> {
+    let mut found = None;
+    for (i, b) in buffer.iter().enumerate() {
+        let changed = b ^ baseline[i];
+        if changed == 0 || noisy[i] || axes[i] {
+            continue;
+        }
+        if changed.count_ones() == 1 && found.is_none() {
+            found = Some((i as u8, changed));
+        } else {
+            // more than one input pressed at once, wait until it settles
+            return None;
+        }
+    }
+    found
+}
+
+/// The byte of the packet moved the most from the baseline, at least by the threshold, which is not an axis yet.
+fn moved_byte(buffer: &[u8], baseline: &[u8], axes: &[bool]) -> Option<usize> {
+    let mut found = None;
+    let mut largest = AXIS_THRESHOLD;
+    for (i, b) in buffer.iter().enumerate() {
+        let moved = distance(*b, baseline[i]);
+        if moved >= largest && !axes[i] {
+            largest = moved;
+            found = Some(i);
+        }
+    }
+    found
+}
+
+impl<'a> Learner<'a> {
+    /// Reads next packet, returns false when nothing arrived in time.
+    fn read(&mut self) -> Result<bool> {
+        match self.endpoint.transfer_type {
+            TransferType::Interrupt => {
+                match self.handle.read_interrupt(self.endpoint.address, &mut self.buffer, Duration::from_millis(100)) {
+                    Ok(len) => {
+                        input_source::clear_tail(&mut self.buffer, len);
+                        Ok(true)
+                    }
+                    Err(libusb::Error::Timeout) => Ok(false),
+                    Err(err) => Err(err.into()),
+                }
+            }
+            ref t => {
+                let msg = format!("Incompatible transfer method: {:?}", t);
+                Err(Error::invalid(msg))
+            }
+        }
+    }
+
+    /// Records the baseline packet and finds bytes which change on their own. Devices which send nothing until
+    /// touched have no baseline yet, a key is pressed and released then and the last packet is the baseline.
+    fn record_baseline(&mut self) -> Result<()> {
+        let start = Instant::now();
+        let mut first = None;
+        while start.elapsed() < BASELINE_DURATION {
+            if !self.read()? {
+                continue;
+            }
+            match first {
+                None => first = Some(self.buffer.clone()),
+                Some(ref first) => {
+                    for (i, b) in self.buffer.iter().enumerate() {
+                        if *b != first[i] {
+                            self.noisy[i] = true;
+                        }
+                    }
+                }
+            }
+        }
+        let first = match first {
+            Some(value) => value,
+            None => {
+                println!("The device sends nothing while untouched, press and release any button");
+                match self.wait_quiet()? {
+                    Some(value) => value,
+                    None => {
+                        let msg = "The device has sent nothing, its rest state is unknown";
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, msg).into());
+                    }
+                }
+            }
+        };
+        self.baseline = first;
+        Ok(())
+    }
+
+    /// Waits for packets, then until the device stops sending them, and returns the last packet. Returns none
+    /// when nothing arrived in the timeout.
+    fn wait_quiet(&mut self) -> Result<Option<Vec<u8>>> {
+        let start = Instant::now();
+        let mut last: Option<(Vec<u8>, Instant)> = None;
+        loop {
+            if self.read()? {
+                last = Some((self.buffer.clone(), Instant::now()));
+                continue;
+            }
+            match last {
+                Some((ref packet, time)) if time.elapsed() >= QUIET_DURATION => return Ok(Some(packet.clone())),
+                None if start.elapsed() >= INPUT_TIMEOUT => return Ok(None),
+                _ => (),
+            }
+        }
+    }
+
+    /// Reads packets until the condition holds for the last one, returns false when it didn't in the timeout.
+    fn wait_for<F: Fn(&Learner) -> bool>(&mut self, timeout: Duration, condition: F) -> Result<bool> {
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            if self.read()? && condition(self) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Waits for a single bit to change from the baseline, then for it to change back. Returns none when nothing
+    /// was pressed in time. A bit which doesn't change back is taken as its new rest.
+    fn learn_digital(&mut self) -> Result<Option<(u8, u8)>> {
+        if !self.wait_for(INPUT_TIMEOUT, |learner| changed_bit(&learner.buffer, &learner.baseline, &learner.noisy, &learner.axes).is_some())? {
+            return Ok(None);
+        }
+        let (index, mask) = changed_bit(&self.buffer, &self.baseline, &self.noisy, &self.axes).unwrap();
+        let i = index as usize;
+        if !self.wait_for(REST_TIMEOUT, |learner| learner.buffer[i] & mask == learner.baseline[i] & mask)? {
+            warn!("Byte {} mask {:08b} did not go back, it's taken as released", index, mask);
+            self.baseline[i] ^= mask;
+        }
+        Ok(Some((index, mask)))
+    }
+
+    /// Waits for a byte to move far enough from the baseline, then for it to come back to rest. Returns none
+    /// when nothing moved in time.
+    fn learn_analog(&mut self) -> Result<Option<u8>> {
+        if !self.wait_for(INPUT_TIMEOUT, |learner| moved_byte(&learner.buffer, &learner.baseline, &learner.axes).is_some())? {
+            return Ok(None);
+        }
+        let index = moved_byte(&self.buffer, &self.baseline, &self.axes).unwrap();
+        // a noisy axis may never settle, it's found anyway
+        if !self.wait_for(REST_TIMEOUT, |learner| distance(learner.buffer[index], learner.baseline[index]) <= AXIS_REST)? {
+            warn!("Byte {} did not come back to rest", index);
+        }
+        self.axes[index] = true;
+        Ok(Some(index as u8))
+    }
+}
+
+impl<'a> Drop for Learner<'a> {
+    /// Gives the device back to its kernel driver.
+    fn drop(&mut self) {
+        if let Err(err) = device_input::close(&mut self.handle, &self.endpoint) {
+            warn!("Failed to release the device: {}", err);
+        }
+    }
+}
+
+/// Opens the device and asks the user to press each of the buttons and move each of the axes,
+/// building the device mapping from the differences in the packets.
+pub fn learn(vendor_id: u16, product_id: u16, name: &str, buttons: &[&str], axes: &[&str]) -> Result<DeviceMapDefinition> {
+    let context = Context::new()?;
+    let mut location = None;
+    for device in context.devices()?.iter() {
+        let device_desc = device.device_descriptor()?;
+        if device_desc.vendor_id() == vendor_id && device_desc.product_id() == product_id {
+            location = Some((device.bus_number(), device.address()));
+            break;
+        }
+    }
+    let (bus_number, address) = match location {
+        Some(value) => value,
+        None => {
+            let msg = format!("Device {:04x}:{:04x} not found", vendor_id, product_id);
+            return Err(io::Error::new(io::ErrorKind::NotFound, msg).into());
+        }
+    };
+    let (handle, endpoint) = device_input::open(&context, bus_number, address, name)?;
+    let packet_size = endpoint.max_packet_size;
+    let mut learner = Learner {
+        handle,
+        endpoint,
+        buffer: vec![0u8; packet_size as usize],
+        baseline: Vec::new(),
+        noisy: vec![false; packet_size as usize],
+        axes: vec![false; packet_size as usize],
+    };
+
+    println!("Leave the device untouched...");
+    learner.record_baseline()?;
+
+    let mut digitals: Vec<DeviceButtonDefinition> = Vec::new();
+    let mut skipped = Vec::new();
+    for button in buttons {
+        println!("Press and release {}, or wait {} seconds to skip it", button, INPUT_TIMEOUT.as_secs());
+        loop {
+            let (index, mask) = match learner.learn_digital()? {
+                Some(value) => value,
+                None => {
+                    skipped.push(button.to_string());
+                    break;
+                }
+            };
+            // the same bit pressed again is asked for again
+            if let Some(other) = digitals.iter().find(|digital| (digital.index, digital.mask) == (index, mask)) {
+                println!("That is {} already, press and release {}", other.name, button);
+                continue;
+            }
+            info!("{} is at byte {} mask {:08b}", button, index, mask);
+            digitals.push(DeviceButtonDefinition {
+                name: button.to_string(),
+                index,
+                mask,
+            });
+            break;
+        }
+    }
+
+    let mut analogs = Vec::new();
+    for axis in axes {
+        println!("Move {} to one end and let it go back, or wait {} seconds to skip it", axis, INPUT_TIMEOUT.as_secs());
+        let index = match learner.learn_analog()? {
+            Some(value) => value,
+            None => {
+                skipped.push(axis.to_string());
+                continue;
+            }
+        };
+        info!("{} is at byte {}", axis, index);
+        analogs.push(DeviceAnalogDefinition {
+            name: axis.to_string(),
+            index,
+            output: (-1.0, 1.0),
+        });
+    }
+    if !skipped.is_empty() {
+        warn!("Skipped inputs, they are not in the mapping: {}", skipped.join(", "));
+    }
+
+    Ok(DeviceMapDefinition {
+        name: name.to_owned(),
+        packet_size,
+        source: None,
+        device_name: None,
+        digitals: if digitals.is_empty() { None } else { Some(digitals) },
+        bytes: None,
+        analogs: if analogs.is_empty() { None } else { Some(analogs) },
+        keys: None,
+        axes: None,
+        lcd: None,
+        backlight: None,
+        instances: None,
+    })
+}
+
+#[test]
+fn test_changed_bit() {
+    let baseline = [0x80, 0x00, 0x10];
+    let noisy = [false, false, true];
+    let axes = [true, false, false];
+    assert_eq!(changed_bit(&[0x80, 0x04, 0x10], &baseline, &noisy, &axes), Some((1, 0x04)));
+    // a moved axis and a noisy byte are not pressed buttons
+    assert_eq!(changed_bit(&[0x20, 0x00, 0x13], &baseline, &noisy, &axes), None);
+    // two buttons pressed at once
+    assert_eq!(changed_bit(&[0x80, 0x05, 0x10], &baseline, &noisy, &axes), None);
+    assert_eq!(changed_bit(&[0x80, 0x00, 0x10], &baseline, &noisy, &axes), None);
+}
+
+#[test]
+fn test_moved_byte() {
+    let baseline = [0x80, 0x80, 0x00];
+    assert_eq!(moved_byte(&[0x80, 0xf0, 0x10], &baseline, &[false, false, false]), Some(1));
+    // small moves are noise, the largest move wins and learned axes are left out
+    assert_eq!(moved_byte(&[0xa0, 0x80, 0x00], &baseline, &[false, false, false]), None);
+    assert_eq!(moved_byte(&[0x00, 0xd0, 0x00], &baseline, &[false, false, false]), Some(0));
+    assert_eq!(moved_byte(&[0x00, 0xd0, 0x00], &baseline, &[true, false, false]), Some(1));
+}
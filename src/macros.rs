@@ -1,4 +0,0 @@
-
-macro_rules! iotry {
-    ($e: expr) => ( $e.map_err(|err| Error::new(ErrorKind::Other, err))?)
-}
@@ -5,85 +5,1185 @@ extern crate log4rs;
 #[macro_use]
 extern crate log;
 
+extern crate libc;
 extern crate libusb;
 extern crate libxdo;
+extern crate png;
 extern crate yaml_rust;
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
 extern crate serde_yaml;
+extern crate toml;
+extern crate xml;
 
-#[macro_use]
-mod macros;
+mod capture;
+mod cheatsheet;
+mod config;
+mod control;
 mod device_manager;
 mod device_input;
 mod device_mapping;
+mod device_output;
+mod error;
+mod evdev;
+mod font;
+mod hid_descriptor;
+mod hidraw;
+mod hotplug;
+mod import;
 mod input;
+mod input_source;
+mod keysyms;
+mod lcd;
+mod learn;
 mod map_input;
+mod mapper;
+mod output;
+mod privileges;
 mod profile_definition;
+mod schema;
+mod signals;
+mod sysfs;
+mod udev;
+mod yaml_lines;
 
 
 use std::{thread, time};
+use std::cmp;
+use std::process;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+
+use clap::ArgMatches;
+use libxdo::XDo;
+
+use capture::Capture;
+use cheatsheet::{Sheet, SheetFormat};
+use config::{Config, Format, Source};
+use device_mapping::{DeviceInputUid, DeviceMap, DeviceMapDefinition, DeviceMaps, device_key, parse_vid_pid};
+use device_manager::{self as manager, DeviceManager, ListedDevice};
+use error::{Error, Recovery};
+use hotplug::DeviceEvent;
+use lcd::{Image, LcdSink, PbmSink};
+use map_input::MapInput;
+use mapper::Mapper;
+use output::{KeyOutput, OutputKind, PrintOutput};
+use privileges::User;
 
-use device_mapping::DeviceMaps;
-use device_manager::DeviceManager;
+use profile_definition::{Action, Profile, Profiles};
 
-use profile_definition::Profiles;
+/// Exit status of failures not covered by the other statuses.
+const EXIT_FAILURE: i32 = 1;
+/// Exit status when the devices or profiles file is invalid.
+const EXIT_CONFIG: i32 = 2;
+/// Exit status when a device, the display or the running driver is not available.
+const EXIT_UNAVAILABLE: i32 = 3;
 
+/// Exit status of the error of the driver.
+fn exit_status(err: &Error) -> i32 {
+    match err {
+        &Error::ConfigParse(..) | &Error::ConfigValidation(_) => EXIT_CONFIG,
+        _ => EXIT_UNAVAILABLE,
+    }
+}
+
+/// Events handled by the main loop of the driver.
+enum DriverEvent {
+    /// Device was connected or disconnected.
+    Device(DeviceEvent),
+    /// Device events are no longer received.
+    EventsStopped,
+    /// Termination signal was received.
+    Terminate(i32),
+}
 
 fn main() {
-    use clap::{App, Arg};
+    use clap::{App, Arg, SubCommand};
+    // the termination signals are blocked before any thread starts, even the one of the logging, so the driver
+    // receives them only in its signal thread, it warns when they can't be handled
+    let _ = signals::block();
     let matches = App::new(env!("CARGO_PKG_NAME"))
         .version(crate_version!())
         .author(env!("CARGO_PKG_AUTHORS"))
         .about(env!("CARGO_PKG_DESCRIPTION"))
+        .arg(Arg::with_name("config")
+                 .long("config")
+                 .help("Configuration file, default config.yaml in the configuration directories")
+                 .takes_value(true))
         .arg(Arg::with_name("log-config")
                  .long("log-config")
-                 .help("Log configuration file")
-                 .takes_value(true)
-                 .default_value("log.yaml"))
+                 .help("Log configuration file, default log.yaml in the configuration directories, or logging to the console")
+                 .takes_value(true))
         .arg(Arg::with_name("devices")
                  .long("devices")
-                 .help("Devices definition file")
-                 .takes_value(true)
-                 .default_value("devices.yaml"))
+                 .help("Devices definition file in yaml, toml or json, default devices.yaml in the configuration directories, or the bundled one")
+                 .takes_value(true))
         .arg(Arg::with_name("profiles")
                  .long("profiles")
-                 .help("Profiles file")
-                 .takes_value(true)
-                 .default_value("profiles.yaml"))
+                 .help("Profiles file in yaml, toml or json, or directory with profiles files, default profiles.yaml or profiles.d in the configuration directories, or the bundled profiles")
+                 .takes_value(true))
         .arg(Arg::with_name("profile")
                  .long("profile")
                  .short("p")
                  .takes_value(true)
-                 .help("Which profile to use by default, default the first one, can be just first letters case insensitive"))
+                 .help("Which profile to use by default, default the one in config.yaml or the first one, can be just first letters case insensitive"))
+        .arg(Arg::with_name("capture")
+                 .long("capture")
+                 .takes_value(true)
+                 .help("Capture raw packets of every device into files in this directory"))
+        .arg(Arg::with_name("socket")
+                 .long("socket")
+                 .takes_value(true)
+                 .help("Control socket of the driver, default game-kb-driver.sock in XDG_RUNTIME_DIR, or control.sock in the private /tmp/game-kb-driver-UID"))
+        .arg(Arg::with_name("user")
+                 .long("user")
+                 .short("u")
+                 .takes_value(true)
+                 .help("User the driver switches to after opening the devices when started as root, default the one in config.yaml or SUDO_USER"))
+        .subcommand(SubCommand::with_name("run")
+                    .about("Runs the driver, mapping inputs of all connected devices, the default without a subcommand"))
+        .subcommand(SubCommand::with_name("check")
+                    .about("Checks the devices and profiles files"))
+        .subcommand(SubCommand::with_name("list-devices")
+                    .about("Lists connected devices and the mappings used for them")
+                    .arg(Arg::with_name("all")
+                             .long("all")
+                             .short("a")
+                             .help("List also the devices without any mapping")))
+        .subcommand(SubCommand::with_name("list-profiles")
+                    .about("Lists the profiles and their modes"))
+        .subcommand(SubCommand::with_name("list-keys")
+                    .about("Lists the key names known to the driver, with their Linux input keys"))
+        .subcommand(SubCommand::with_name("dump")
+                    .about("Prints actions mapped to the inputs in the selected profile")
+                    .arg(Arg::with_name("mode")
+                             .long("mode")
+                             .short("m")
+                             .takes_value(true)
+                             .help("Print only the mode given by its number or first letters, default all modes")))
+        .subcommand(SubCommand::with_name("cheatsheet")
+                    .about("Renders the actions of a mode of the selected profile on the layout of the G13, or lists them for other devices, for printing")
+                    .arg(Arg::with_name("mode")
+                             .long("mode")
+                             .short("m")
+                             .takes_value(true)
+                             .help("Mode given by its number or first letters, default the first mode"))
+                    .arg(Arg::with_name("device")
+                             .long("device")
+                             .short("d")
+                             .takes_value(true)
+                             .default_value("G13")
+                             .help("Device whose inputs are shown, with the instance if it has any"))
+                    .arg(Arg::with_name("format")
+                             .long("format")
+                             .short("f")
+                             .takes_value(true)
+                             .possible_values(&["svg", "html"])
+                             .help("Format of the cheat-sheet, default by the extension of the output or svg"))
+                    .arg(Arg::with_name("output")
+                             .help("File to write, default printing")))
+        .subcommand(SubCommand::with_name("describe")
+                    .about("Prints a draft device mapping generated from the HID report descriptor")
+                    .arg(Arg::with_name("device")
+                             .long("device")
+                             .short("d")
+                             .takes_value(true)
+                             .required_unless("file")
+                             .help("Read the descriptor from the device given as VID:PID in hex, e.g. 046d:c21c"))
+                    .arg(Arg::with_name("file")
+                             .long("file")
+                             .short("f")
+                             .takes_value(true)
+                             .conflicts_with("device")
+                             .requires("id")
+                             .help("Read the descriptor from a saved binary file"))
+                    .arg(Arg::with_name("id")
+                             .long("id")
+                             .takes_value(true)
+                             .requires("file")
+                             .help("Device the descriptor file was saved from, as VID:PID in hex, the key of the generated mapping"))
+                    .arg(Arg::with_name("interface")
+                             .long("interface")
+                             .short("i")
+                             .takes_value(true)
+                             .default_value("0")
+                             .help("Interface of the device to read the descriptor of"))
+                    .arg(Arg::with_name("name")
+                             .long("name")
+                             .short("n")
+                             .takes_value(true)
+                             .default_value("Device")
+                             .help("Name of the device in the generated mapping")))
+        .subcommand(SubCommand::with_name("learn")
+                    .about("Builds a device mapping by asking to press each button and move each axis")
+                    .arg(Arg::with_name("device")
+                             .long("device")
+                             .short("d")
+                             .takes_value(true)
+                             .required(true)
+                             .help("The device given as VID:PID in hex, e.g. 046d:c21c"))
+                    .arg(Arg::with_name("name")
+                             .long("name")
+                             .short("n")
+                             .takes_value(true)
+                             .required(true)
+                             .help("Name of the device in the generated mapping"))
+                    .arg(Arg::with_name("buttons")
+                             .long("buttons")
+                             .short("b")
+                             .takes_value(true)
+                             .multiple(true)
+                             .use_delimiter(true)
+                             .required_unless("axes")
+                             .help("Names of the buttons to learn, in the order they will be pressed"))
+                    .arg(Arg::with_name("axes")
+                             .long("axes")
+                             .short("a")
+                             .takes_value(true)
+                             .multiple(true)
+                             .use_delimiter(true)
+                             .help("Names of the axes to learn, in the order they will be moved"))
+                    .arg(Arg::with_name("output")
+                             .long("output")
+                             .short("o")
+                             .takes_value(true)
+                             .help("Append the mapping to this devices file instead of printing it")))
+        .subcommand(SubCommand::with_name("replay")
+                    .about("Replays captured packets of a device through the selected profile")
+                    .arg(Arg::with_name("file")
+                             .required(true)
+                             .help("Capture file to replay"))
+                    .arg(Arg::with_name("dry-run")
+                             .long("dry-run")
+                             .help("Print the key events instead of sending them"))
+                    .arg(Arg::with_name("realtime")
+                             .long("realtime")
+                             .help("Keep the delays between the packets as they were captured")))
+        .subcommand(SubCommand::with_name("lcd")
+                    .about("Shows the screen of the selected profile, or an image, on the G13 display")
+                    .arg(Arg::with_name("device")
+                             .long("device")
+                             .short("d")
+                             .takes_value(true)
+                             .default_value("046d:c21c")
+                             .help("The device given as VID:PID in hex"))
+                    .arg(Arg::with_name("image")
+                             .long("image")
+                             .short("i")
+                             .takes_value(true)
+                             .help("PBM or PNG image to show instead of the profile screen"))
+                    .arg(Arg::with_name("output")
+                             .long("output")
+                             .short("o")
+                             .takes_value(true)
+                             .help("Write the frame as PBM file into this directory instead of the device")))
+        .subcommand(SubCommand::with_name("install-udev-rules")
+                    .about("Installs udev rules which let users open the mapped devices without root")
+                    .arg(Arg::with_name("group")
+                             .long("group")
+                             .short("g")
+                             .takes_value(true)
+                             .help("Give access to the users of this group, default the user logged in at the seat"))
+                    .arg(Arg::with_name("output")
+                             .long("output")
+                             .short("o")
+                             .takes_value(true)
+                             .default_value(udev::RULES_PATH)
+                             .help("Rules file to write"))
+                    .arg(Arg::with_name("print")
+                             .long("print")
+                             .conflicts_with("output")
+                             .help("Print the rules instead of installing them")))
+        .subcommand(SubCommand::with_name("convert")
+                    .about("Converts a devices or profiles file between yaml, toml and json, comments are not kept")
+                    .arg(Arg::with_name("kind")
+                             .required(true)
+                             .possible_values(&["devices", "profiles"])
+                             .help("What the file defines"))
+                    .arg(Arg::with_name("input")
+                             .required(true)
+                             .help("File to convert, its format is given by its extension"))
+                    .arg(Arg::with_name("output")
+                             .help("File to write, its format is given by its extension, default printing"))
+                    .arg(Arg::with_name("format")
+                             .long("format")
+                             .short("f")
+                             .takes_value(true)
+                             .possible_values(&["yaml", "toml", "json"])
+                             .required_unless("output")
+                             .help("Format to convert to, default by the extension of the output")))
+        .subcommand(SubCommand::with_name("import")
+                    .about("Imports the profiles from a .bind file of the ecraven/g13 driver or a Logitech profile .xml")
+                    .arg(Arg::with_name("input")
+                             .required(true)
+                             .help("File to import, its format is given by its extension"))
+                    .arg(Arg::with_name("output")
+                             .help("Profiles file to write, its format is given by its extension, default printing"))
+                    .arg(Arg::with_name("format")
+                             .long("format")
+                             .short("f")
+                             .takes_value(true)
+                             .possible_values(&["yaml", "toml", "json"])
+                             .help("Format of the profiles, default by the extension of the output or yaml")))
+        .subcommand(SubCommand::with_name("schema")
+                    .about("Prints the json schema of the devices or profiles file, for validation and completion in editors")
+                    .arg(Arg::with_name("kind")
+                             .required(true)
+                             .possible_values(&["devices", "profiles"])
+                             .help("What the file defines")))
+        .subcommand(SubCommand::with_name("ctl")
+                    .about("Sends a command to the running driver, e.g. profile, mode, reload, events, see help")
+                    .arg(Arg::with_name("command")
+                             .required(true)
+                             .multiple(true)
+                             .help("The command and its arguments")))
         .get_matches();
-    let _ = log4rs::init_file(&matches.value_of("log-config").unwrap(), Default::default())
-        .unwrap();
+    if matches.subcommand_name().is_some() {
+        let _ = signals::unblock();
+    }
+    // the driver runs in the session of the user, whose environment is set before any thread starts,
+    // the logging refreshes its configuration in a thread
+    let config = Config::new(matches.value_of("config"));
+    let target = match (&config, matches.subcommand_name()) {
+        (&Ok(ref config), None) => Some(target_user(&matches, config)),
+        _ => None,
+    };
+    if let Some(Ok((Some(ref user), _))) = target {
+        user.use_session();
+    }
+    if let Err(err) = config::init_logging(matches.value_of("log-config")) {
+        eprintln!("Failed to initialize logging: {}", err);
+        process::exit(EXIT_CONFIG);
+    }
+    let config = match config {
+        Ok(value) => value,
+        Err(err) => {
+            error!("Failed to read configuration: {}", err);
+            process::exit(exit_status(&err));
+        }
+    };
+
+    let result = match matches.subcommand() {
+        ("check", Some(_)) => check(&matches),
+        ("list-devices", Some(sub_matches)) => list_devices(&matches, sub_matches),
+        ("list-profiles", Some(_)) => list_profiles(&matches),
+        ("list-keys", Some(_)) => list_keys(),
+        ("dump", Some(sub_matches)) => dump(&matches, sub_matches, &config),
+        ("cheatsheet", Some(sub_matches)) => cheatsheet(&matches, sub_matches, &config),
+        ("describe", Some(sub_matches)) => describe(sub_matches),
+        ("learn", Some(sub_matches)) => learn(sub_matches),
+        ("replay", Some(sub_matches)) => replay(&matches, sub_matches, &config),
+        ("lcd", Some(sub_matches)) => lcd(&matches, sub_matches, &config),
+        ("install-udev-rules", Some(sub_matches)) => install_udev_rules(&matches, sub_matches),
+        ("convert", Some(sub_matches)) => convert(sub_matches),
+        ("import", Some(sub_matches)) => import(sub_matches),
+        ("schema", Some(sub_matches)) => schema(sub_matches),
+        ("ctl", Some(sub_matches)) => ctl(&matches, sub_matches),
+        _ => run(&matches, &config, target.unwrap_or(Ok((None, None)))),
+    };
+    if let Err(status) = result {
+        process::exit(status);
+    }
+}
+
+/// Reads the devices file, failures are logged.
+fn read_devices(matches: &ArgMatches) -> Result<DeviceMaps, i32> {
+    let source = Source::devices(matches.value_of("devices"));
+    DeviceMaps::new(&source).map_err(|err| {
+        error!("Failed to read devices {}: {}", source, err);
+        EXIT_CONFIG
+    })
+}
+
+/// Reads the profiles for the inputs of the devices, failures are logged.
+fn read_profiles(matches: &ArgMatches, mappings: &DeviceMaps) -> Result<Profiles, i32> {
+    let source = Source::profiles(matches.value_of("profiles"));
+    Profiles::new(&source, mappings.get_inputs()).map_err(|err| {
+        error!("Failed to read profiles {}:\n{}", source, err);
+        EXIT_CONFIG
+    })
+}
+
+/// Logs the advice how to fix the error, if there is one.
+fn log_hint(err: &Error) {
+    if let Some(hint) = err.hint() {
+        error!("{}", hint);
+    }
+}
+
+/// Profile given on the command line, or the one in the configuration.
+fn selected_profile<'a>(matches: &'a ArgMatches, config: &'a Config) -> Option<&'a str> {
+    matches.value_of("profile").or(config.profile.as_ref().map(|s| s.as_str()))
+}
+
+/// Outcome of checking the devices and profiles files.
+#[derive(Debug, Default)]
+struct Checked {
+    /// Summary of every file which could be read.
+    summary: Vec<String>,
+    warnings: Vec<String>,
+    /// Errors of all the files, prefixed with the file they were found in.
+    errors: Vec<String>,
+    /// Exit status of the worst error.
+    status: Option<i32>,
+}
+
+impl Checked {
+    fn error(&mut self, message: String, status: i32) {
+        self.errors.push(message);
+        self.status = cmp::max(self.status, Some(status));
+    }
+}
+
+/// Checks the devices and the profiles files. When the devices have errors, the profiles are still checked
+/// against the inputs of the bundled devices.
+fn check_files(devices: &Source, profiles: &Source) -> Checked {
+    let mut checked = Checked::default();
+    let inputs = match DeviceMaps::new(devices) {
+        Ok(mappings) => {
+            let inputs = mappings.get_inputs();
+            checked.summary.push(format!("{}: {} devices with {} inputs", devices, mappings.devices.len(), inputs.len()));
+            inputs
+        }
+        Err(err) => {
+            let msg = format!("Failed to read devices {}, the profiles are checked against the bundled devices: {}", devices, err);
+            checked.error(msg, exit_status(&err));
+            DeviceMaps::new(&Source::bundled_devices()).map(|mappings| mappings.get_inputs()).unwrap_or_default()
+        }
+    };
+    match Profiles::read(profiles, &inputs) {
+        Ok((read, reports)) => {
+            for (report_path, report) in reports {
+                for warning in &report.warnings {
+                    checked.warnings.push(format!("{}: {}", report_path, warning));
+                }
+                for error in &report.errors {
+                    checked.error(format!("{}: {}", report_path, error), EXIT_CONFIG);
+                }
+            }
+            let modes: usize = read.profiles.iter().map(|profile| profile.modes.len()).sum();
+            checked.summary.push(format!("{}: {} valid profiles with {} modes", profiles, read.profiles.len(), modes));
+        }
+        Err(err) => checked.error(format!("Failed to read profiles {}:\n{}", profiles, err), exit_status(&err)),
+    }
+    checked
+}
+
+/// Checks the devices and profiles files and reports all their errors.
+/// Fails with the status of the worst error, when any of the files has errors.
+fn check(matches: &ArgMatches) -> Result<(), i32> {
+    let checked = check_files(&Source::devices(matches.value_of("devices")), &Source::profiles(matches.value_of("profiles")));
+    for warning in &checked.warnings {
+        warn!("{}", warning);
+    }
+    for error in &checked.errors {
+        error!("{}", error);
+    }
+    for line in &checked.summary {
+        println!("{}", line);
+    }
+    match checked.status {
+        Some(status) => Err(status),
+        None => Ok(()),
+    }
+}
+
+/// Line of the listed device, with its mapping and the identity telling apart the devices of the same kind.
+fn device_line(device: &ListedDevice) -> String {
+    let mut line = format!("{:<10} {:04x}:{:04x} {}", device.address.to_string(), device.key >> 16, device.key & 0xffff,
+                           device.mapping.as_ref().map(|name| name.as_str()).unwrap_or("-"));
+    if let Some(ref name) = device.device_name {
+        line = format!("{} name \"{}\"", line, name);
+    }
+    if let Some(ref serial) = device.identity.serial {
+        line = format!("{} serial {}", line, serial);
+    }
+    if let Some(ref port) = device.identity.port {
+        line = format!("{} port {}", line, port);
+    }
+    line
+}
+
+/// Lists connected devices with their mappings, fails when no mapped device is connected.
+fn list_devices(matches: &ArgMatches, sub_matches: &ArgMatches) -> Result<(), i32> {
+    let mappings = read_devices(matches)?;
+    let devices = match manager::list_devices(&mappings) {
+        Ok(value) => value,
+        Err(err) => {
+            error!("Failed to list devices: {}", err);
+            return Err(EXIT_UNAVAILABLE);
+        }
+    };
+    let all = sub_matches.is_present("all");
+    let mut mapped = 0;
+    for device in &devices {
+        if device.mapping.is_some() {
+            mapped += 1;
+        } else if !all {
+            continue;
+        }
+        println!("{}", device_line(device));
+    }
+    if mapped == 0 {
+        warn!("No connected device has a mapping in {}", Source::devices(matches.value_of("devices")));
+        return Err(EXIT_UNAVAILABLE);
+    }
+    Ok(())
+}
+
+/// Lines of the profiles and their numbered modes.
+fn profile_lines(profiles: &Profiles) -> Vec<String> {
+    let mut lines = Vec::new();
+    for profile in &profiles.profiles {
+        lines.push(format!("{} (pattern {})", profile.name, profile.pattern));
+        for (i, mode) in profile.modes.iter().enumerate() {
+            lines.push(format!("  {} {}", i + 1, mode.name));
+        }
+    }
+    lines
+}
+
+/// Lists profiles and their numbered modes.
+fn list_profiles(matches: &ArgMatches) -> Result<(), i32> {
+    let mappings = read_devices(matches)?;
+    let profiles = read_profiles(matches, &mappings)?;
+    for line in profile_lines(&profiles) {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// Prints the key names of the catalogue, the modifiers can be written also as shift, ctrl, alt and super.
+fn list_keys() -> Result<(), i32> {
+    for keysym in keysyms::all() {
+        let shift = if keysym.shift { " with shift" } else { "" };
+        println!("{:<22} KEY_{} ({}){}", keysym.name, keysym.key, keysym.code, shift);
+    }
+    Ok(())
+}
+
+/// Prints actions mapped to the inputs of all devices in the selected profile and mode.
+fn dump(matches: &ArgMatches, sub_matches: &ArgMatches, config: &Config) -> Result<(), i32> {
+    let mappings = read_devices(matches)?;
+    let profiles = read_profiles(matches, &mappings)?;
+    let profile = find_profile(&profiles, matches, config)?;
+    let modes: Vec<usize> = match sub_matches.value_of("mode") {
+        Some(name) => match profile.find_mode(name) {
+            Some(value) => vec![value],
+            None => {
+                error!("No mode {} in profile {}", name, profile.name);
+                return Err(EXIT_FAILURE);
+            }
+        },
+        None => (0..profile.modes.len()).collect(),
+    };
+    for line in dump_lines(profile, &modes, mappings.get_inputs()) {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// Lines of the actions mapped to the inputs in the modes of the profile, ordered by the input uids.
+fn dump_lines(profile: &Profile, modes: &[usize], mut inputs: Vec<DeviceInputUid>) -> Vec<String> {
+    inputs.sort_by_key(|input| input.parts().2);
+    let mut lines = Vec::new();
+    for &index in modes {
+        let mode = &profile.modes[index];
+        lines.push(format!("{} mode {} {}", profile.name, index + 1, mode.name));
+        for input in &inputs {
+            let (device, name, uid) = input.parts();
+            match mode.output[uid as usize] {
+                Action::NoOp => (),
+                ref action => lines.push(format!("  {:>4} {} {}: {}", uid, device, name, action)),
+            }
+        }
+    }
+    lines
+}
+
+/// Profile selected on the command line or in config.yaml, the first one without any.
+fn find_profile<'a>(profiles: &'a Profiles, matches: &ArgMatches, config: &Config) -> Result<&'a Profile, i32> {
+    match selected_profile(matches, config) {
+        Some(name) => match profiles.find(name) {
+            Some(value) => Ok(&profiles.profiles[value]),
+            None => {
+                error!("No profile {}", name);
+                Err(EXIT_FAILURE)
+            }
+        },
+        None => Ok(&profiles.profiles[0]),
+    }
+}
+
+/// Renders a cheat-sheet of the actions of the device in a mode of the selected profile.
+fn cheatsheet(matches: &ArgMatches, sub_matches: &ArgMatches, config: &Config) -> Result<(), i32> {
+    let mappings = read_devices(matches)?;
+    let profiles = read_profiles(matches, &mappings)?;
+    let profile = find_profile(&profiles, matches, config)?;
+    let mode = match sub_matches.value_of("mode") {
+        Some(name) => match profile.find_mode(name) {
+            Some(value) => &profile.modes[value],
+            None => {
+                error!("No mode {} in profile {}", name, profile.name);
+                return Err(EXIT_FAILURE);
+            }
+        },
+        None => &profile.modes[0],
+    };
+    let mut inputs = mappings.get_inputs();
+    inputs.sort_by_key(|input| input.parts().2);
+    let device = sub_matches.value_of("device").unwrap();
+    let sheet = Sheet::new(profile, mode, &inputs, device, cheatsheet::is_g13(&mappings, device));
+    if sheet.is_empty() {
+        error!("No device {} in the devices definition", device);
+        return Err(EXIT_FAILURE);
+    }
+    let extension = sub_matches.value_of("output").and_then(|output| Path::new(output).extension()).and_then(|ext| ext.to_str());
+    let format = sub_matches.value_of("format").or(extension).and_then(SheetFormat::new).unwrap_or(SheetFormat::Svg);
+    write_output(sub_matches, &sheet.render(format))
+}
+
+/// Writes udev rules for the devices of the devices file, or prints them.
+fn install_udev_rules(matches: &ArgMatches, sub_matches: &ArgMatches) -> Result<(), i32> {
+    let mappings = read_devices(matches)?;
+    let rules = udev::rules(&mappings, sub_matches.value_of("group"));
+    if sub_matches.is_present("print") {
+        print!("{}", rules);
+        return Ok(());
+    }
+    let file_path = sub_matches.value_of("output").unwrap();
+    udev::install(Path::new(file_path), &rules).map_err(|err| {
+        error!("Failed to write udev rules to {}: {}", file_path, err);
+        if !privileges::is_root() {
+            error!("Run it as root, e.g. with sudo, or print the rules with --print");
+        }
+        EXIT_FAILURE
+    })
+}
+
+/// User the driver switches to when it runs as root, given on the command line, in the configuration,
+/// or the user who started it with sudo. Nothing is switched when it doesn't run as root. The user is found
+/// before the logging starts, so the warning to log with it, or the error, is returned.
+fn target_user(matches: &ArgMatches, config: &Config) -> Result<(Option<User>, Option<String>), String> {
+    let name = matches.value_of("user").map(str::to_owned).or(config.user.clone());
+    if !privileges::is_root() {
+        let warning = name.map(|name| format!("Not running as root, user {} is ignored", name));
+        return Ok((None, warning));
+    }
+    let name = match name.or(env::var("SUDO_USER").ok()) {
+        Some(value) => value,
+        None => {
+            let warning = "Running as root, set --user or user in config.yaml to drop the privileges after opening the devices";
+            return Ok((None, Some(warning.to_owned())));
+        }
+    };
+    match User::find(&name) {
+        Ok(user) => Ok((Some(user), None)),
+        Err(err) => Err(format!("Failed to find user {}: {}", name, err)),
+    }
+}
+
+/// Converts a devices or profiles file to another format, writing it to the output or printing it.
+fn convert(matches: &ArgMatches) -> Result<(), i32> {
+    let input = Path::new(matches.value_of("input").unwrap());
+    let text = fs::read_to_string(input).map_err(|err| {
+        error!("Failed to read {}: {}", input.display(), err);
+        EXIT_FAILURE
+    })?;
+    let to = output_format(matches);
+    let converted = match matches.value_of("kind").unwrap() {
+        "devices" => DeviceMapDefinition::convert(&text, Format::of(input), to),
+        _ => profile_definition::convert(&text, Format::of(input), to),
+    };
+    let converted = converted.map_err(|err| {
+        error!("Failed to convert {}: {}", input.display(), err);
+        EXIT_CONFIG
+    })?;
+    write_output(matches, &converted)
+}
+
+/// Format to write, given by the format argument or by the extension of the output file, yaml without both.
+fn output_format(matches: &ArgMatches) -> Format {
+    match (matches.value_of("format").and_then(Format::from_extension), matches.value_of("output")) {
+        (Some(format), _) => format,
+        (None, Some(output)) => Format::of(Path::new(output)),
+        (None, None) => Format::Yaml,
+    }
+}
+
+/// Writes the text into the output file, or prints it without one.
+fn write_output(matches: &ArgMatches, text: &str) -> Result<(), i32> {
+    match matches.value_of("output").map(Path::new) {
+        Some(output) => fs::write(output, text).map_err(|err| {
+            error!("Failed to write {}: {}", output.display(), err);
+            EXIT_FAILURE
+        }),
+        None => {
+            print!("{}", text);
+            Ok(())
+        }
+    }
+}
+
+/// Imports the profiles from a file of another driver, the bindings which can't be imported are reported.
+fn import(matches: &ArgMatches) -> Result<(), i32> {
+    let input = Path::new(matches.value_of("input").unwrap());
+    let text = fs::read_to_string(input).map_err(|err| {
+        error!("Failed to read {}: {}", input.display(), err);
+        EXIT_FAILURE
+    })?;
+    let (profile_defs, issues) = import::import(input, &text).map_err(|err| {
+        error!("Failed to import {}: {}", input.display(), err);
+        EXIT_CONFIG
+    })?;
+    for issue in &issues {
+        warn!("{}: not imported: {}", input.display(), issue);
+    }
+    let imported = profile_definition::write(&profile_defs, output_format(matches)).map_err(|err| {
+        error!("Failed to write the profiles: {}", err);
+        EXIT_FAILURE
+    })?;
+    write_output(matches, &imported)
+}
+
+/// Prints the json schema of the devices or profiles file.
+fn schema(matches: &ArgMatches) -> Result<(), i32> {
+    let schema = match matches.value_of("kind").unwrap() {
+        "devices" => DeviceMapDefinition::schema(),
+        _ => profile_definition::schema(),
+    };
+    let schema = schema.map_err(|err| {
+        error!("Failed to trace the schema: {}", err);
+        EXIT_FAILURE
+    })?;
+    match serde_json::to_string_pretty(&schema) {
+        Ok(text) => {
+            println!("{}", text);
+            Ok(())
+        }
+        Err(err) => {
+            error!("Failed to write the schema: {}", err);
+            Err(EXIT_FAILURE)
+        }
+    }
+}
 
-    let mappings = DeviceMaps::new(matches.value_of("devices").unwrap().as_ref()).unwrap();
+/// Path of the control socket, given on the command line or the default one.
+fn socket_path(matches: &ArgMatches) -> PathBuf {
+    matches.value_of("socket").map(PathBuf::from).unwrap_or_else(control::default_socket_path)
+}
+
+/// Sends the command to the running driver and prints its result.
+fn ctl(matches: &ArgMatches, sub_matches: &ArgMatches) -> Result<(), i32> {
+    let command = sub_matches.values_of("command").unwrap().collect::<Vec<_>>().join(" ");
+    match control::send(&socket_path(matches), &command) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(EXIT_FAILURE),
+        Err(err) => {
+            error!("Failed to send the command: {}", err);
+            Err(EXIT_UNAVAILABLE)
+        }
+    }
+}
+
+/// Prints draft mapping of a device read from its HID report descriptor.
+fn describe(matches: &ArgMatches) -> Result<(), i32> {
+    let (key, data) = match matches.value_of("device") {
+        Some(device) => {
+            let (vid, pid) = match parse_vid_pid(device) {
+                Some(value) => value,
+                None => {
+                    error!("Invalid device {}, expected VID:PID in hex", device);
+                    return Err(EXIT_FAILURE);
+                }
+            };
+            let interface = match value_t!(matches, "interface", u8) {
+                Ok(value) => value,
+                Err(err) => {
+                    error!("Invalid interface: {}", err);
+                    return Err(EXIT_FAILURE);
+                }
+            };
+            (device_key(vid, pid), hid_descriptor::read_from_device(vid, pid, interface))
+        }
+        None => {
+            let id = matches.value_of("id").unwrap();
+            let (vid, pid) = match parse_vid_pid(id) {
+                Some(value) => value,
+                None => {
+                    error!("Invalid id {}, expected VID:PID in hex", id);
+                    return Err(EXIT_FAILURE);
+                }
+            };
+            (device_key(vid, pid), hid_descriptor::read_from_file(matches.value_of("file").unwrap()))
+        }
+    };
+    let data = match data {
+        Ok(value) => value,
+        Err(err) => {
+            error!("Failed to read report descriptor: {}", err);
+            log_hint(&err);
+            return Err(EXIT_UNAVAILABLE);
+        }
+    };
+    let descriptor = match hid_descriptor::parse(&data) {
+        Ok(value) => value,
+        Err(err) => {
+            error!("Failed to parse report descriptor: {}", err);
+            return Err(EXIT_FAILURE);
+        }
+    };
+    let draft = descriptor.draft(matches.value_of("name").unwrap());
+    for skipped in &draft.skipped {
+        println!("# skipped {}", skipped);
+    }
+    match draft.definition.to_yaml_entry(key) {
+        Ok(entry) => {
+            print!("{}", entry);
+            Ok(())
+        }
+        Err(err) => {
+            error!("Failed to write mapping: {}", err);
+            Err(EXIT_FAILURE)
+        }
+    }
+}
+
+/// Learns mapping of a device from its live packets and writes it out.
+fn learn(matches: &ArgMatches) -> Result<(), i32> {
+    let device = matches.value_of("device").unwrap();
+    let (vid, pid) = match parse_vid_pid(device) {
+        Some(value) => value,
+        None => {
+            error!("Invalid device {}, expected VID:PID in hex", device);
+            return Err(EXIT_FAILURE);
+        }
+    };
+    let buttons = matches.values_of("buttons").map(|v| v.collect()).unwrap_or(Vec::new());
+    let axes = matches.values_of("axes").map(|v| v.collect()).unwrap_or(Vec::new());
+    let definition = match learn::learn(vid, pid, matches.value_of("name").unwrap(), &buttons, &axes) {
+        Ok(value) => value,
+        Err(err) => {
+            error!("Failed to learn the device: {}", err);
+            log_hint(&err);
+            return Err(EXIT_UNAVAILABLE);
+        }
+    };
+    let entry = match definition.to_yaml_entry(device_key(vid, pid)) {
+        Ok(value) => value,
+        Err(err) => {
+            error!("Failed to write mapping: {}", err);
+            return Err(EXIT_FAILURE);
+        }
+    };
+    match matches.value_of("output") {
+        Some(file_path) => {
+            let written = OpenOptions::new().create(true).append(true).open(file_path)
+                .and_then(|mut file| write!(file, "\n{}", entry));
+            match written {
+                Ok(_) => {
+                    info!("Mapping of {} appended to {}", device, file_path);
+                    Ok(())
+                }
+                Err(err) => {
+                    error!("Failed to write mapping to {}: {}", file_path, err);
+                    Err(EXIT_FAILURE)
+                }
+            }
+        }
+        None => {
+            print!("{}", entry);
+            Ok(())
+        }
+    }
+}
+
+/// Feeds captured packets through the device mapping and the mapper.
+/// Inputs held at the end of the capture are released.
+fn replay_packets<O: KeyOutput>(mapper: &mut Mapper<O>, mapping: &DeviceMap, capture: &Capture, realtime: bool) {
+    let mut map_input = MapInput::new(mapping.digitals.len(), mapping.analogs.len());
+    let mut last_timestamp = 0;
+    for packet in &capture.packets {
+        if realtime && packet.timestamp > last_timestamp {
+            thread::sleep(time::Duration::from_micros(packet.timestamp - last_timestamp));
+        }
+        last_timestamp = packet.timestamp;
+        if packet.data.len() > mapping.packet_size as usize {
+            warn!("Skipping packet at {}us, it has {} bytes instead of {}", packet.timestamp, packet.data.len(), mapping.packet_size);
+            continue;
+        }
+        // short packets are read into a cleared buffer, as they are by the device
+        let mut data = packet.data.clone();
+        data.resize(mapping.packet_size as usize, 0);
+        for inp in map_input.generate_input(&mapping.digitals, &mapping.analogs, &data) {
+            mapper.handle(inp);
+        }
+    }
+    mapper.release_inputs(&mapping.uids());
+}
+
+/// Replays capture file through the selected profile, the key events are printed in dry run or with the print output.
+fn replay(matches: &ArgMatches, sub_matches: &ArgMatches, config: &Config) -> Result<(), i32> {
+    let file_path = sub_matches.value_of("file").unwrap();
+    let capture = match Capture::new(file_path) {
+        Ok(value) => value,
+        Err(err) => {
+            error!("Failed to read capture {}: {}", file_path, err);
+            return Err(EXIT_FAILURE);
+        }
+    };
+    let mappings = read_devices(matches)?;
+    // replay through the instance the packets were captured from, or the generic mapping
+    let mapping = mappings.devices.get(&capture.key)
+        .and_then(|maps| maps.iter().find(|map| map.full_name() == capture.name).or(maps.first()));
+    let mapping = match mapping {
+        Some(value) => value.clone(),
+        None => {
+            error!("No mapping for captured device {} (0x{:08x})", capture.name, capture.key);
+            return Err(EXIT_CONFIG);
+        }
+    };
+    let profiles = read_profiles(matches, &mappings)?;
+    let profile = selected_profile(matches, config);
+    let realtime = sub_matches.is_present("realtime");
+    if sub_matches.is_present("dry-run") || config.output == OutputKind::Print {
+        let mut mapper = Mapper::new(profiles, profile, PrintOutput);
+        info!("Profile selected: {}", mapper.profile().name);
+        replay_packets(&mut mapper, &mapping, &capture, realtime);
+    } else {
+        let xdo = match XDo::new(None) {
+            Ok(value) => value,
+            Err(err) => {
+                error!("Failed to open display: {:?}", err);
+                return Err(EXIT_UNAVAILABLE);
+            }
+        };
+        let mut mapper = Mapper::new(profiles, profile, xdo);
+        info!("Profile selected: {}", mapper.profile().name);
+        replay_packets(&mut mapper, &mapping, &capture, realtime);
+    }
+    Ok(())
+}
+
+/// Shows a frame on the display of the device or writes it to a directory.
+fn lcd(matches: &ArgMatches, sub_matches: &ArgMatches, config: &Config) -> Result<(), i32> {
+    let frame = match sub_matches.value_of("image") {
+        Some(file_path) => {
+            let image = match Image::open(file_path) {
+                Ok(value) => value,
+                Err(err) => {
+                    error!("Failed to read image {}: {}", file_path, err);
+                    return Err(EXIT_FAILURE);
+                }
+            };
+            let mut frame = Image::frame();
+            let x = (lcd::WIDTH as i32 - image.width as i32) / 2;
+            let y = (lcd::HEIGHT as i32 - image.height as i32) / 2;
+            frame.draw(x, y, &image);
+            frame
+        }
+        None => {
+            let mappings = read_devices(matches)?;
+            let profiles = read_profiles(matches, &mappings)?;
+            let profile = &profiles.profiles[profiles.select(selected_profile(matches, config))];
+            lcd::status_screen(&profile.name, &profile.modes[0].name)
+        }
+    };
+    let (result, status) = match sub_matches.value_of("output") {
+        Some(dir) => (PbmSink::new(dir).and_then(|mut sink| sink.write_frame(&frame)), EXIT_FAILURE),
+        None => {
+            let device = sub_matches.value_of("device").unwrap();
+            match parse_vid_pid(device) {
+                Some((vid, pid)) => (lcd::write_to_device(vid, pid, &frame), EXIT_UNAVAILABLE),
+                None => {
+                    error!("Invalid device {}, expected VID:PID in hex", device);
+                    return Err(EXIT_FAILURE);
+                }
+            }
+        }
+    };
+    result.map_err(|err| {
+        error!("Failed to write the frame: {}", err);
+        log_hint(&err);
+        status
+    })
+}
+
+/// Runs the driver, mapping inputs of all connected devices to the selected profile, until it fails
+/// or it's terminated by a signal.
+fn run(matches: &ArgMatches, config: &Config, target: Result<(Option<User>, Option<String>), String>) -> Result<(), i32> {
+    // the key output is created as root, but connects to the display of the user
+    let user = match target {
+        Ok((user, warning)) => {
+            if let Some(warning) = warning {
+                warn!("{}", warning);
+            }
+            user
+        }
+        Err(err) => {
+            error!("{}", err);
+            return Err(EXIT_CONFIG);
+        }
+    };
+    if let Some(ref user) = user {
+        if config.output == OutputKind::Xdo && env::var_os("DISPLAY").is_none() {
+            warn!("No X session of user {} found, set DISPLAY for the key output", user.name);
+        }
+    }
+
+    // the signals were blocked in main, so only the signal thread receives them
+    let (event_sender, event_receiver) = mpsc::channel();
+    let signal_sender = event_sender.clone();
+    if let Err(err) = signals::on_termination(move |signal| {
+        let _ = signal_sender.send(DriverEvent::Terminate(signal));
+    }) {
+        warn!("Termination signals not handled, keys may stay pressed on exit: {}", err);
+    }
+
+    let mappings = read_devices(matches)?;
     let device_inputs = mappings.get_inputs();
-    let profiles = Profiles::new(matches.value_of("profiles").unwrap().as_ref(), device_inputs).unwrap();
+    let profiles = read_profiles(matches, &mappings)?;
     info!("Found profiles:");
     for ref profile in profiles.profiles.iter() {
         info!("{}", profile.name);
     }
     //println!("{:?}", mappings);
 
-    let mut device_manager: DeviceManager = match DeviceManager::new(mappings, profiles, matches.value_of("profile").take()) {
+    let capture_dir = matches.value_of("capture").map(PathBuf::from);
+    let mut device_manager: DeviceManager = match DeviceManager::new(mappings, profiles, selected_profile(matches, config), config.output, capture_dir) {
         Ok(value) => value,
         Err(err) => {
             error!("Failed to create manager: {}", err);
-            return;
+            return Err(exit_status(&err));
+        }
+    };
+
+    // devices are discovered on udev events, polling is used only when the events are not available
+    let mut polling = match hotplug::Monitor::new() {
+        Ok(mut monitor) => {
+            thread::spawn(move || {
+                loop {
+                    match monitor.next_event() {
+                        Ok(event) => {
+                            if event_sender.send(DriverEvent::Device(event)).is_err() {
+                                break;
+                            }
+                        }
+                        Err(err) => {
+                            error!("Failed to receive device events: {}", err);
+                            let _ = event_sender.send(DriverEvent::EventsStopped);
+                            break;
+                        }
+                    }
+                }
+            });
+            false
+        }
+        Err(err) => {
+            warn!("Device events not available, polling for devices instead: {}", err);
+            true
         }
     };
 
+    let poll_interval = time::Duration::from_secs(config.poll_interval);
+    // the devices found now are opened before the switch, the ones connected later with the permissions of the user
+    let mut result = device_manager.discover();
+    if let Some(user) = user {
+        if let Err(err) = user.switch() {
+            error!("Failed to switch to user {}: {}", user.name, err);
+            device_manager.shutdown();
+            return Err(EXIT_FAILURE);
+        }
+        info!("Running as user {}", user.name);
+    }
+
+    // the driver runs without the control socket, when it can't listen on it
+    let control = device_manager.control(Source::profiles(matches.value_of("profiles")), device_inputs);
+    let socket = socket_path(matches);
+    if let Err(err) = control::start(&socket, control) {
+        error!("Failed to start control socket: {}", err);
+    }
+
+    // failed devices are retried on the timer, also when the device events keep coming
+    let mut next_poll = time::Instant::now() + poll_interval;
     loop {
-        match device_manager.discover() {
-            Ok(_) => {},
-            Err(err) => {
-                error!("Failed to discover devices: {}", err);
+        if let Err(err) = result {
+            if err.recovery() == Recovery::Exit {
+                error!("Driver can't continue: {}", err);
+                return Err(exit_status(&err));
             }
+            error!("Failed to discover devices: {}", err);
         }
-        thread::sleep(time::Duration::from_secs(4));
+        let now = time::Instant::now();
+        let timeout = if next_poll > now { next_poll - now } else { time::Duration::from_secs(0) };
+        result = match event_receiver.recv_timeout(timeout) {
+            Ok(DriverEvent::Device(event)) => device_manager.handle_event(event),
+            Ok(DriverEvent::EventsStopped) => {
+                warn!("Device events stopped, polling for devices instead");
+                polling = true;
+                device_manager.poll(polling)
+            }
+            Ok(DriverEvent::Terminate(signal)) => {
+                info!("Stopping on signal {}", signal);
+                device_manager.shutdown();
+                let _ = fs::remove_file(&socket);
+                return Ok(());
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                next_poll = time::Instant::now() + poll_interval;
+                device_manager.poll(polling)
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                // without the signal thread and the device events
+                thread::sleep(poll_interval);
+                device_manager.poll(true)
+            }
+        };
     }
 }
+
+#[test]
+fn test_check() {
+    let bundled = check_files(&Source::bundled_devices(), &Source::profiles(Some("profiles.yaml")));
+    assert_eq!(bundled.errors, Vec::<String>::new());
+    assert_eq!(bundled.status, None);
+    assert_eq!(bundled.summary.len(), 2);
+    // the profiles are checked also when the devices can't be read
+    let checked = check_files(&Source::Bundled("devices.yaml", "G13: ["), &Source::File(PathBuf::from("testdata/profiles")));
+    assert_eq!(checked.status, Some(EXIT_CONFIG));
+    assert!(checked.errors[0].starts_with("Failed to read devices bundled devices.yaml"));
+    assert!(checked.errors.contains(&"testdata/profiles/broken.yaml: line 7: profile Broken, mode Walk, device G15: unknown device".to_owned()));
+    assert_eq!(checked.summary, vec!["testdata/profiles: 3 valid profiles with 3 modes"]);
+}
+
+#[test]
+fn test_device_line() {
+    use device_mapping::DeviceIdentity;
+    let mut device = ListedDevice {
+        address: manager::DeviceAddress::Usb(3, 5),
+        key: device_key(0x046d, 0xc21c),
+        device_name: None,
+        mapping: Some("G13".to_owned()),
+        identity: DeviceIdentity { serial: None, port: Some("3-1".to_owned()) },
+    };
+    assert_eq!(device_line(&device), "3-5        046d:c21c G13 port 3-1");
+    device.address = manager::DeviceAddress::Evdev(PathBuf::from("/dev/input/event7"));
+    device.mapping = None;
+    device.device_name = Some("Logitech Gaming Mouse G600".to_owned());
+    device.identity = DeviceIdentity::default();
+    assert_eq!(device_line(&device), "event7     046d:c21c - name \"Logitech Gaming Mouse G600\"");
+}
+
+#[test]
+fn test_list_profiles_and_dump() {
+    let inputs = DeviceMaps::new(&Source::bundled_devices()).unwrap().get_inputs();
+    let (profiles, _) = Profiles::read(&Source::File(PathBuf::from("testdata/profiles")), &inputs).unwrap();
+    assert_eq!(profile_lines(&profiles), vec!["Base (pattern *)", "  1 Walk", "Extra (pattern *)", "  1 Walk", "Game (pattern *)", "  1 Walk"]);
+    assert_eq!(dump_lines(&profiles.profiles[0], &[0], inputs), vec!["Base mode 1 Walk", "     0 G13 G1: key Escape"]);
+}
@@ -5,7 +5,8 @@ use input::Input;
 
 pub struct MapInput {
     pressed: Vec<bool>,
-    analogs: Vec<u8>,
+    /// Last values of the analogs, unknown until the first packet, so it reports them wherever they are.
+    analogs: Vec<Option<u8>>,
 }
 
 
@@ -13,7 +14,7 @@ impl MapInput {
     pub fn new(num_digitals: usize, num_analogs: usize) -> MapInput {
         MapInput {
             pressed: vec![false; num_digitals],
-            analogs: vec![0; num_analogs],
+            analogs: vec![None; num_analogs],
         }
     }
     pub fn generate_input(&mut self, digitals: &[DeviceDigitalInput], analogs: &[DeviceAnalogInput], buffer: &[u8]) -> Vec<Input> {
@@ -31,10 +32,9 @@ impl MapInput {
         }
         for (i, ref analog) in analogs.iter().enumerate() {
             let current = buffer[analog.index as usize];
-            if current != self.analogs[i] {
-                let old = self.analogs[i];
-                self.analogs[i] = current;
-                res.push(Input::Axis(analog.uid, analog.convert(current), analog.convert(old)));
+            if Some(current) != self.analogs[i] {
+                self.analogs[i] = Some(current);
+                res.push(Input::Axis(analog.uid, analog.convert(current)));
             }
         }
 
@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+
+use device_output::DeviceCommand;
+use input::Input;
+use lcd;
+use output::KeyOutput;
+use profile_definition::{Profiles, Profile, Mode};
+
+/// Side of an input which presses a key, the buttons are pressed on the positive side.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Side {
+    Rest,
+    Negative,
+    Positive,
+}
+
+impl Side {
+    fn of(value: f32) -> Side {
+        if value < -0.5 {
+            Side::Negative
+        } else if value > 0.5 {
+            Side::Positive
+        } else {
+            Side::Rest
+        }
+    }
+}
+
+/// Maps inputs from the devices to the actions of the active profile.
+pub struct Mapper<O: KeyOutput> {
+    profiles: Profiles,
+    /// Index of the active profile.
+    profile: usize,
+    /// Index of the active mode of the profile.
+    mode: usize,
+    output: O,
+    /// Inputs which are not at rest, by their uids, with their side and the keys they pressed. The keys are
+    /// released as they were pressed, even when the mode or profile has changed since.
+    active: HashMap<u16, (Side, Option<String>)>,
+}
+
+impl<O: KeyOutput> Mapper<O> {
+    /// Creates mapper with the profile selected by the first letters of its name, or the first profile.
+    pub fn new(profiles: Profiles, profile: Option<&str>, output: O) -> Mapper<O> {
+        let profile = profiles.select(profile);
+        Mapper {
+            profiles,
+            profile,
+            mode: 0,
+            output,
+            active: HashMap::new(),
+        }
+    }
+
+    /// The active profile.
+    pub fn profile(&self) -> &Profile {
+        &self.profiles.profiles[self.profile]
+    }
+
+    /// The active mode of the active profile.
+    pub fn mode(&self) -> &Mode {
+        &self.profile().modes[self.mode]
+    }
+
+    /// Output of the key presses.
+    pub fn output(&self) -> &O {
+        &self.output
+    }
+
+    /// All the profiles.
+    pub fn profiles(&self) -> &Profiles {
+        &self.profiles
+    }
+
+    /// Activates the profile selected by the first letters of its name in its first mode, returns false when there is no such profile.
+    pub fn set_profile(&mut self, name: &str) -> bool {
+        match self.profiles.find(name) {
+            Some(profile) => {
+                self.profile = profile;
+                self.mode = 0;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Activates the mode of the active profile selected by its number or the first letters of its name,
+    /// returns false when there is no such mode.
+    pub fn set_mode(&mut self, name: &str) -> bool {
+        match self.profile().find_mode(name) {
+            Some(mode) => {
+                self.mode = mode;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Replaces the profiles, the active profile and mode are kept when they still exist.
+    pub fn set_profiles(&mut self, profiles: Profiles) {
+        let profile_name = self.profile().name.to_owned();
+        let mode_name = self.mode().name.to_owned();
+        self.profile = profiles.profiles.iter().position(|profile| profile.name == profile_name).unwrap_or(0);
+        self.mode = profiles.profiles[self.profile].modes.iter().position(|mode| mode.name == mode_name).unwrap_or(0);
+        self.profiles = profiles;
+    }
+
+    /// Commands setting backlight, mode LEDs and display of the devices for the active profile and mode.
+    /// Without LEDs set, the LED of the mode is lit, M1 for the first mode.
+    pub fn device_state(&self) -> Vec<DeviceCommand> {
+        let profile = self.profile();
+        let mode = self.mode();
+        let mut res = Vec::new();
+        if let Some(color) = mode.backlight.or(profile.backlight) {
+            res.push(DeviceCommand::Backlight(color));
+        }
+        let default_leds = if self.mode < 3 { 1 << self.mode } else { 0 };
+        res.push(DeviceCommand::Leds(mode.leds.or(profile.leds).unwrap_or(default_leds)));
+        res.push(DeviceCommand::Frame(lcd::status_screen(&profile.name, &mode.name)));
+        res
+    }
+
+    /// Executes action mapped to the input in the active mode, the keys are pressed when the input leaves
+    /// its rest or changes its side, and the keys it pressed before are released.
+    pub fn handle(&mut self, inp: Input) {
+        let (uid, value) = match inp {
+            Input::ButtonDown(uid) => (uid, 1.0),
+            Input::ButtonUp(uid) => (uid, 0.0),
+            Input::Axis(uid, value) => (uid, value),
+        };
+        let side = Side::of(value);
+        if self.active.get(&uid).map(|active| active.0).unwrap_or(Side::Rest) == side {
+            return;
+        }
+        if let Some((_, Some(keys))) = self.active.remove(&uid) {
+            self.output.key_up(&keys);
+        }
+        if side != Side::Rest {
+            let keys = self.mode().output[uid as usize].keys(value).map(str::to_owned);
+            if let Some(ref keys) = keys {
+                self.output.key_down(keys);
+            }
+            self.active.insert(uid, (side, keys));
+        }
+    }
+
+    /// Releases the keys pressed by the uids which are not at rest, used when their device is gone.
+    pub fn release_inputs(&mut self, uids: &[u16]) {
+        for uid in uids {
+            if let Some((side, keys)) = self.active.remove(uid) {
+                trace!("Releasing input {} on {:?} side", uid, side);
+                if let Some(keys) = keys {
+                    self.output.key_up(&keys);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_replay_packets() {
+    use device_mapping::{DeviceDigitalInput, DeviceAnalogInput};
+    use map_input::MapInput;
+    use output::RecordOutput;
+    use profile_definition::Action;
+
+    let digitals = vec![DeviceDigitalInput { name: "G1".to_owned(), uid: 0, index: 1, mask: 1, code: None }];
+    let analogs = vec![DeviceAnalogInput { name: "X".to_owned(), uid: 1, index: 0, output: (-1.0, 1.0), code: None }];
+    let profiles = Profiles {
+        profiles: vec![Profile {
+            name: "Test".to_owned(),
+            pattern: "*".to_owned(),
+            backlight: None,
+            leds: None,
+            modes: vec![Mode {
+                name: "Mode-1".to_owned(),
+                backlight: None,
+                leds: None,
+                output: vec![Action::Key("Escape".to_owned()), Action::Axis("a".to_owned(), "d".to_owned())],
+            }],
+        }],
+    };
+    let mut mapper = Mapper::new(profiles, None, RecordOutput::default());
+    let mut map_input = MapInput::new(digitals.len(), analogs.len());
+    for packet in &[[0x00, 0], [0x80, 1], [0xff, 1], [0x80, 0]] {
+        for inp in map_input.generate_input(&digitals, &analogs, packet) {
+            mapper.handle(inp);
+        }
+    }
+    // the first packet presses the key of the axis already pushed left, the keys are released only after being pressed
+    assert_eq!(*mapper.output.events.borrow(), vec!["down a", "down Escape", "up a", "down d", "up Escape", "up d"]);
+    // without any lights set, the led of the first mode is lit
+    assert_eq!(mapper.device_state()[0], DeviceCommand::Leds(1));
+}
+
+#[test]
+fn test_release_disconnected() {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::mpsc;
+    use device_input;
+    use device_mapping::{DeviceMap, DeviceDigitalInput, DeviceAnalogInput, SourceKind};
+    use input_source::{FakeSource, PacketInput};
+    use output::RecordOutput;
+    use profile_definition::Action;
+
+    let mapping = DeviceMap {
+        name: "Fake".to_owned(),
+        packet_size: 2,
+        instance: None,
+        source: SourceKind::Libusb,
+        device_name: None,
+        lcd: false,
+        backlight: false,
+        digitals: vec![DeviceDigitalInput { name: "G1".to_owned(), uid: 0, index: 1, mask: 1, code: None }],
+        analogs: vec![DeviceAnalogInput { name: "X".to_owned(), uid: 1, index: 0, output: (-1.0, 1.0), code: None }],
+    };
+    let profiles = Profiles {
+        profiles: vec![Profile {
+            name: "Test".to_owned(),
+            pattern: "*".to_owned(),
+            backlight: None,
+            leds: None,
+            modes: vec![Mode {
+                name: "Mode-1".to_owned(),
+                backlight: None,
+                leds: None,
+                output: vec![Action::Key("Escape".to_owned()), Action::Axis("a".to_owned(), "d".to_owned())],
+            }],
+        }],
+    };
+    let mut mapper = Mapper::new(profiles, None, RecordOutput::default());
+    let uids = mapping.uids();
+    // the device is disconnected while G1 is held and the axis is pushed right
+    let source = FakeSource::new(vec![vec![0x80, 0], vec![0xff, 1]]);
+    let (sender, receiver) = mpsc::channel();
+    assert!(device_input::run(&mut PacketInput::new(source, mapping, None), &sender, &AtomicBool::new(false)).is_err());
+    for inp in receiver.try_iter() {
+        mapper.handle(inp);
+    }
+    mapper.release_inputs(&uids);
+    // released inputs are at rest, so they are not released again
+    mapper.release_inputs(&uids);
+    assert_eq!(*mapper.output.events.borrow(), vec!["down Escape", "down d", "up Escape", "up d"]);
+}
+
+#[test]
+fn test_release_after_switch() {
+    use output::RecordOutput;
+    use profile_definition::Action;
+
+    let mode = |name: &str, key: &str, left: &str| Mode {
+        name: name.to_owned(),
+        backlight: None,
+        leds: None,
+        output: vec![Action::Key(key.to_owned()), Action::Axis(left.to_owned(), "d".to_owned())],
+    };
+    let profiles = Profiles {
+        profiles: vec![Profile {
+            name: "Test".to_owned(),
+            pattern: "*".to_owned(),
+            backlight: None,
+            leds: None,
+            modes: vec![mode("Walk", "Escape", "a"), mode("Drive", "space", "Left")],
+        }],
+    };
+    let mut mapper = Mapper::new(profiles, None, RecordOutput::default());
+    mapper.handle(Input::ButtonDown(0));
+    mapper.handle(Input::Axis(1, -1.0));
+    mapper.set_mode("Drive");
+    // the held keys are released as they were pressed in the first mode, the axis presses the key of
+    // the second mode when it's pushed again
+    mapper.handle(Input::Axis(1, -0.9));
+    mapper.handle(Input::ButtonUp(0));
+    mapper.handle(Input::Axis(1, 0.0));
+    mapper.handle(Input::Axis(1, -1.0));
+    mapper.release_inputs(&[0, 1]);
+    assert_eq!(*mapper.output.events.borrow(), vec!["down Escape", "down a", "up Escape", "up a", "down Left", "up Left"]);
+}
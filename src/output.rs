@@ -0,0 +1,150 @@
+use std::cell::RefCell;
+
+use libxdo::XDo;
+
+/// Destination of the simulated key presses.
+pub trait KeyOutput {
+    /// Presses the key sequence, e.g. `ctrl+c` or `Escape`.
+    fn key_down(&self, keys: &str);
+    /// Releases the key sequence.
+    fn key_up(&self, keys: &str);
+}
+
+impl KeyOutput for XDo {
+    fn key_down(&self, keys: &str) {
+        if let Err(err) = self.send_keysequence_down(keys, 0) {
+            error!("Failed to press {}: {}", keys, err);
+        }
+    }
+
+    fn key_up(&self, keys: &str) {
+        if let Err(err) = self.send_keysequence_up(keys, 0) {
+            error!("Failed to release {}: {}", keys, err);
+        }
+    }
+}
+
+/// Remembers the key sequences pressed through the output, so they can be released when the driver stops.
+pub struct HeldKeys<O: KeyOutput> {
+    output: O,
+    /// Pressed key sequences, in the order they were first pressed, with the number of presses not yet released.
+    /// Inputs pressing the same sequence hold it until all of them release it.
+    held: RefCell<Vec<(String, usize)>>,
+}
+
+impl<O: KeyOutput> HeldKeys<O> {
+    pub fn new(output: O) -> HeldKeys<O> {
+        HeldKeys {
+            output,
+            held: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Releases all the held key sequences, the last pressed first.
+    pub fn release_all(&self) {
+        let held: Vec<String> = self.held.borrow_mut().drain(..).map(|entry| entry.0).collect();
+        for keys in held.iter().rev() {
+            info!("Releasing held {}", keys);
+            self.output.key_up(keys);
+        }
+    }
+}
+
+impl<O: KeyOutput> KeyOutput for HeldKeys<O> {
+    fn key_down(&self, keys: &str) {
+        {
+            let mut held = self.held.borrow_mut();
+            match held.iter().position(|entry| entry.0 == keys) {
+                Some(index) => held[index].1 += 1,
+                None => held.push((keys.to_owned(), 1)),
+            }
+        }
+        self.output.key_down(keys);
+    }
+
+    fn key_up(&self, keys: &str) {
+        {
+            let mut held = self.held.borrow_mut();
+            if let Some(index) = held.iter().position(|entry| entry.0 == keys) {
+                held[index].1 -= 1;
+                if held[index].1 == 0 {
+                    held.remove(index);
+                }
+            }
+        }
+        self.output.key_up(keys);
+    }
+}
+
+/// Backend of the key output selected in the configuration.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputKind {
+    /// Key presses are sent to the X display through libxdo.
+    Xdo,
+    /// Key events are printed, nothing is pressed.
+    Print,
+}
+
+impl OutputKind {
+    pub fn new(name: &str) -> Option<OutputKind> {
+        match name {
+            "xdo" => Some(OutputKind::Xdo),
+            "print" => Some(OutputKind::Print),
+            _ => None,
+        }
+    }
+}
+
+/// Prints the key events instead of sending them, used for dry runs.
+pub struct PrintOutput;
+
+impl KeyOutput for PrintOutput {
+    fn key_down(&self, keys: &str) {
+        println!("down {}", keys);
+    }
+
+    fn key_up(&self, keys: &str) {
+        println!("up {}", keys);
+    }
+}
+
+/// Records the key events, used in tests.
+#[cfg(test)]
+#[derive(Default)]
+pub struct RecordOutput {
+    pub events: ::std::cell::RefCell<Vec<String>>,
+}
+
+#[cfg(test)]
+impl KeyOutput for RecordOutput {
+    fn key_down(&self, keys: &str) {
+        self.events.borrow_mut().push(format!("down {}", keys));
+    }
+
+    fn key_up(&self, keys: &str) {
+        self.events.borrow_mut().push(format!("up {}", keys));
+    }
+}
+
+#[test]
+fn test_held_keys() {
+    let output = HeldKeys::new(RecordOutput::default());
+    output.key_down("a");
+    output.key_down("ctrl+c");
+    output.key_down("a");
+    output.key_up("b");
+    output.key_down("d");
+    output.key_up("ctrl+c");
+    output.release_all();
+    // released keys are not released again, and nothing is left after the release
+    output.release_all();
+    assert_eq!(*output.output.events.borrow(), vec!["down a", "down ctrl+c", "down a", "up b", "down d", "up ctrl+c", "up d", "up a"]);
+
+    // the sequence pressed by two inputs is held until both release it
+    let output = HeldKeys::new(RecordOutput::default());
+    output.key_down("a");
+    output.key_down("a");
+    output.key_up("a");
+    output.release_all();
+    assert_eq!(*output.output.events.borrow(), vec!["down a", "down a", "up a", "up a"]);
+}
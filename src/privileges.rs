@@ -0,0 +1,111 @@
+use std::env;
+use std::ffi::{CStr, CString, OsString};
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use libc;
+
+/// Whether the process runs as root.
+pub fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// User the driver switches to after it has opened the devices as root.
+#[derive(Debug)]
+pub struct User {
+    pub name: String,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    home: PathBuf,
+}
+
+impl User {
+    /// Finds the user by name in the user database.
+    pub fn find(name: &str) -> Result<User> {
+        let c_name = CString::new(name).map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+        let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+        let mut buf = vec![0 as libc::c_char; 16384];
+        let mut found: *mut libc::passwd = ptr::null_mut();
+        let res = unsafe { libc::getpwnam_r(c_name.as_ptr(), &mut passwd, buf.as_mut_ptr(), buf.len(), &mut found) };
+        if res != 0 {
+            return Err(Error::from_raw_os_error(res));
+        }
+        if found.is_null() {
+            return Err(Error::new(ErrorKind::NotFound, format!("no user {}", name)));
+        }
+        let home = unsafe { CStr::from_ptr(passwd.pw_dir) }.to_string_lossy().into_owned();
+        Ok(User {
+            name: name.to_owned(),
+            uid: passwd.pw_uid,
+            gid: passwd.pw_gid,
+            home: PathBuf::from(home),
+        })
+    }
+
+    /// DISPLAY and XAUTHORITY of the X session of the user, taken from the environment of one of its processes.
+    fn x_session(&self) -> Option<(OsString, Option<OsString>)> {
+        for entry in fs::read_dir("/proc").ok()?.filter_map(|entry| entry.ok()) {
+            match entry.metadata() {
+                Ok(ref metadata) if metadata.uid() == self.uid => (),
+                _ => continue,
+            }
+            // processes which have exited or aren't readable are skipped
+            let environ = match fs::read(entry.path().join("environ")) {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            let var = |name: &str| environ.split(|byte| *byte == 0)
+                .find(|item| item.len() > name.len() && item.starts_with(name.as_bytes()) && item[name.len()] == b'=')
+                .map(|item| OsString::from_vec(item[name.len() + 1..].to_vec()));
+            if let Some(display) = var("DISPLAY") {
+                return Some((display, var("XAUTHORITY")));
+            }
+        }
+        None
+    }
+
+    /// Points the X display and the runtime directory at the session of the user, unless they are already set,
+    /// e.g. when sudo has kept them. The display stays unset when the user has no X session. Must be called
+    /// before any thread starts, the environment is not safe to change while other threads may read it.
+    pub fn use_session(&self) {
+        if env::var_os("DISPLAY").is_none() {
+            if let Some((display, xauthority)) = self.x_session() {
+                env::set_var("DISPLAY", display);
+                if let (None, Some(xauthority)) = (env::var_os("XAUTHORITY"), xauthority) {
+                    env::set_var("XAUTHORITY", xauthority);
+                }
+            }
+        }
+        let xauthority = self.home.join(".Xauthority");
+        if env::var_os("XAUTHORITY").is_none() && xauthority.exists() {
+            env::set_var("XAUTHORITY", xauthority);
+        }
+        // the control socket is created there, where the clients of the user look for it
+        let runtime_dir = Path::new("/run/user").join(self.uid.to_string());
+        if runtime_dir.is_dir() {
+            env::set_var("XDG_RUNTIME_DIR", runtime_dir);
+        }
+        env::set_var("HOME", &self.home);
+        env::set_var("USER", &self.name);
+    }
+
+    /// Switches the process with all its threads to the user and its groups, for good.
+    pub fn switch(&self) -> Result<()> {
+        let c_name = CString::new(self.name.as_str()).map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+        unsafe {
+            if libc::initgroups(c_name.as_ptr(), self.gid) != 0 || libc::setgid(self.gid) != 0 || libc::setuid(self.uid) != 0 {
+                return Err(Error::last_os_error());
+            }
+            // root can't be regained
+            if libc::setuid(0) == 0 {
+                return Err(Error::new(ErrorKind::Other, "root privileges were not dropped"));
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,73 +1,179 @@
-use std::io::{self};
-use std::fs::File;
-use std::collections::HashMap;
-use serde_yaml::{self};
-use libxdo::XDo;
+use std::fmt;
+use std::fs;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::result;
+use serde_json;
+use serde_yaml::{self, Value};
 
+use config::{Format, FormatError, Source};
 use device_mapping::DeviceInputUid;
+use device_output::{self, Color};
+use error::{Error, Result};
+use keysyms;
+use schema::{document, trace};
+use yaml_lines::YamlLines;
 
+/// Key which clears the binding inherited from the parent.
+const UNSET: &str = "unset";
+
+/// Profile as written in the profiles file.
 #[derive(Serialize, Deserialize)]
-struct ProfileDef {
-    name: String,
-    pattern: String,
-    modes: Vec<ModeDef>,
+pub struct ProfileDef {
+    pub name: String,
+    /// Name of the parent profile, its pattern, lights and modes are inherited.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extends: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    /// Backlight colour as `#rrggbb`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backlight: Option<String>,
+    /// Names of the mode LEDs to turn on, e.g. [M1, MR].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub leds: Option<Vec<String>>,
+    /// Modes named as the modes of the parent override them, the others are added.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub modes: Vec<ModeDef>,
 }
 
 #[derive(Serialize, Deserialize)]
-struct ModeDef {
-    name: String,
-    devices: Vec<DeviceDef>,
+pub struct ModeDef {
+    pub name: String,
+    /// Name of another mode of the profile, whose lights and bindings are inherited.
+    /// The mode of the parent profile with the same name is inherited without it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extends: Option<String>,
+    /// Backlight colour of the mode, overrides the one of the profile.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backlight: Option<String>,
+    /// Mode LEDs to turn on, overrides the ones of the profile.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub leds: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub devices: Vec<DeviceDef>,
 }
 
 #[derive(Serialize, Deserialize)]
-struct DeviceDef {
-    device: String,
-    single: Vec<SingleDef>,
-    singles: Vec<SinglesDef>,
-    axes: Vec<AxisDef>,
+pub struct DeviceDef {
+    pub device: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub single: Vec<SingleDef>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub singles: Vec<SinglesDef>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub axes: Vec<AxisDef>,
 }
 
 #[derive(Serialize, Deserialize)]
-struct SingleDef {
-    button: String,
-    cmd: String,
+pub struct SingleDef {
+    pub button: String,
+    pub cmd: String,
 }
 
 #[derive(Serialize, Deserialize)]
-struct SinglesDef {
-    button: String,
-    index: u8,
-    cmds: Vec<String>,
+pub struct SinglesDef {
+    pub button: String,
+    pub index: u8,
+    pub cmds: Vec<String>,
 }
 
 
 
 #[derive(Serialize, Deserialize)]
-struct AxisDef {
-    axis: String,
-    simple: Vec<String>,
+pub struct AxisDef {
+    pub axis: String,
+    pub simple: Vec<String>,
+}
+
+/// Profiles file in toml, which can't be a list, has the entries as `[[profiles]]` tables.
+#[derive(Serialize, Deserialize)]
+struct TomlProfiles {
+    #[serde(default)]
+    profiles: Vec<Value>,
 }
 
+/// Parses the entries of a profiles file in the format, the profiles and the includes.
+fn parse_entries(text: &str, format: Format) -> result::Result<Vec<Value>, FormatError> {
+    match format {
+        Format::Toml => Ok(format.parse::<TomlProfiles>(text)?.profiles),
+        _ => format.parse(text),
+    }
+}
+
+/// Whether the entry of a profiles file includes another file.
+fn is_include(entry: &Value) -> bool {
+    entry.as_mapping().and_then(|mapping| mapping.get(&Value::String("include".to_owned()))).is_some()
+}
 
-#[derive(Debug)]
+/// Converts a profiles file from one format to another. The profiles are checked to have the expected structure,
+/// included paths are kept as they are.
+pub fn convert(text: &str, from: Format, to: Format) -> result::Result<String, FormatError> {
+    let mut entries = Vec::new();
+    for entry in parse_entries(text, from)? {
+        if is_include(&entry) {
+            entries.push(entry);
+        } else {
+            let profile_def: ProfileDef = serde_yaml::from_value(entry)?;
+            entries.push(serde_yaml::to_value(&profile_def)?);
+        }
+    }
+    write_entries(entries, to)
+}
+
+/// Writes the entries of a profiles file in the format.
+fn write_entries(entries: Vec<Value>, format: Format) -> result::Result<String, FormatError> {
+    match format {
+        Format::Toml => format.write(&TomlProfiles { profiles: entries }),
+        _ => format.write(&entries),
+    }
+}
+
+/// Writes the profiles as a profiles file in the format.
+pub fn write(profile_defs: &[ProfileDef], format: Format) -> result::Result<String, FormatError> {
+    let entries = profile_defs.iter().map(serde_yaml::to_value).collect::<result::Result<Vec<_>, _>>()?;
+    write_entries(entries, format)
+}
+
+/// Json schema of a profiles file in yaml or json, a list of profiles and includes.
+pub fn schema() -> result::Result<serde_json::Value, String> {
+    let mut definitions = serde_json::Map::new();
+    let profile = trace::<ProfileDef>(&mut definitions)?;
+    definitions.insert("Include".to_owned(), json!({
+        "type": "object",
+        "properties": {"include": {"type": "string"}},
+        "required": ["include"],
+    }));
+    let root = json!({
+        "type": "array",
+        "items": {"anyOf": [profile, {"$ref": "#/definitions/Include"}]},
+    });
+    Ok(document("game-kb-driver profiles", root, definitions))
+}
+
+#[derive(Debug, PartialEq)]
 pub struct Profiles {
     pub profiles: Vec<Profile>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Profile {
     pub name: String,
     pub pattern: String,
+    pub backlight: Option<Color>,
+    pub leds: Option<u8>,
     pub modes: Vec<Mode>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Mode {
     pub name: String,
+    pub backlight: Option<Color>,
+    pub leds: Option<u8>,
     pub output: Vec<Action>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Action {
     NoOp,
     Key(String),
@@ -76,106 +182,890 @@ pub enum Action {
 
 
 
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Action::NoOp => write!(f, "-"),
+            &Action::Key(ref key) => write!(f, "key {}", key),
+            &Action::Axis(ref left, ref right) => write!(f, "axis {} {}", left, right),
+        }
+    }
+}
+
 impl Action {
-    pub fn execute(&self, xdo: &XDo, input: f32, old_input: f32) {
+    /// Keys pressed by the input at the value, the buttons are pressed at 1, the axes press their left key
+    /// below -0.5 and their right key above 0.5.
+    pub fn keys(&self, value: f32) -> Option<&str> {
         match self {
-            &Action::NoOp => (),
-            &Action::Key(ref s) => {
-                if input > 0.5 {
-                    xdo.send_keysequence_down(s,0).unwrap();
-                } else {
-                    xdo.send_keysequence_up(s,0).unwrap();
-                }
+            &Action::NoOp => None,
+            &Action::Key(ref s) if value > 0.5 => Some(s),
+            &Action::Axis(ref l, _) if value < -0.5 => Some(l),
+            &Action::Axis(_, ref r) if value > 0.5 => Some(r),
+            _ => None,
+        }
+    }
+}
+
+
+/// Problem found in the profiles, with the line of the entry it was found in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Issue {
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}", line, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Errors and warnings found while loading profiles, profiles with errors are rejected.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub errors: Vec<Issue>,
+    pub warnings: Vec<Issue>,
+}
+
+impl Report {
+    fn error(&mut self, line: Option<usize>, message: String) {
+        self.errors.push(Issue { line, message });
+    }
+
+    fn warning(&mut self, line: Option<usize>, message: String) {
+        self.warnings.push(Issue { line, message });
+    }
+}
+
+/// Parses backlight colour and mode LEDs of a profile or a mode, invalid ones are reported.
+fn parse_lights(owner: &str, backlight: Option<String>, leds: Option<Vec<String>>, line: Option<usize>, report: &mut Report) -> (Option<Color>, Option<u8>) {
+    let backlight = backlight.and_then(|text| {
+        let color = Color::parse(&text);
+        if color.is_none() {
+            report.error(line, format!("{}: invalid backlight {}, expected #rrggbb", owner, text));
+        }
+        color
+    });
+    let leds = leds.and_then(|names| {
+        let leds = device_output::parse_leds(&names);
+        if leds.is_none() {
+            report.error(line, format!("{}: invalid leds {:?}, expected some of {:?}", owner, names, device_output::LED_NAMES));
+        }
+        leds
+    });
+    (backlight, leds)
+}
+
+/// Unique ids of the inputs by their device and input names, a device name without the instance
+/// targets the generic mapping and all its named instances.
+struct InputsIndex {
+    inputs: HashMap<(String, String), Vec<u16>>,
+    devices: HashSet<String>,
+}
+
+impl InputsIndex {
+    fn new(device_inputs: &[DeviceInputUid]) -> InputsIndex {
+        let mut inputs: HashMap<(String, String), Vec<u16>> = HashMap::new();
+        let mut devices = HashSet::new();
+        for dev_inp in device_inputs {
+            let (device, button, index) = dev_inp.parts();
+            let generic = device.split('@').next().unwrap_or(device);
+            for name in if generic == device { vec![device] } else { vec![device, generic] } {
+                inputs.entry((name.to_owned(), button.to_owned())).or_insert_with(Vec::new).push(index);
+                devices.insert(name.to_owned());
             }
-            &Action::Axis(ref l, ref r) => {
-                let lp = old_input < -0.5;
-                let rp = old_input > 0.5;
-                if lp && input >= -0.5 {
-                    xdo.send_keysequence_up(l,0).unwrap();
-                } else if !lp && input < -0.5 {
-                    xdo.send_keysequence_down(l,0).unwrap();
-                }
-                if rp && input <= 0.5 {
-                    xdo.send_keysequence_up(r,0).unwrap();
-                } else if !rp && input > 0.5 {
-                    xdo.send_keysequence_down(r,0).unwrap();
-                }
+        }
+        InputsIndex {
+            inputs,
+            devices,
+        }
+    }
+}
+
+/// Actions of a mode being loaded, with the lines where the inputs were bound.
+struct ModeOutput {
+    output: Vec<Action>,
+    bound: HashMap<u16, Option<usize>>,
+}
+
+impl ModeOutput {
+    /// Binds the action to the input of the device, unknown inputs are errors and inputs bound twice warnings.
+    fn bind(&mut self, index: &InputsIndex, owner: &str, device: &str, input: &str, action: Action, line: Option<usize>, report: &mut Report) {
+        let uids = match index.inputs.get(&(device.to_owned(), input.to_owned())) {
+            Some(value) => value,
+            None => {
+                report.error(line, format!("{}: unknown input {}", owner, input));
+                return;
+            }
+        };
+        let mut overlap = None;
+        for &uid in uids {
+            if let Some(previous) = self.bound.insert(uid, line) {
+                overlap = overlap.or(Some((previous, self.output[uid as usize].to_string())));
             }
+            self.output[uid as usize] = action.clone();
+        }
+        if let Some((previous, old_action)) = overlap {
+            let at = previous.map(|line| format!(" at line {}", line)).unwrap_or_default();
+            report.warning(line, format!("{}: {} overrides {} bound to {}{}", owner, action, old_action, input, at));
         }
     }
 }
 
+impl Profile {
+    /// Finds mode by its number counted from 1, or by the first letters of its name, case insensitive.
+    pub fn find_mode(&self, name: &str) -> Option<usize> {
+        if let Ok(number) = name.parse::<usize>() {
+            return if number >= 1 && number <= self.modes.len() { Some(number - 1) } else { None };
+        }
+        let name = name.to_lowercase();
+        self.modes.iter().position(|mode| mode.name.to_lowercase().starts_with(&name))
+    }
+}
 
 impl Profiles {
-    pub fn new(file_path: &str, device_inputs: Vec<DeviceInputUid>) -> io::Result<Profiles> {
-        let mut file = File::open(file_path)?;
-        let mut profiles_def: Vec<ProfileDef> = match serde_yaml::from_reader(&mut file) {
-            Ok(value) => value,
-            Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err)),
-        };
+    /// Finds profile by the first letters of its name, case insensitive.
+    pub fn find(&self, name: &str) -> Option<usize> {
+        let name = name.to_lowercase();
+        self.profiles.iter().position(|profile| profile.name.to_lowercase().starts_with(&name))
+    }
+
+    /// Index of the profile selected by the first letters of its name, or the first profile.
+    pub fn select(&self, name: Option<&str>) -> usize {
+        name.and_then(|name| self.find(name)).unwrap_or(0)
+    }
+
+    /// Reads profiles from the file, or from the yaml files in the directory, following their includes. Problems are
+    /// logged, profiles of the files with errors are left out, fails when no profile is left.
+    pub fn new(source: &Source, device_inputs: Vec<DeviceInputUid>) -> Result<Profiles> {
+        let (profiles, reports) = Profiles::read(source, &device_inputs)?;
+        let mut errors = Vec::new();
+        let mut rejected = Vec::new();
+        for &(ref file_path, ref report) in &reports {
+            for warning in &report.warnings {
+                warn!("{}: {}", file_path, warning);
+            }
+            errors.extend(report.errors.iter().map(|error| format!("{}: {}", file_path, error)));
+            if !report.errors.is_empty() {
+                rejected.push(file_path);
+            }
+        }
+        if profiles.profiles.is_empty() {
+            return Err(Error::invalid(errors.join("\n")));
+        }
+        for error in &errors {
+            error!("{}", error);
+        }
+        for file_path in rejected {
+            warn!("Profiles of {} are left out because of its errors", file_path);
+        }
+        Ok(profiles)
+    }
+
+    /// Reads profiles like new, returning the problems found in each of the files. Fails only when the path
+    /// can't be read, or when the file at the path isn't valid yaml. Bundled profiles have no includes.
+    pub fn read(source: &Source, device_inputs: &[DeviceInputUid]) -> Result<(Profiles, Vec<(String, Report)>)> {
+        let mut reader = Reader::default();
+        match source {
+            &Source::File(ref path) => reader.add_path(path)?,
+            &Source::Bundled(_, text) => {
+                let file_path = source.to_string();
+                reader.add_text(&file_path, text, Format::Yaml, None).map_err(|err| Error::ConfigParse(file_path, err))?
+            }
+        }
+        if reader.files.is_empty() {
+            return Err(Error::invalid(format!("There are no profiles files in {}", source)));
+        }
+        let (profiles, files) = reader.load(device_inputs);
+        let profiles = profiles.into_iter()
+            .filter(|&(file, _)| files[file].report.errors.is_empty())
+            .map(|(_, profile)| profile)
+            .collect();
+        Ok((Profiles { profiles }, files.into_iter().map(|file| (file.path, file.report)).collect()))
+    }
+
+    /// Loads profiles from text in the format without includes, every error and warning is collected in the report.
+    /// The profiles are returned only when there is no error, text which can't be parsed fails at once.
+    #[cfg(test)]
+    pub fn load(text: &str, format: Format, device_inputs: &[DeviceInputUid]) -> result::Result<(Option<Profiles>, Report), FormatError> {
+        let mut reader = Reader::default();
+        reader.add_text("", text, format, None)?;
+        let (profiles, mut files) = reader.load(device_inputs);
+        let report = files.remove(0).report;
+        if report.errors.is_empty() {
+            Ok((Some(Profiles { profiles: profiles.into_iter().map(|(_, profile)| profile).collect() }), report))
+        } else {
+            Ok((None, report))
+        }
+    }
+}
+
+/// Yaml file of profiles, with the problems found in it.
+struct ProfilesFile {
+    path: String,
+    lines: YamlLines,
+    report: Report,
+}
+
+/// Reads profiles from the files, following their includes.
+#[derive(Default)]
+struct Reader {
+    files: Vec<ProfilesFile>,
+    /// Profiles with the indices of their files and of their entries in the files.
+    entries: Vec<(usize, usize, ProfileDef)>,
+    /// Files already read, files included again are skipped.
+    read: HashSet<PathBuf>,
+}
+
+impl Reader {
+    /// Adds the profiles of the file, or of the yaml, toml and json files in the directory sorted by their names.
+    /// Files of the directory which can't be read are reported without stopping the others.
+    fn add_path(&mut self, path: &Path) -> Result<()> {
+        if !path.is_dir() {
+            return self.add_file(path);
+        }
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let entry_path = entry?.path();
+            match entry_path.extension().and_then(|ext| ext.to_str()).and_then(Format::from_extension) {
+                Some(_) if entry_path.is_file() => paths.push(entry_path),
+                _ => (),
+            }
+        }
+        paths.sort();
+        for file_path in paths {
+            if let Err(err) = self.add_file(&file_path) {
+                let message = match err {
+                    Error::ConfigParse(_, err) => err.to_string(),
+                    err => err.to_string(),
+                };
+                let mut report = Report::default();
+                report.error(None, message);
+                self.files.push(ProfilesFile {
+                    path: file_path.display().to_string(),
+                    lines: YamlLines::new(""),
+                    report,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the profiles of the file, unless it was already read.
+    fn add_file(&mut self, path: &Path) -> Result<()> {
+        if !self.read.insert(path.canonicalize()?) {
+            trace!("Profiles file {} was already read", path.display());
+            return Ok(());
+        }
+        let text = fs::read_to_string(path)?;
+        let file_path = path.display().to_string();
+        self.add_text(&file_path, &text, Format::of(path), path.parent()).map_err(|err| Error::ConfigParse(file_path, err))
+    }
 
-        let mut inputs_index = HashMap::new();
-        for dev_inp in &device_inputs {
-            match dev_inp {
-                &DeviceInputUid::Digital(ref device, ref button, ref index) => {
-                    inputs_index.insert((device,button), *index);
+    /// Adds the profiles of the text of the file in the format, included paths are relative to the directory.
+    /// Includes can't be followed without the directory. Json is yaml too, so its problems have lines, those
+    /// of toml don't.
+    fn add_text(&mut self, file_path: &str, text: &str, format: Format, dir: Option<&Path>) -> result::Result<(), FormatError> {
+        let entries = parse_entries(text, format)?;
+        let file = self.files.len();
+        self.files.push(ProfilesFile {
+            path: file_path.to_owned(),
+            lines: YamlLines::new(if format == Format::Toml { "" } else { text }),
+            report: Report::default(),
+        });
+        let include_key = Value::String("include".to_owned());
+        for (index, entry) in entries.into_iter().enumerate() {
+            let line = self.files[file].lines.line(&index.to_string());
+            let include = entry.as_mapping().and_then(|mapping| mapping.get(&include_key)).map(|include| include.as_str().map(str::to_owned));
+            match (include, dir) {
+                (Some(Some(include)), Some(dir)) => {
+                    if let Err(err) = self.add_path(&dir.join(&include)) {
+                        self.files[file].report.error(line, format!("include {}: {}", include, err));
+                    }
+                }
+                (Some(Some(include)), None) => {
+                    self.files[file].report.error(line, format!("include {}: includes need a profiles file", include));
                 }
-                &DeviceInputUid::Analog(ref device, ref button, ref index) => {
-                    inputs_index.insert((device,button), *index);
+                (Some(None), _) => {
+                    self.files[file].report.error(line, "include needs a path to a file or a directory".to_owned());
                 }
+                (None, _) => match serde_yaml::from_value::<ProfileDef>(entry) {
+                    Ok(profile_def) => self.entries.push((file, index, profile_def)),
+                    Err(err) => self.files[file].report.error(line, format!("invalid profile: {}", err)),
+                },
             }
         }
+        Ok(())
+    }
+
+    /// Loads the profiles, with the indices of their files. Problems are collected in the reports of the files.
+    fn load(self, device_inputs: &[DeviceInputUid]) -> (Vec<(usize, Profile)>, Vec<ProfilesFile>) {
+        let Reader { mut files, entries, .. } = self;
+        let loader = Loader {
+            index: InputsIndex::new(device_inputs),
+            inputs: device_inputs.len(),
+        };
+        if entries.is_empty() {
+            files[0].report.error(None, "There are no profiles".to_owned());
+        }
 
         trace!("dev_inputs: {:?}", device_inputs);
-        let mut profiles = Vec::new();
-        for mut profile_def in profiles_def.drain(..) {
-            let name = profile_def.name;
-            let pattern = profile_def.pattern;
-            let mut modes = Vec::new();
-
-            for mut mode_def in profile_def.modes.drain(..) {
-                let name = mode_def.name;
-                let mut output = vec![Action::NoOp; device_inputs.len()];
-                for mut device_def in mode_def.devices.drain(..) {
-                    let device_name = device_def.device;
-                    for single in device_def.single.drain(..) {
-                        let hash = (&device_name, &single.button);
-                        let index = *inputs_index.get(&hash).unwrap() as usize;
-                        output[index] = Action::Key(single.cmd.to_owned());
-                    }
-                    for mut singles in device_def.singles.drain(..) {
-                        let prefix = singles.button;
-                        let index = singles.index;
-                        for (i,cmd) in singles.cmds.drain(..).enumerate() {
-                            let name = format!("{}{}", prefix, index + (i as u8));
-                            let hash = (&device_name, &name);
-                            let index = *inputs_index.get(&hash).unwrap() as usize;
-                            output[index] = Action::Key(cmd);
+        let mut parents = Vec::new();
+        for (i, &(file, index, ref profile_def)) in entries.iter().enumerate() {
+            let owner = format!("profile {}", profile_def.name);
+            let line = files[file].lines.line(&index.to_string());
+            // the name would select the first of them
+            if let Some(&(other_file, other_index, _)) = entries[..i].iter().find(|entry| entry.2.name == profile_def.name) {
+                let at = files[other_file].lines.line(&other_index.to_string()).map(|line| format!(" at line {}", line)).unwrap_or_default();
+                let other_path = if other_file == file { String::new() } else { format!(" in {}", files[other_file].path) };
+                files[file].report.error(line, format!("{}: another profile has the same name{}{}", owner, other_path, at));
+            }
+            parents.push(profile_def.extends.as_ref().and_then(|parent| {
+                let found = entries.iter().position(|entry| &entry.2.name == parent);
+                if found.is_none() {
+                    let line = files[file].lines.line(&format!("{}/extends", index));
+                    files[file].report.error(line, format!("{}: extends unknown profile {}", owner, parent));
+                }
+                found
+            }));
+        }
+        let (order, cycles) = inheritance_order(&mut parents);
+        for (i, cycle) in cycles {
+            let names: Vec<&str> = cycle.iter().map(|&j| entries[j].2.name.as_str()).collect();
+            let msg = format!("profile {}: inheritance cycle {} -> {}", names[0], names.join(" -> "), names[0]);
+            let (file, index, _) = entries[i];
+            let line = files[file].lines.line(&format!("{}/extends", index));
+            files[file].report.error(line, msg);
+        }
+
+        // parents are loaded before their children
+        let mut loaded: Vec<Option<(usize, Profile)>> = entries.iter().map(|_| None).collect();
+        let mut entries: Vec<Option<(usize, usize, ProfileDef)>> = entries.into_iter().map(Some).collect();
+        for i in order {
+            let (file, index, profile_def) = entries[i].take().unwrap();
+            let profile = {
+                let parent = parents[i].and_then(|parent| loaded[parent].as_ref()).map(|&(_, ref profile)| profile);
+                loader.load_profile(&mut files[file], profile_def, &index.to_string(), parent)
+            };
+            loaded[i] = Some((file, profile));
+        }
+        (loaded.into_iter().flatten().collect(), files)
+    }
+}
+
+/// Breaks the inheritance cycles of the entries, given by the indices of their parents, the entries closing a cycle lose
+/// their parents and are returned with the entries in the cycle. Returns the order in which the entries can be loaded,
+/// parents before their children.
+fn inheritance_order(parents: &mut [Option<usize>]) -> (Vec<usize>, Vec<(usize, Vec<usize>)>) {
+    let mut cycles = Vec::new();
+    for start in 0..parents.len() {
+        let mut chain = vec![start];
+        let mut current = parents[start];
+        while let Some(parent) = current {
+            if parent == start {
+                cycles.push((start, chain));
+                break;
+            }
+            if chain.contains(&parent) {
+                break; // the cycle doesn't contain this entry, it's reported for the entries in it
+            }
+            chain.push(parent);
+            current = parents[parent];
+        }
+    }
+    for &(i, _) in &cycles {
+        parents[i] = None;
+    }
+    let depth = |i: usize| {
+        let mut depth = 0;
+        let mut current = parents[i];
+        while let Some(parent) = current {
+            depth += 1;
+            current = parents[parent];
+        }
+        depth
+    };
+    let mut order: Vec<usize> = (0..parents.len()).collect();
+    order.sort_by_key(|&i| depth(i));
+    (order, cycles)
+}
+
+/// Reports the keys of the command which X doesn't know, unknown keysyms are given by their hex code.
+fn check_keys(owner: &str, input: &str, cmd: &str, line: Option<usize>, report: &mut Report) {
+    if cmd == UNSET {
+        return;
+    }
+    if let Err(msg) = keysyms::check(cmd) {
+        report.error(line, format!("{}: {}: {}", owner, input, msg));
+    }
+}
+
+/// Key action of the command, `unset` clears the inherited binding.
+fn key_action(cmd: String) -> Action {
+    if cmd == UNSET {
+        Action::NoOp
+    } else {
+        Action::Key(cmd)
+    }
+}
+
+/// Where a mode being loaded inherits its bindings from.
+#[derive(Clone, Copy)]
+enum ModeBase {
+    Nothing,
+    /// Another mode of the profile, by its index in the modes of the profile.
+    Own(usize),
+    /// Mode of the parent profile, by its index in the modes of the parent.
+    Inherited(usize),
+}
+
+/// Inputs of the devices the profiles are loaded for.
+struct Loader {
+    index: InputsIndex,
+    /// Number of the inputs of all the devices.
+    inputs: usize,
+}
+
+impl Loader {
+    /// Loads the profile at the path of the yaml of the file, inheriting from the loaded parent.
+    fn load_profile(&self, file: &mut ProfilesFile, profile_def: ProfileDef, profile_path: &str, parent: Option<&Profile>) -> Profile {
+        let profile_line = file.lines.line(profile_path);
+        let name = profile_def.name;
+        let owner = format!("profile {}", name);
+        let (backlight, leds) = parse_lights(&owner, profile_def.backlight, profile_def.leds, profile_line, &mut file.report);
+        // missing parent was already reported, the pattern and modes come from it
+        let orphan = profile_def.extends.is_some() && parent.is_none();
+        let pattern = match profile_def.pattern.or_else(|| parent.map(|parent| parent.pattern.to_owned())) {
+            Some(value) => value,
+            None => {
+                if !orphan {
+                    file.report.error(profile_line, format!("{}: there is no pattern", owner));
+                }
+                String::new()
+            }
+        };
+        let mut modes: Vec<Mode> = parent.map(|parent| parent.modes.clone()).unwrap_or_default();
+        let inherited = modes.len();
+        let modes_def = profile_def.modes;
+        if modes_def.is_empty() && modes.is_empty() && !orphan {
+            file.report.error(profile_line, format!("{}: there are no modes", owner));
+        }
+
+        let mut bases = Vec::new();
+        for (i, mode_def) in modes_def.iter().enumerate() {
+            let mode_path = format!("{}/modes/{}", profile_path, i);
+            let owner = format!("profile {}, mode {}", name, mode_def.name);
+            if modes_def[..i].iter().any(|mode| mode.name == mode_def.name) {
+                file.report.warning(file.lines.line(&mode_path), format!("{}: another mode has the same name", owner));
+            }
+            let base_name = mode_def.extends.as_ref().unwrap_or(&mode_def.name);
+            // a mode extending its own name extends the mode of the parent
+            let own = modes_def.iter().enumerate().position(|(j, mode)| j != i && &mode.name == base_name);
+            let base = match (own, modes[..inherited].iter().position(|mode| &mode.name == base_name)) {
+                (Some(j), _) if mode_def.extends.is_some() => ModeBase::Own(j),
+                (_, Some(j)) => ModeBase::Inherited(j),
+                _ if mode_def.extends.is_some() => {
+                    let line = file.lines.line(&format!("{}/extends", mode_path));
+                    file.report.error(line, format!("{}: extends unknown mode {}", owner, base_name));
+                    ModeBase::Nothing
+                }
+                _ => ModeBase::Nothing,
+            };
+            bases.push(base);
+        }
+        let mut parents: Vec<Option<usize>> = bases.iter().map(|base| match base {
+            &ModeBase::Own(j) => Some(j),
+            _ => None,
+        }).collect();
+        let (order, cycles) = inheritance_order(&mut parents);
+        for (i, cycle) in cycles {
+            let names: Vec<&str> = cycle.iter().map(|&j| modes_def[j].name.as_str()).collect();
+            let msg = format!("profile {}, mode {}: inheritance cycle {} -> {}", name, names[0], names.join(" -> "), names[0]);
+            file.report.error(file.lines.line(&format!("{}/modes/{}/extends", profile_path, i)), msg);
+            bases[i] = ModeBase::Nothing;
+        }
+
+        let mut loaded: Vec<Option<Mode>> = modes_def.iter().map(|_| None).collect();
+        let mut modes_def: Vec<Option<ModeDef>> = modes_def.into_iter().map(Some).collect();
+        for i in order {
+            let mode_def = modes_def[i].take().unwrap();
+            let mode = {
+                let base = match bases[i] {
+                    ModeBase::Nothing => None,
+                    ModeBase::Own(j) => loaded[j].as_ref(),
+                    ModeBase::Inherited(j) => Some(&modes[j]),
+                };
+                self.load_mode(file, &name, mode_def, &format!("{}/modes/{}", profile_path, i), base)
+            };
+            loaded[i] = Some(mode);
+        }
+        // modes named as the modes of the parent replace them, keeping their numbers
+        for mode in loaded.into_iter().flatten() {
+            match modes[..inherited].iter().position(|inherited| inherited.name == mode.name) {
+                Some(j) => modes[j] = mode,
+                None => modes.push(mode),
+            }
+        }
+
+        Profile {
+            name,
+            pattern,
+            backlight: backlight.or_else(|| parent.and_then(|parent| parent.backlight)),
+            leds: leds.or_else(|| parent.and_then(|parent| parent.leds)),
+            modes,
+        }
+    }
+
+    /// Loads the mode at the path of the yaml of the file, starting with the bindings of the base mode.
+    fn load_mode(&self, file: &mut ProfilesFile, profile_name: &str, mode_def: ModeDef, mode_path: &str, base: Option<&Mode>) -> Mode {
+        let mode_line = file.lines.line(mode_path);
+        let owner = format!("profile {}, mode {}", profile_name, mode_def.name);
+        let (backlight, leds) = parse_lights(&owner, mode_def.backlight, mode_def.leds, mode_line, &mut file.report);
+        // inherited bindings are overridden without warnings, only the inputs bound here are tracked
+        let mut output = ModeOutput {
+            output: base.map(|base| base.output.clone()).unwrap_or_else(|| vec![Action::NoOp; self.inputs]),
+            bound: HashMap::new(),
+        };
+        for (device_index, device_def) in mode_def.devices.into_iter().enumerate() {
+            let device_path = format!("{}/devices/{}", mode_path, device_index);
+            let owner = format!("{}, device {}", owner, device_def.device);
+            if !self.index.devices.contains(&device_def.device) {
+                file.report.error(file.lines.line(&device_path), format!("{}: unknown device", owner));
+                continue;
+            }
+            for (i, single) in device_def.single.into_iter().enumerate() {
+                let line = file.lines.line(&format!("{}/single/{}", device_path, i));
+                check_keys(&owner, &single.button, &single.cmd, line, &mut file.report);
+                output.bind(&self.index, &owner, &device_def.device, &single.button, key_action(single.cmd), line, &mut file.report);
+            }
+            for (i, singles) in device_def.singles.into_iter().enumerate() {
+                let line = file.lines.line(&format!("{}/singles/{}", device_path, i));
+                for (offset, cmd) in singles.cmds.into_iter().enumerate() {
+                    let input = format!("{}{}", singles.button, singles.index as usize + offset);
+                    check_keys(&owner, &input, &cmd, line, &mut file.report);
+                    output.bind(&self.index, &owner, &device_def.device, &input, key_action(cmd), line, &mut file.report);
+                }
+            }
+            for (i, axis) in device_def.axes.into_iter().enumerate() {
+                let line = file.lines.line(&format!("{}/axes/{}", device_path, i));
+                let action = match axis.simple.len() {
+                    1 if axis.simple[0] == UNSET => Action::NoOp,
+                    2 => {
+                        for cmd in &axis.simple {
+                            check_keys(&owner, &axis.axis, cmd, line, &mut file.report);
                         }
+                        Action::Axis(axis.simple[0].to_owned(), axis.simple[1].to_owned())
                     }
-                    for mut axis in device_def.axes.drain(..) {
-                        let hash = (&device_name, &axis.axis);
-                        let index = *inputs_index.get(&hash).unwrap() as usize;
-                        let right = axis.simple.pop().unwrap();
-                        let left = axis.simple.pop().unwrap();
-                        output[index] = Action::Axis(left, right);
+                    len => {
+                        let msg = format!("{}: axis {} needs two keys [left, right], it has {}", owner, axis.axis, len);
+                        file.report.error(line, msg);
+                        continue;
                     }
-                }
-                modes.push(Mode {
-                    name,
-                    output,
-                });
+                };
+                output.bind(&self.index, &owner, &device_def.device, &axis.axis, action, line, &mut file.report);
             }
-
-            profiles.push( Profile {
-                name,
-                pattern,
-                modes,
-            });
         }
-        Ok(Profiles{
-            profiles,
-        })
+        Mode {
+            name: mode_def.name,
+            backlight: backlight.or_else(|| base.and_then(|base| base.backlight)),
+            leds: leds.or_else(|| base.and_then(|base| base.leds)),
+            output: output.output,
+        }
     }
 }
 
+#[cfg(test)]
+fn test_inputs() -> Vec<DeviceInputUid> {
+    vec![
+        DeviceInputUid::Digital("G13".to_owned(), "G1".to_owned(), 0),
+        DeviceInputUid::Digital("G13".to_owned(), "G2".to_owned(), 1),
+        DeviceInputUid::Analog("G13".to_owned(), "X".to_owned(), 2),
+    ]
+}
+
+#[test]
+fn test_load_profiles() {
+    let yaml = "- name: Test
+  pattern: '*'
+  modes:
+    - name: Walk
+      devices:
+        - device: G13
+          single:
+            - button: G2
+              cmd: Tab
+          singles:
+            - button: G
+              index: 1
+              cmds: [Escape, m]
+          axes:
+            - axis: X
+              simple: [a, d]
+";
+    let (profiles, report) = Profiles::load(yaml, Format::Yaml, &test_inputs()).unwrap();
+    let output = &profiles.unwrap().profiles[0].modes[0].output;
+    assert_eq!(output.iter().map(|action| action.to_string()).collect::<Vec<_>>(), vec!["key Escape", "key m", "axis a d"]);
+    // the singles override the single bound before them
+    assert!(report.errors.is_empty());
+    assert_eq!(report.warnings, vec![Issue {
+        line: Some(11),
+        message: "profile Test, mode Walk, device G13: key m overrides key Tab bound to G2 at line 8".to_owned(),
+    }]);
+}
+
+#[test]
+fn test_unknown_inputs() {
+    let yaml = "- name: Test
+  pattern: '*'
+  modes:
+    - name: Walk
+      devices:
+        - device: G15
+          single: []
+          singles: []
+          axes: []
+        - device: G13
+          single:
+            - button: G7
+              cmd: Tab
+          singles:
+            - button: G
+              index: 2
+              cmds: [a, b]
+          axes: []
+";
+    let (profiles, report) = Profiles::load(yaml, Format::Yaml, &test_inputs()).unwrap();
+    assert!(profiles.is_none());
+    let errors: Vec<String> = report.errors.iter().map(|error| error.to_string()).collect();
+    assert_eq!(errors, vec![
+        "line 6: profile Test, mode Walk, device G15: unknown device",
+        "line 12: profile Test, mode Walk, device G13: unknown input G7",
+        "line 15: profile Test, mode Walk, device G13: unknown input G3",
+    ]);
+}
+
+#[test]
+fn test_unknown_keys() {
+    let yaml = "- name: Test
+  pattern: '*'
+  modes:
+    - name: Walk
+      devices:
+        - device: G13
+          single:
+            - button: G1
+              cmd: ctrl+Escpe
+          singles:
+            - button: G
+              index: 2
+              cmds: [unset]
+          axes:
+            - axis: X
+              simple: [a, ENTER]
+";
+    let (profiles, report) = Profiles::load(yaml, Format::Yaml, &test_inputs()).unwrap();
+    assert!(profiles.is_none());
+    let errors: Vec<String> = report.errors.iter().map(|error| error.to_string()).collect();
+    assert_eq!(errors, vec![
+        "line 8: profile Test, mode Walk, device G13: G1: unknown key Escpe, did you mean Escape?",
+        "line 15: profile Test, mode Walk, device G13: X: unknown key ENTER, did you mean Return?",
+    ]);
+    // keysyms which are not in the catalogue are bound by their hex code, quoted so it's not a number
+    let yaml = yaml.replace("ctrl+Escpe", "'0x1008ff13'").replace("ENTER", "Return");
+    let (profiles, report) = Profiles::load(&yaml, Format::Yaml, &test_inputs()).unwrap();
+    assert!(report.errors.is_empty());
+    assert_eq!(profiles.unwrap().profiles[0].modes[0].output[0], Action::Key("0x1008ff13".to_owned()));
+    // or by their name, when X knows it
+    let yaml = yaml.replace("'0x1008ff13'", "XF86AudioMicMute");
+    let (profiles, report) = Profiles::load(&yaml, Format::Yaml, &test_inputs()).unwrap();
+    assert!(report.errors.is_empty());
+    assert_eq!(profiles.unwrap().profiles[0].modes[0].output[0], Action::Key("XF86AudioMicMute".to_owned()));
+}
+
+#[test]
+fn test_invalid_entries() {
+    let yaml = "- name: Test
+  pattern: '*'
+  backlight: red
+  modes:
+    - name: Walk
+      leds: [M5]
+      devices:
+        - device: G13
+          single: []
+          singles: []
+          axes:
+            - axis: X
+              simple: [a]
+- name: Empty
+  pattern: '*'
+  modes: []
+";
+    let (profiles, report) = Profiles::load(yaml, Format::Yaml, &test_inputs()).unwrap();
+    assert!(profiles.is_none());
+    let errors: Vec<String> = report.errors.iter().map(|error| error.to_string()).collect();
+    assert_eq!(errors, vec![
+        "line 1: profile Test: invalid backlight red, expected #rrggbb",
+        "line 5: profile Test, mode Walk: invalid leds [\"M5\"], expected some of [\"M1\", \"M2\", \"M3\", \"MR\"]",
+        "line 12: profile Test, mode Walk, device G13: axis X needs two keys [left, right], it has 1",
+        "line 14: profile Empty: there are no modes",
+    ]);
+    // yaml which isn't a list of profiles fails at once, invalid profiles are reported
+    assert!(Profiles::load("name: Test\n", Format::Yaml, &test_inputs()).is_err());
+    let (_, report) = Profiles::load("- pattern: '*'\n", Format::Yaml, &test_inputs()).unwrap();
+    assert_eq!(report.errors[0].to_string(), "line 1: invalid profile: missing field `name`");
+}
+
+#[test]
+fn test_extends() {
+    let yaml = "- name: Game
+  extends: Base
+  modes:
+    - name: Walk
+      devices:
+        - device: G13
+          single:
+            - button: G1
+              cmd: Return
+          axes:
+            - axis: X
+              simple: [unset]
+    - name: Drive
+      extends: Walk
+      leds: [M3]
+      devices:
+        - device: G13
+          single:
+            - button: G2
+              cmd: unset
+- name: Base
+  pattern: '*'
+  backlight: '#ff8000'
+  modes:
+    - name: Menu
+      devices:
+        - device: G13
+          single:
+            - button: G1
+              cmd: Escape
+    - name: Walk
+      devices:
+        - device: G13
+          single:
+            - button: G1
+              cmd: Escape
+            - button: G2
+              cmd: Tab
+          axes:
+            - axis: X
+              simple: [a, d]
+";
+    let (profiles, report) = Profiles::load(yaml, Format::Yaml, &test_inputs()).unwrap();
+    assert!(report.errors.is_empty() && report.warnings.is_empty());
+    let profiles = profiles.unwrap();
+    let game = &profiles.profiles[0];
+    assert_eq!((game.pattern.as_str(), game.backlight), ("*", Color::parse("#ff8000")));
+    // inherited modes keep their numbers, the new ones are added after them
+    let modes: Vec<(&str, Vec<String>)> = game.modes.iter()
+        .map(|mode| (mode.name.as_str(), mode.output.iter().map(|action| action.to_string()).collect()))
+        .collect();
+    assert_eq!(modes, vec![
+        ("Menu", vec!["key Escape".to_owned(), "-".to_owned(), "-".to_owned()]),
+        ("Walk", vec!["key Return".to_owned(), "key Tab".to_owned(), "-".to_owned()]),
+        ("Drive", vec!["key Return".to_owned(), "-".to_owned(), "-".to_owned()]),
+    ]);
+    assert_eq!(game.modes[2].leds, Some(4));
+    // the parent is unchanged
+    assert_eq!(profiles.profiles[1].modes[1].output[0].to_string(), "key Escape");
+}
+
+#[test]
+fn test_extends_errors() {
+    let yaml = "- name: A
+  extends: B
+- name: B
+  extends: A
+- name: C
+  extends: D
+- name: E
+  pattern: '*'
+  modes:
+    - name: M1
+      extends: M2
+    - name: M2
+      extends: M1
+    - name: M3
+      extends: M4
+";
+    let (profiles, report) = Profiles::load(yaml, Format::Yaml, &test_inputs()).unwrap();
+    assert!(profiles.is_none());
+    let errors: Vec<String> = report.errors.iter().map(|error| error.to_string()).collect();
+    assert_eq!(errors, vec![
+        "line 6: profile C: extends unknown profile D",
+        "line 2: profile A: inheritance cycle A -> B -> A",
+        "line 4: profile B: inheritance cycle B -> A -> B",
+        "line 15: profile E, mode M3: extends unknown mode M4",
+        "line 11: profile E, mode M1: inheritance cycle M1 -> M2 -> M1",
+        "line 13: profile E, mode M2: inheritance cycle M2 -> M1 -> M2",
+    ]);
+}
+
+#[test]
+fn test_read_directory() {
+    let (profiles, reports) = Profiles::read(&Source::File(PathBuf::from("testdata/profiles")), &test_inputs()).unwrap();
+    // profiles of the files with errors are left out, the profiles they extend are not
+    let names: Vec<&str> = profiles.profiles.iter().map(|profile| profile.name.as_str()).collect();
+    assert_eq!(names, vec!["Base", "Extra", "Game"]);
+    assert_eq!(profiles.profiles[2].modes[0].output[1].to_string(), "key Tab");
+    let errors: Vec<String> = reports.iter()
+        .flat_map(|&(ref path, ref report)| report.errors.iter().map(move |error| format!("{}: {}", path, error)))
+        .collect();
+    assert_eq!(errors, vec![
+        "testdata/profiles/broken.yaml: line 1: include missing.yaml: No such file or directory (os error 2)",
+        "testdata/profiles/broken.yaml: line 7: profile Broken, mode Walk, device G15: unknown device",
+        "testdata/profiles/duplicate.yaml: line 3: profile Base: another profile has the same name in testdata/profiles/base.yaml at line 1",
+    ]);
+    let paths: Vec<&str> = reports.iter().map(|&(ref path, _)| path.as_str()).collect();
+    assert_eq!(paths, vec![
+        "testdata/profiles/base.yaml",
+        "testdata/profiles/broken.yaml",
+        "testdata/profiles/duplicate.yaml",
+        "testdata/profiles/game.yaml",
+        "testdata/profiles/shared/extra.yaml",
+    ]);
+}
+
+#[test]
+fn test_convert() {
+    use device_mapping::DeviceMaps;
+    let inputs = DeviceMaps::new(&Source::Bundled("devices.yaml", include_str!("../devices.yaml"))).unwrap().get_inputs();
+    let yaml = include_str!("../profiles.yaml");
+    let (expected, _) = Profiles::load(yaml, Format::Yaml, &inputs).unwrap();
+    assert!(expected.is_some());
+    // the same profiles are loaded from every format, and after converting back
+    for &format in &[Format::Toml, Format::Json] {
+        let text = convert(yaml, Format::Yaml, format).unwrap();
+        assert_eq!(Profiles::load(&text, format, &inputs).unwrap().0, expected);
+        let back = convert(&text, format, Format::Yaml).unwrap();
+        assert_eq!(Profiles::load(&back, Format::Yaml, &inputs).unwrap().0, expected);
+    }
+    let toml = convert("- include: base.yaml\n- name: Game\n  extends: Base\n", Format::Yaml, Format::Toml).unwrap();
+    assert_eq!(toml, "[[profiles]]\ninclude = \"base.yaml\"\n[[profiles]]\nextends = \"Base\"\nname = \"Game\"\n");
+}
+
+#[test]
+fn test_schema() {
+    use schema::validate;
+
+    let schema = schema().unwrap();
+    // the bundled profiles, with the sections left empty, are valid
+    let text = fs::read_to_string("profiles.yaml").unwrap();
+    let value: serde_yaml::Value = serde_yaml::from_str(&text).unwrap();
+    let value = serde_json::to_value(&value).unwrap();
+    assert_eq!(validate(&schema, &schema, &value, ""), Ok(()));
+    let invalid = json!([{"name": "Test", "modes": [{"name": "Walk", "devices": [{"device": "G13", "single": [{"button": "G1"}]}]}]}]);
+    assert!(validate(&schema, &schema, &invalid, "").is_err());
+}
@@ -0,0 +1,478 @@
+use std::cell::RefCell;
+use std::error;
+use std::fmt;
+
+use serde::de::{self, Deserialize, DeserializeSeed, Deserializer, MapVisitor, SeqVisitor, Visitor};
+use serde::de::value::ValueDeserializer;
+use serde_json::{Map, Value};
+
+/// Json schema draft the schemas are written for.
+const SCHEMA_DRAFT: &str = "http://json-schema.org/draft-07/schema#";
+
+/// Error of tracing a schema, a field missing from a struct is kept to find the required fields.
+#[derive(Debug)]
+struct TraceError {
+    msg: String,
+    missing: Option<&'static str>,
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl error::Error for TraceError {
+    fn description(&self) -> &str {
+        &self.msg
+    }
+}
+
+impl de::Error for TraceError {
+    fn custom<T: fmt::Display>(msg: T) -> TraceError {
+        TraceError { msg: msg.to_string(), missing: None }
+    }
+
+    fn missing_field(field: &'static str) -> TraceError {
+        TraceError { msg: format!("missing field `{}`", field), missing: Some(field) }
+    }
+}
+
+/// Struct whose fields are traced, with its field names.
+type TracedStruct = (&'static str, &'static [&'static str]);
+
+/// Deserializer which writes the schema of the value it is asked for, and gives the visitor the simplest value
+/// of that kind, e.g. one item of a sequence. Structs are written to the definitions and referred to.
+struct Tracer<'a> {
+    out: &'a mut Value,
+    definitions: &'a RefCell<Map<String, Value>>,
+    /// Structs met while tracing.
+    structs: &'a RefCell<Vec<TracedStruct>>,
+    /// Field of a struct left out, to find out whether the struct requires it.
+    omit: Option<(&'static str, &'static str)>,
+}
+
+impl<'a> Tracer<'a> {
+    /// Tracer of a value nested in the traced one.
+    fn nested<'b>(&'b self, out: &'b mut Value) -> Tracer<'b> {
+        Tracer {
+            out,
+            definitions: self.definitions,
+            structs: self.structs,
+            omit: self.omit,
+        }
+    }
+
+    fn integer(self, min: i64, max: u64) {
+        *self.out = json!({"type": "integer", "minimum": min, "maximum": max});
+    }
+
+    fn typed(self, name: &str) {
+        *self.out = json!({ "type": name });
+    }
+}
+
+/// Gives the visitor the items traced into the schemas, one item for sequences and one for each tuple element.
+struct Items<'a, 'b: 'a> {
+    tracer: &'a Tracer<'b>,
+    schemas: Vec<Value>,
+    next: usize,
+}
+
+impl<'a, 'b> SeqVisitor for Items<'a, 'b> {
+    type Error = TraceError;
+
+    fn visit_seed<T: DeserializeSeed>(&mut self, seed: T) -> Result<Option<T::Value>, TraceError> {
+        if self.next == self.schemas.len() {
+            return Ok(None);
+        }
+        let index = self.next;
+        self.next += 1;
+        seed.deserialize(self.tracer.nested(&mut self.schemas[index])).map(Some)
+    }
+}
+
+/// Gives the visitor the fields of a struct, without the omitted one, tracing their values into the properties.
+struct Fields<'a, 'b: 'a> {
+    tracer: &'a Tracer<'b>,
+    fields: Vec<&'static str>,
+    properties: Map<String, Value>,
+    next: usize,
+}
+
+impl<'a, 'b> MapVisitor for Fields<'a, 'b> {
+    type Error = TraceError;
+
+    fn visit_key_seed<K: DeserializeSeed>(&mut self, seed: K) -> Result<Option<K::Value>, TraceError> {
+        match self.fields.get(self.next) {
+            Some(field) => seed.deserialize(ValueDeserializer::<TraceError>::into_deserializer(*field)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn visit_value_seed<V: DeserializeSeed>(&mut self, seed: V) -> Result<V::Value, TraceError> {
+        let field = self.fields[self.next];
+        self.next += 1;
+        let mut schema = Value::Null;
+        let value = seed.deserialize(self.tracer.nested(&mut schema))?;
+        self.properties.insert(field.to_owned(), schema);
+        Ok(value)
+    }
+}
+
+impl<'a> Deserializer for Tracer<'a> {
+    type Error = TraceError;
+
+    /// Any value, e.g. an ignored field.
+    fn deserialize<V: Visitor>(self, visitor: V) -> Result<V::Value, TraceError> {
+        *self.out = json!({});
+        visitor.visit_unit()
+    }
+
+    fn deserialize_bool<V: Visitor>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.typed("boolean");
+        visitor.visit_bool(false)
+    }
+
+    fn deserialize_u8<V: Visitor>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.integer(0, u8::MAX as u64);
+        visitor.visit_u8(0)
+    }
+
+    fn deserialize_u16<V: Visitor>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.integer(0, u16::MAX as u64);
+        visitor.visit_u16(0)
+    }
+
+    fn deserialize_u32<V: Visitor>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.integer(0, u32::MAX as u64);
+        visitor.visit_u32(0)
+    }
+
+    fn deserialize_u64<V: Visitor>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.integer(0, u64::MAX);
+        visitor.visit_u64(0)
+    }
+
+    fn deserialize_i8<V: Visitor>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.typed("integer");
+        visitor.visit_i8(0)
+    }
+
+    fn deserialize_i16<V: Visitor>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.typed("integer");
+        visitor.visit_i16(0)
+    }
+
+    fn deserialize_i32<V: Visitor>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.typed("integer");
+        visitor.visit_i32(0)
+    }
+
+    fn deserialize_i64<V: Visitor>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.typed("integer");
+        visitor.visit_i64(0)
+    }
+
+    fn deserialize_f32<V: Visitor>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.typed("number");
+        visitor.visit_f32(0.0)
+    }
+
+    fn deserialize_f64<V: Visitor>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.typed("number");
+        visitor.visit_f64(0.0)
+    }
+
+    fn deserialize_char<V: Visitor>(self, visitor: V) -> Result<V::Value, TraceError> {
+        *self.out = json!({"type": "string", "minLength": 1, "maxLength": 1});
+        visitor.visit_char(' ')
+    }
+
+    fn deserialize_str<V: Visitor>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.typed("string");
+        visitor.visit_str("")
+    }
+
+    fn deserialize_string<V: Visitor>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.typed("string");
+        visitor.visit_string(String::new())
+    }
+
+    fn deserialize_bytes<V: Visitor>(self, visitor: V) -> Result<V::Value, TraceError> {
+        *self.out = json!({"type": "array", "items": {"type": "integer", "minimum": 0, "maximum": 255}});
+        visitor.visit_bytes(&[])
+    }
+
+    fn deserialize_byte_buf<V: Visitor>(self, visitor: V) -> Result<V::Value, TraceError> {
+        *self.out = json!({"type": "array", "items": {"type": "integer", "minimum": 0, "maximum": 255}});
+        visitor.visit_byte_buf(Vec::new())
+    }
+
+    /// Optional values can be left out or null, e.g. a key of a section without a value in yaml.
+    fn deserialize_option<V: Visitor>(self, visitor: V) -> Result<V::Value, TraceError> {
+        let mut schema = Value::Null;
+        let value = visitor.visit_some(self.nested(&mut schema))?;
+        *self.out = match schema.get("type").cloned() {
+            Some(Value::String(name)) => {
+                schema["type"] = json!([name, "null"]);
+                schema
+            }
+            // already nullable
+            Some(Value::Array(_)) => schema,
+            _ => json!({"anyOf": [schema, {"type": "null"}]}),
+        };
+        Ok(value)
+    }
+
+    fn deserialize_unit<V: Visitor>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.typed("null");
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor>(self, _name: &'static str, visitor: V) -> Result<V::Value, TraceError> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor>(self, _name: &'static str, visitor: V) -> Result<V::Value, TraceError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    /// Sequences take null as empty, e.g. a key of a section without a value in yaml.
+    fn deserialize_seq<V: Visitor>(self, visitor: V) -> Result<V::Value, TraceError> {
+        let (value, items) = {
+            let mut items = Items { tracer: &self, schemas: vec![Value::Null], next: 0 };
+            let value = visitor.visit_seq(&mut items)?;
+            (value, items.schemas.remove(0))
+        };
+        *self.out = json!({"type": ["array", "null"], "items": items});
+        Ok(value)
+    }
+
+    fn deserialize_seq_fixed_size<V: Visitor>(self, len: usize, visitor: V) -> Result<V::Value, TraceError> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_tuple<V: Visitor>(self, len: usize, visitor: V) -> Result<V::Value, TraceError> {
+        let (value, items) = {
+            let mut items = Items { tracer: &self, schemas: vec![Value::Null; len], next: 0 };
+            let value = visitor.visit_seq(&mut items)?;
+            (value, items.schemas)
+        };
+        *self.out = json!({"type": "array", "items": items, "minItems": len, "maxItems": len});
+        Ok(value)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor>(self, _name: &'static str, len: usize, visitor: V) -> Result<V::Value, TraceError> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    /// Maps are traced without entries, their keys and values are any.
+    fn deserialize_map<V: Visitor>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.typed("object");
+        visitor.visit_map(de::value::MapDeserializer::new(Vec::<(String, String)>::new().into_iter()))
+    }
+
+    fn deserialize_struct<V: Visitor>(self, name: &'static str, fields: &'static [&'static str], visitor: V) -> Result<V::Value, TraceError> {
+        if !self.structs.borrow().iter().any(|traced| traced.0 == name) {
+            self.structs.borrow_mut().push((name, fields));
+        }
+        let (value, properties) = {
+            let omit = self.omit;
+            let fields = fields.iter().cloned().filter(|field| omit != Some((name, *field))).collect();
+            let mut visited = Fields { tracer: &self, fields, properties: Map::new(), next: 0 };
+            let value = visitor.visit_map(&mut visited)?;
+            (value, visited.properties)
+        };
+        let mut definitions = self.definitions.borrow_mut();
+        // unknown fields are ignored when the files are loaded, so they are allowed
+        let definition = definitions.entry(name).or_insert_with(|| json!({"type": "object"}));
+        definition.as_object_mut().unwrap().insert("properties".to_owned(), Value::Object(properties));
+        *self.out = json!({ "$ref": format!("#/definitions/{}", name) });
+        Ok(value)
+    }
+
+    fn deserialize_struct_field<V: Visitor>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor>(self, name: &'static str, _variants: &'static [&'static str], _visitor: V) -> Result<V::Value, TraceError> {
+        Err(de::Error::custom(format!("enum {} can't be traced", name)))
+    }
+
+    fn deserialize_ignored_any<V: Visitor>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.deserialize(visitor)
+    }
+}
+
+/// Traces the json schema of the type from its Deserialize implementation. The structs are added to the definitions
+/// with the fields they require, which are found by leaving out each field in turn. Values can't be checked
+/// beyond their types, they are checked when the files are loaded. Fails on the types which can't be traced,
+/// enums.
+pub fn trace<T: Deserialize>(definitions: &mut Map<String, Value>) -> Result<Value, String> {
+    let shared = RefCell::new(Map::new());
+    let structs = RefCell::new(Vec::new());
+    let mut schema = Value::Null;
+    let traced = T::deserialize(Tracer { out: &mut schema, definitions: &shared, structs: &structs, omit: None });
+    if let Err(err) = traced {
+        return Err(err.msg);
+    }
+    let mut shared = shared.into_inner();
+    for &(name, fields) in structs.borrow().iter() {
+        let mut required = Vec::new();
+        for field in fields {
+            let mut scratch = Value::Null;
+            let scratch_definitions = RefCell::new(Map::new());
+            let scratch_structs = RefCell::new(Vec::new());
+            let tracer = Tracer {
+                out: &mut scratch,
+                definitions: &scratch_definitions,
+                structs: &scratch_structs,
+                omit: Some((name, field)),
+            };
+            match T::deserialize(tracer) {
+                Err(TraceError { missing: Some(missing), .. }) if missing == *field => required.push(json!(field)),
+                _ => (),
+            }
+        }
+        if !required.is_empty() {
+            shared[name].as_object_mut().unwrap().insert("required".to_owned(), Value::Array(required));
+        }
+    }
+    definitions.extend(shared);
+    Ok(schema)
+}
+
+/// Json schema document of the root schema and the definitions it refers to.
+pub fn document(title: &str, root: Value, definitions: Map<String, Value>) -> Value {
+    let mut document = json!({"$schema": SCHEMA_DRAFT, "title": title});
+    {
+        let object = document.as_object_mut().unwrap();
+        if let Value::Object(root) = root {
+            object.extend(root);
+        }
+        object.insert("definitions".to_owned(), Value::Object(definitions));
+    }
+    document
+}
+
+/// Validates the value against the schema, with the keywords the traced schemas use. The names of properties
+/// are not checked.
+#[cfg(test)]
+pub fn validate(document: &Value, schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+    if let Some(reference) = schema.get("$ref").and_then(|reference| reference.as_str()) {
+        let name = reference.trim_start_matches("#/definitions/");
+        return validate(document, &document["definitions"][name], value, path);
+    }
+    if let Some(schemas) = schema.get("anyOf").and_then(|schemas| schemas.as_array()) {
+        if !schemas.iter().any(|schema| validate(document, schema, value, path).is_ok()) {
+            return Err(format!("{}: matches none of the schemas", path));
+        }
+    }
+    let type_name = match value {
+        &Value::Null => "null",
+        &Value::Bool(_) => "boolean",
+        &Value::Number(ref number) if number.is_f64() => "number",
+        &Value::Number(_) => "integer",
+        &Value::String(_) => "string",
+        &Value::Array(_) => "array",
+        &Value::Object(_) => "object",
+    };
+    let types = match schema.get("type") {
+        Some(&Value::String(ref name)) => vec![name.as_str()],
+        Some(&Value::Array(ref names)) => names.iter().filter_map(|name| name.as_str()).collect(),
+        _ => Vec::new(),
+    };
+    let number = type_name == "integer" && types.contains(&"number");
+    if !types.is_empty() && !types.contains(&type_name) && !number {
+        return Err(format!("{}: {} is not {:?}", path, type_name, types));
+    }
+    if let (Some(minimum), Some(value)) = (schema.get("minimum").and_then(|minimum| minimum.as_f64()), value.as_f64()) {
+        if value < minimum {
+            return Err(format!("{}: {} is below {}", path, value, minimum));
+        }
+    }
+    if let (Some(maximum), Some(value)) = (schema.get("maximum").and_then(|maximum| maximum.as_f64()), value.as_f64()) {
+        if value > maximum {
+            return Err(format!("{}: {} is above {}", path, value, maximum));
+        }
+    }
+    match value {
+        &Value::Array(ref items) => {
+            for (index, item) in items.iter().enumerate() {
+                let item_schema = match schema.get("items") {
+                    Some(&Value::Array(ref schemas)) => schemas.get(index).cloned().unwrap_or(json!({})),
+                    Some(item_schema) => item_schema.clone(),
+                    None => json!({}),
+                };
+                validate(document, &item_schema, item, &format!("{}/{}", path, index))?;
+            }
+            let len = items.len() as u64;
+            if schema.get("minItems").and_then(|min| min.as_u64()).map(|min| len < min).unwrap_or(false)
+                || schema.get("maxItems").and_then(|max| max.as_u64()).map(|max| len > max).unwrap_or(false) {
+                return Err(format!("{}: wrong number of items {}", path, len));
+            }
+        }
+        &Value::Object(ref object) => {
+            for required in schema.get("required").and_then(|required| required.as_array()).into_iter().flat_map(|required| required.iter()) {
+                let required = required.as_str().unwrap_or_default();
+                if !object.contains_key(required) {
+                    return Err(format!("{}: missing {}", path, required));
+                }
+            }
+            for (key, item) in object {
+                let item_path = format!("{}/{}", path, key);
+                match (schema.get("properties").and_then(|properties| properties.get(key)), schema.get("additionalProperties")) {
+                    (Some(property), _) => validate(document, property, item, &item_path)?,
+                    (None, Some(&Value::Bool(false))) => return Err(format!("{}: unknown property", item_path)),
+                    (None, Some(additional)) => validate(document, additional, item, &item_path)?,
+                    (None, None) => (),
+                }
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+#[test]
+fn test_trace() {
+    use device_mapping::DeviceMapDefinition;
+    let mut definitions = Map::new();
+    let schema = trace::<Vec<DeviceMapDefinition>>(&mut definitions).unwrap();
+    assert_eq!(schema, json!({"type": ["array", "null"], "items": {"$ref": "#/definitions/DeviceMapDefinition"}}));
+    // optional and defaulted fields are not required
+    assert_eq!(definitions["DeviceMapDefinition"]["required"], json!(["name"]));
+    assert_eq!(definitions["DeviceMapDefinition"]["properties"]["packet_size"], json!({"type": "integer", "minimum": 0, "maximum": 65535}));
+    assert_eq!(definitions["DeviceMapDefinition"]["properties"]["instances"], json!({
+        "type": ["array", "null"],
+        "items": {"$ref": "#/definitions/DeviceInstanceDefinition"},
+    }));
+    assert_eq!(definitions["DeviceAnalogDefinition"], json!({
+        "type": "object",
+        "properties": {
+            "name": {"type": "string"},
+            "index": {"type": "integer", "minimum": 0, "maximum": 255},
+            "output": {
+                "type": "array",
+                "items": [{"type": "number"}, {"type": "number"}],
+                "minItems": 2,
+                "maxItems": 2,
+            },
+        },
+        "required": ["name", "index", "output"],
+    }));
+}
+
+#[cfg(test)]
+#[derive(Deserialize)]
+enum Side {
+    Left,
+    Right,
+}
+
+#[test]
+fn test_trace_enum() {
+    // fails instead of panicking
+    assert_eq!(trace::<Vec<Side>>(&mut Map::new()), Err("enum Side can't be traced".to_owned()));
+}
@@ -0,0 +1,65 @@
+use std::io::{Result, Error};
+use std::mem;
+use std::process;
+use std::ptr;
+use std::thread;
+
+use libc;
+
+/// Set of the termination signals, SIGINT and SIGTERM.
+fn termination_set() -> libc::sigset_t {
+    let mut set: libc::sigset_t = unsafe { mem::zeroed() };
+    unsafe {
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+    }
+    set
+}
+
+fn set_mask(how: libc::c_int, set: &libc::sigset_t) -> Result<()> {
+    let res = unsafe { libc::pthread_sigmask(how, set, ptr::null_mut()) };
+    if res != 0 {
+        return Err(Error::from_raw_os_error(res));
+    }
+    Ok(())
+}
+
+/// Blocks SIGINT and SIGTERM in the calling thread and in the threads it starts afterwards. Called first in main,
+/// before any thread starts, otherwise a thread with the signals unblocked may receive them and the process
+/// is killed without cleanup.
+pub fn block() -> Result<()> {
+    set_mask(libc::SIG_BLOCK, &termination_set())
+}
+
+/// Unblocks SIGINT and SIGTERM in the calling thread, for the commands which are simply stopped by them.
+pub fn unblock() -> Result<()> {
+    set_mask(libc::SIG_UNBLOCK, &termination_set())
+}
+
+/// Receives the signals blocked by `block` in a new thread, which calls the handler on the first of them.
+/// The second signal exits the process at once, in case the shutdown hangs.
+pub fn on_termination<F: FnOnce(i32) + Send + 'static>(handler: F) -> Result<()> {
+    let set = termination_set();
+    // the calling thread doesn't need to be the first one blocking them
+    set_mask(libc::SIG_BLOCK, &set)?;
+    thread::spawn(move || {
+        let mut handler = Some(handler);
+        loop {
+            let mut signal: libc::c_int = 0;
+            let res = unsafe { libc::sigwait(&set, &mut signal) };
+            if res != 0 {
+                error!("Failed to wait for signals: {}", Error::from_raw_os_error(res));
+                break;
+            }
+            match handler.take() {
+                Some(handler) => handler(signal),
+                None => {
+                    warn!("Received signal {} again, exiting without cleanup", signal);
+                    process::exit(128 + signal);
+                }
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,74 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use device_mapping::DeviceIdentity;
+
+/// Reads trimmed value of a sysfs attribute file.
+fn read_attribute(path: &Path) -> Option<String> {
+    fs::read_to_string(path).ok().map(|value| value.trim().to_owned())
+}
+
+/// Reads bus number and device address of a usb device directory.
+fn usb_address(dir: &Path) -> Option<(u8, u8)> {
+    let busnum = read_attribute(&dir.join("busnum"))?.parse().ok()?;
+    let devnum = read_attribute(&dir.join("devnum"))?.parse().ok()?;
+    Some((busnum, devnum))
+}
+
+/// Walks up from a device (e.g. hid or input device) to the usb device it belongs to.
+pub fn usb_parent(path: &Path) -> Option<PathBuf> {
+    let mut path = fs::canonicalize(path).ok()?;
+    loop {
+        if usb_address(&path).is_some() {
+            return Some(path);
+        }
+        if !path.pop() {
+            return None;
+        }
+    }
+}
+
+/// Finds sysfs directory of the usb device at the bus and address.
+pub fn usb_device(bus_number: u8, address: u8) -> Option<PathBuf> {
+    for entry in fs::read_dir("/sys/bus/usb/devices").ok()? {
+        let path = match entry {
+            Ok(value) => value.path(),
+            Err(_) => continue,
+        };
+        if usb_address(&path) == Some((bus_number, address)) {
+            return Some(path);
+        }
+    }
+    None
+}
+
+/// Serial number and port path of a usb device directory, the port path is the name of the directory.
+fn usb_identity(dir: &Path) -> DeviceIdentity {
+    DeviceIdentity {
+        serial: read_attribute(&dir.join("serial")).filter(|serial| !serial.is_empty()),
+        port: dir.file_name().map(|name| name.to_string_lossy().into_owned()),
+    }
+}
+
+/// Identity of the usb device at the bus and address.
+pub fn identity(bus_number: u8, address: u8) -> DeviceIdentity {
+    usb_device(bus_number, address).map(|dir| usb_identity(&dir)).unwrap_or_default()
+}
+
+/// Identity of an input device node, taken from its usb device or from the input device itself.
+pub fn input_identity(event_path: &Path) -> DeviceIdentity {
+    let name = match event_path.file_name() {
+        Some(value) => value,
+        None => return DeviceIdentity::default(),
+    };
+    let input = Path::new("/sys/class/input").join(name).join("device");
+    match usb_parent(&input) {
+        Some(dir) => usb_identity(&dir),
+        None => {
+            DeviceIdentity {
+                serial: read_attribute(&input.join("uniq")).filter(|serial| !serial.is_empty()),
+                port: None,
+            }
+        }
+    }
+}
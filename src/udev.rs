@@ -0,0 +1,77 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+use device_mapping::{DeviceMaps, SourceKind};
+
+/// Rules file installed by default, numbered before 73-seat-late.rules which applies the uaccess tag.
+pub const RULES_PATH: &str = "/etc/udev/rules.d/70-game-kb-driver.rules";
+
+/// Generates udev rules giving access to the mapped devices, to the users of the group, or to the user logged in
+/// at the seat without a group. The usb device is always allowed, it is used to read the device and to write its
+/// display and lights, the device node of the hidraw or evdev source is allowed too.
+pub fn rules(mappings: &DeviceMaps, group: Option<&str>) -> String {
+    let access = match group {
+        Some(group) => format!("GROUP=\"{}\", MODE=\"0660\"", group),
+        None => "TAG+=\"uaccess\"".to_owned(),
+    };
+    let mut keys: Vec<&u32> = mappings.devices.keys().collect();
+    keys.sort();
+    let mut res = format!("# Generated by {} install-udev-rules\n", env!("CARGO_PKG_NAME"));
+    for key in keys {
+        let maps = &mappings.devices[key];
+        let ids = format!("ATTRS{{idVendor}}==\"{:04x}\", ATTRS{{idProduct}}==\"{:04x}\"", key >> 16, key & 0xffff);
+        res = format!("{}\n# {}\n", res, maps[0].name);
+        res = format!("{}SUBSYSTEM==\"usb\", ENV{{DEVTYPE}}==\"usb_device\", {}, {}\n", res, ids, access);
+        if maps.iter().any(|map| map.source == SourceKind::Hidraw) {
+            res = format!("{}SUBSYSTEM==\"hidraw\", {}, {}\n", res, ids, access);
+        }
+        if maps.iter().any(|map| map.source == SourceKind::Evdev) {
+            res = format!("{}SUBSYSTEM==\"input\", KERNEL==\"event*\", {}, {}\n", res, ids, access);
+        }
+    }
+    res
+}
+
+/// Writes the rules into the file and asks udev to apply them to the connected devices. Failure of udevadm is
+/// only logged, the rules are applied when the devices are reconnected.
+pub fn install(path: &Path, rules: &str) -> io::Result<()> {
+    fs::write(path, rules)?;
+    info!("Udev rules written to {}", path.display());
+    for args in &[&["control", "--reload-rules"][..], &["trigger", "--subsystem-match=usb", "--subsystem-match=hidraw", "--subsystem-match=input"][..]] {
+        match Command::new("udevadm").args(*args).status() {
+            Ok(ref status) if status.success() => (),
+            Ok(status) => warn!("udevadm {} failed with {}, reconnect the devices to apply the rules", args[0], status),
+            Err(err) => warn!("Failed to run udevadm {}, reconnect the devices to apply the rules: {}", args[0], err),
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_rules() {
+    use std::collections::HashMap;
+    use device_mapping::DeviceMapDefinition;
+    let yaml = "
+0x046dc21c:
+  name: G13
+  packet_size: 8
+0x046dc24a:
+  name: G600
+  source: hidraw
+  packet_size: 8
+";
+    let def: HashMap<u32, DeviceMapDefinition> = ::serde_yaml::from_str(yaml).unwrap();
+    let mappings = DeviceMaps::from_definition(def).unwrap();
+    assert_eq!(rules(&mappings, Some("games")), "# Generated by game-kb-driver install-udev-rules
+
+# G13
+SUBSYSTEM==\"usb\", ENV{DEVTYPE}==\"usb_device\", ATTRS{idVendor}==\"046d\", ATTRS{idProduct}==\"c21c\", GROUP=\"games\", MODE=\"0660\"
+
+# G600
+SUBSYSTEM==\"usb\", ENV{DEVTYPE}==\"usb_device\", ATTRS{idVendor}==\"046d\", ATTRS{idProduct}==\"c24a\", GROUP=\"games\", MODE=\"0660\"
+SUBSYSTEM==\"hidraw\", ATTRS{idVendor}==\"046d\", ATTRS{idProduct}==\"c24a\", GROUP=\"games\", MODE=\"0660\"
+");
+    assert!(rules(&mappings, None).contains("ATTRS{idProduct}==\"c21c\", TAG+=\"uaccess\"\n"));
+}
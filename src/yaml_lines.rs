@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+
+use yaml_rust::Event;
+use yaml_rust::parser::{MarkedEventReceiver, Parser};
+use yaml_rust::scanner::Marker;
+
+/// Lines of the nodes in a yaml document, addressed by their paths, e.g. `0/modes/1/devices/0`
+/// for the first device of the second mode of the first profile.
+pub struct YamlLines {
+    lines: HashMap<String, usize>,
+}
+
+/// Collection being read, with the state needed to build the paths of its children.
+enum Collection {
+    /// Mapping and the key of the value which comes next, none when a key comes next.
+    Mapping(Option<String>),
+    /// Sequence and the index of the next item.
+    Sequence(usize),
+}
+
+/// Builds the paths of the nodes from the parser events.
+struct LinesReceiver {
+    /// Open collections and their paths.
+    stack: Vec<(String, Collection)>,
+    lines: HashMap<String, usize>,
+}
+
+impl LinesReceiver {
+    /// Path of the node which starts now, or none when a key of a mapping starts.
+    fn next_path(&self) -> Option<String> {
+        let (parent, child) = match self.stack.last() {
+            Some(&(ref parent, Collection::Mapping(Some(ref key)))) => (parent, key.to_owned()),
+            Some(&(_, Collection::Mapping(None))) => return None,
+            Some(&(ref parent, Collection::Sequence(index))) => (parent, index.to_string()),
+            None => return Some(String::new()),
+        };
+        if parent.is_empty() {
+            Some(child)
+        } else {
+            Some(format!("{}/{}", parent, child))
+        }
+    }
+
+    /// Moves to the next child of the open collection.
+    fn advance(&mut self) {
+        match self.stack.last_mut() {
+            Some(&mut (_, Collection::Mapping(ref mut key))) => *key = None,
+            Some(&mut (_, Collection::Sequence(ref mut index))) => *index += 1,
+            None => (),
+        }
+    }
+}
+
+impl MarkedEventReceiver for LinesReceiver {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        match event {
+            Event::Scalar(value, ..) => match self.next_path() {
+                Some(path) => {
+                    self.lines.insert(path, mark.line());
+                    self.advance();
+                }
+                None => {
+                    if let Some(&mut (_, Collection::Mapping(ref mut key))) = self.stack.last_mut() {
+                        *key = Some(value);
+                    }
+                }
+            },
+            Event::Alias(_) => {
+                if let Some(path) = self.next_path() {
+                    self.lines.insert(path, mark.line());
+                }
+                self.advance();
+            }
+            Event::MappingStart(_) | Event::SequenceStart(_) => {
+                let path = self.next_path().unwrap_or_default();
+                self.lines.insert(path.to_owned(), mark.line());
+                self.advance();
+                let collection = match event {
+                    Event::MappingStart(_) => Collection::Mapping(None),
+                    _ => Collection::Sequence(0),
+                };
+                self.stack.push((path, collection));
+            }
+            Event::MappingEnd | Event::SequenceEnd => {
+                self.stack.pop();
+            }
+            _ => (),
+        }
+    }
+}
+
+impl YamlLines {
+    /// Finds lines of all the nodes of the first document, invalid yaml gives no lines.
+    pub fn new(text: &str) -> YamlLines {
+        let mut receiver = LinesReceiver {
+            stack: Vec::new(),
+            lines: HashMap::new(),
+        };
+        let mut parser = Parser::new(text.chars());
+        if parser.load(&mut receiver, false).is_err() {
+            receiver.lines.clear();
+        }
+        YamlLines {
+            lines: receiver.lines,
+        }
+    }
+
+    /// Line of the node at the path, counted from 1.
+    pub fn line(&self, path: &str) -> Option<usize> {
+        self.lines.get(path).cloned()
+    }
+}
+
+#[test]
+fn test_yaml_lines() {
+    let lines = YamlLines::new("- name: A
+  modes:
+    - name: M
+      keys: [a, b]
+-
+  name: B
+");
+    assert_eq!(lines.line("0"), Some(1));
+    assert_eq!(lines.line("0/modes"), Some(3));
+    assert_eq!(lines.line("0/modes/0"), Some(3));
+    assert_eq!(lines.line("0/modes/0/keys/1"), Some(4));
+    assert_eq!(lines.line("1/name"), Some(6));
+    assert_eq!(lines.line("2"), None);
+}